@@ -0,0 +1,7 @@
+CREATE TABLE rate_limit (
+  name TEXT NOT NULL PRIMARY KEY,
+  tokens REAL NOT NULL,
+  updated_at REAL NOT NULL,
+  full_at REAL NOT NULL
+) STRICT;
+CREATE INDEX rate_limit_full_at ON rate_limit (full_at);
@@ -0,0 +1,321 @@
+use bon::Builder;
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    fmt,
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Limits of resources used by an evaluation. Resources without a limit are
+/// still counted and reported in [`crate::Solution::usage`].
+///
+/// ```rust
+/// # use std::io::empty;
+/// use lmb::*;
+///
+/// # fn main() -> Result<()> {
+/// let budget = Budget::builder().instructions(1000).build();
+/// let e = Evaluation::builder("while true do end", empty())
+///     .budget(budget)
+///     .build()?;
+/// assert!(matches!(
+///     e.evaluate().call(),
+///     Err(Error::BudgetExceeded(Resource::Instructions, 1000))
+/// ));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Builder, Clone, Copy, Debug, Default)]
+pub struct Budget {
+    /// Maximum number of interrupts of the Lua virtual machine,
+    /// as a proxy of executed instructions.
+    pub instructions: Option<u64>,
+    /// Maximum number of HTTP requests sent with `@lmb/http`.
+    pub http_requests: Option<u64>,
+    /// Maximum number of bytes read from the input.
+    pub input_bytes: Option<u64>,
+    /// Maximum number of bytes of response bodies read with `@lmb/http`.
+    pub fetched_bytes: Option<u64>,
+    /// Maximum number of values written to the store.
+    pub store_writes: Option<u64>,
+}
+
+impl Budget {
+    fn limit(&self, resource: Resource) -> Option<u64> {
+        match resource {
+            Resource::Instructions => self.instructions,
+            Resource::HttpRequests => self.http_requests,
+            Resource::InputBytes => self.input_bytes,
+            Resource::FetchedBytes => self.fetched_bytes,
+            Resource::StoreWrites => self.store_writes,
+        }
+    }
+}
+
+/// Resource counted against a [`Budget`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Resource {
+    /// Interrupts of the Lua virtual machine
+    Instructions,
+    /// HTTP requests
+    HttpRequests,
+    /// Bytes read from the input
+    InputBytes,
+    /// Bytes of response bodies
+    FetchedBytes,
+    /// Values written to the store
+    StoreWrites,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Instructions => "instructions",
+            Self::HttpRequests => "HTTP requests",
+            Self::InputBytes => "bytes read from input",
+            Self::FetchedBytes => "bytes fetched",
+            Self::StoreWrites => "store writes",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Resources used by an evaluation.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Usage {
+    /// Interrupts of the Lua virtual machine.
+    pub instructions: u64,
+    /// HTTP requests sent.
+    pub http_requests: u64,
+    /// Bytes read from the input.
+    pub input_bytes: u64,
+    /// Bytes of response bodies read.
+    pub fetched_bytes: u64,
+    /// Values written to the store.
+    pub store_writes: u64,
+}
+
+/// Counter of resources shared by the virtual machine and readers of an evaluation.
+#[derive(Debug, Default)]
+pub(crate) struct Meter {
+    budget: Budget,
+    used: [AtomicU64; 5],
+    exceeded: Mutex<Option<(Resource, u64)>>,
+}
+
+impl Meter {
+    pub(crate) fn new(budget: Budget) -> Self {
+        Self {
+            budget,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    /// Count the amount of the resource, and fail once the budget is exceeded.
+    pub(crate) fn charge(&self, resource: Resource, amount: u64) -> LuaResult<()> {
+        let used = self.used[resource as usize].fetch_add(amount, Ordering::Relaxed) + amount;
+        match self.budget.limit(resource) {
+            Some(limit) if used > limit => {
+                self.exceeded.lock().get_or_insert((resource, limit));
+                Err(LuaError::runtime(format!(
+                    "budget of {limit} {resource} exceeded"
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The first resource exceeding the budget, with its limit.
+    pub(crate) fn exceeded(&self) -> Option<(Resource, u64)> {
+        *self.exceeded.lock()
+    }
+
+    pub(crate) fn usage(&self) -> Usage {
+        let used = |resource: Resource| self.used[resource as usize].load(Ordering::Relaxed);
+        Usage {
+            instructions: used(Resource::Instructions),
+            http_requests: used(Resource::HttpRequests),
+            input_bytes: used(Resource::InputBytes),
+            fetched_bytes: used(Resource::FetchedBytes),
+            store_writes: used(Resource::StoreWrites),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        for used in &self.used {
+            used.store(0, Ordering::Relaxed);
+        }
+        self.exceeded.lock().take();
+    }
+}
+
+/// Count the amount of the resource against the budget of the evaluation running on the virtual machine.
+pub(crate) fn charge(vm: &Lua, resource: Resource, amount: u64) -> LuaResult<()> {
+    match vm.app_data_ref::<Arc<Meter>>() {
+        Some(meter) => meter.charge(resource, amount),
+        None => Ok(()),
+    }
+}
+
+/// Reader counting bytes read from the inner reader.
+#[derive(Debug)]
+pub(crate) struct MeteredReader<R> {
+    inner: R,
+    meter: Arc<Meter>,
+    resource: Resource,
+}
+
+impl<R> MeteredReader<R> {
+    pub(crate) fn new(inner: R, meter: Arc<Meter>, resource: Resource) -> Self {
+        Self {
+            inner,
+            meter,
+            resource,
+        }
+    }
+}
+
+impl<R: Read> Read for MeteredReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.meter
+            .charge(self.resource, n as u64)
+            .map_err(io::Error::other)?;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Server;
+    use serde_json::json;
+    use std::io::{empty, Cursor};
+    use test_case::test_case;
+
+    use crate::{Budget, Error, Evaluation, Resource, Store};
+
+    #[test_case(
+        "while true do pcall(function() while true do end end) end",
+        Budget::builder().instructions(100).build(),
+        Resource::Instructions,
+        100
+    )]
+    #[test_case(
+        "return io.read('*a')",
+        Budget::builder().input_bytes(4).build(),
+        Resource::InputBytes,
+        4
+    )]
+    #[test_case(
+        "local m = require('@lmb') m.store.a = 1 m.store.b = 2",
+        Budget::builder().store_writes(1).build(),
+        Resource::StoreWrites,
+        1
+    )]
+    #[test_case(
+        "require('@lmb').store:update({ 'a', 'b' }, function(v) return v end, { 0, 0 })",
+        Budget::builder().store_writes(1).build(),
+        Resource::StoreWrites,
+        1
+    )]
+    fn exceed(script: &str, budget: Budget, resource: Resource, limit: u64) {
+        let e = Evaluation::builder(script, &b"hello"[..])
+            .budget(budget)
+            .store(Store::default())
+            .build()
+            .unwrap();
+        let Err(Error::BudgetExceeded(exceeded, exceeded_limit)) = e.evaluate().call() else {
+            panic!("expect budget exceeded");
+        };
+        assert_eq!(resource, exceeded);
+        assert_eq!(limit, exceeded_limit);
+    }
+
+    #[test]
+    fn exceed_http() {
+        let mut server = Server::new();
+        let mock = server.mock("GET", "/").with_body("hello").create();
+        let url = server.url();
+
+        let script = format!("local m = require('@lmb/http') m:fetch('{url}') m:fetch('{url}')");
+        let budget = Budget::builder().http_requests(1).build();
+        let e = Evaluation::builder(script, empty())
+            .budget(budget)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            e.evaluate().call(),
+            Err(Error::BudgetExceeded(Resource::HttpRequests, 1))
+        ));
+
+        let script = format!("return require('@lmb/http'):fetch('{url}'):read('*a')");
+        let budget = Budget::builder().fetched_bytes(4).build();
+        let e = Evaluation::builder(script, empty())
+            .budget(budget)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            e.evaluate().call(),
+            Err(Error::BudgetExceeded(Resource::FetchedBytes, 4))
+        ));
+
+        mock.expect(2).assert();
+    }
+
+    #[test]
+    fn input_bytes_consumed() {
+        let input = format!("first line\n{}", "a".repeat(16 * 1024)).into_bytes();
+        let budget = Budget::builder().input_bytes(100).build();
+        let e = Evaluation::builder("return io.read('*l')", Cursor::new(input))
+            .budget(budget)
+            .build()
+            .unwrap();
+        let solution = e.evaluate().call().unwrap();
+        assert_eq!(json!("first line"), solution.payload);
+        assert_eq!(11, solution.usage.input_bytes);
+    }
+
+    #[test]
+    fn usage() {
+        let mut server = Server::new();
+        let mock = server.mock("GET", "/").with_body("hello").create();
+        let url = server.url();
+
+        let script = format!(
+            r#"
+            local m = require('@lmb')
+            m.store.a = io.read('*a')
+            m.store.b = require('@lmb/http'):fetch('{url}'):read('*a')
+            for i = 1, 10 do end
+            "#
+        );
+        let budget = Budget::builder().instructions(1000).build();
+        let e = Evaluation::builder(script, &b"hi"[..])
+            .budget(budget)
+            .store(Store::default())
+            .build()
+            .unwrap();
+        let usage = e.evaluate().call().unwrap().usage;
+        assert!(usage.instructions > 0);
+        assert_eq!(1, usage.http_requests);
+        assert_eq!(2, usage.input_bytes);
+        assert_eq!(5, usage.fetched_bytes);
+        assert_eq!(2, usage.store_writes);
+
+        // usage is counted for each evaluation
+        e.set_input(&b""[..]);
+        let usage = e.evaluate().call().unwrap().usage;
+        assert_eq!(1, usage.http_requests);
+        assert_eq!(0, usage.input_bytes);
+        mock.expect(2).assert();
+    }
+}
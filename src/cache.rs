@@ -0,0 +1,405 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fs::{self, DirBuilder},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tracing::{debug, warn};
+
+use crate::{compiler_options, Result};
+
+const EXTENSION: &str = "luac";
+
+/// Suffix of temporary files, so concurrent writers do not write to the same file.
+static TEMP_SUFFIX: AtomicUsize = AtomicUsize::new(0);
+
+/// On-disk cache of compiled scripts, keyed by a hash of the script, the compiler
+/// options and the versions of lmb and Luau. Entries of other scripts, options or
+/// versions are never read, so the cache is invalidated when any of them change.
+///
+/// Luau does not verify bytecode, so the directory is created accessible by the
+/// owner only, and entries are not read when the directory is writable by group
+/// or others. Entries which cannot be loaded are removed and compiled again.
+///
+/// ```rust
+/// # use std::io::empty;
+/// # use assert_fs::TempDir;
+/// # use serde_json::json;
+/// use lmb::*;
+///
+/// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+/// let dir = TempDir::new()?;
+/// let cache = BytecodeCache::new(dir.path());
+/// for _ in 0..2 {
+///     let e = Evaluation::builder("return 1+1", empty())
+///         .cache(cache.clone())
+///         .build()?;
+///     assert_eq!(json!(2), e.evaluate().call()?.payload);
+/// }
+/// assert_eq!(1, cache.stats()?.entries);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct BytecodeCache {
+    dir: PathBuf,
+}
+
+/// Entries of a [`BytecodeCache`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct CacheStats {
+    /// Number of compiled scripts.
+    pub entries: u64,
+    /// Total size of compiled scripts in bytes.
+    pub bytes: u64,
+}
+
+impl BytecodeCache {
+    /// Create a cache in the directory. The directory is created on the first write.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Default directory of the cache, `$XDG_CACHE_HOME/lmb` or `$HOME/.cache/lmb`.
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base.join("lmb"))
+    }
+
+    /// Directory of the cache.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Count entries of the cache.
+    pub fn stats(&self) -> Result<CacheStats> {
+        let mut stats = CacheStats::default();
+        for path in self.entries()? {
+            stats.entries += 1;
+            stats.bytes += fs::metadata(&path)?.len();
+        }
+        Ok(stats)
+    }
+
+    /// Remove all entries of the cache, and return the number of removed entries.
+    /// Temporary files left by interrupted writes are removed as well.
+    pub fn clear(&self) -> Result<u64> {
+        let mut removed = 0;
+        for path in self.entries()? {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+        for path in self.files(is_temp)? {
+            fs::remove_file(&path)?;
+        }
+        Ok(removed)
+    }
+
+    /// Read the compiled script from the cache.
+    /// Failures of reading the cache are logged and treated as a miss.
+    pub(crate) fn get(&self, script: &str) -> Option<Vec<u8>> {
+        let path = self.path(script)?;
+        if let Err(err) = self.check_dir() {
+            if err.kind() != ErrorKind::NotFound {
+                warn!(%err, dir = ?self.dir, "refuse to read compiled script from cache");
+            }
+            return None;
+        }
+        match fs::read(&path) {
+            Ok(compiled) => {
+                debug!(?path, "compiled script read from cache");
+                Some(compiled)
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => {
+                warn!(?err, ?path, "failed to read compiled script from cache");
+                None
+            }
+        }
+    }
+
+    /// Write the compiled script to the cache. Failures are logged.
+    pub(crate) fn put(&self, script: &str, compiled: &[u8]) {
+        let Some(path) = self.path(script) else {
+            return;
+        };
+        if let Err(err) = self.write(&path, compiled) {
+            warn!(?err, ?path, "failed to write compiled script to cache");
+        }
+    }
+
+    /// Remove the compiled script from the cache e.g. when it cannot be loaded.
+    pub(crate) fn evict(&self, script: &str) {
+        let Some(path) = self.path(script) else {
+            return;
+        };
+        if let Err(err) = fs::remove_file(&path) {
+            warn!(?err, ?path, "failed to remove compiled script from cache");
+        }
+    }
+
+    /// Path of the entry, or `None` when the version of Luau is unknown, as entries
+    /// of another bytecode format would be read otherwise.
+    fn path(&self, script: &str) -> Option<PathBuf> {
+        let Some(version) = version() else {
+            debug!("version of Luau is unknown, scripts are not cached");
+            return None;
+        };
+        let key = key(script, &compiler_options(), &version);
+        Some(self.dir.join(format!("{key}.{EXTENSION}")))
+    }
+
+    /// The directory may have been created by someone else, or its mode changed
+    /// since, so entries are only trusted when others cannot write to it.
+    fn check_dir(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+
+            let mode = fs::metadata(&self.dir)?.permissions().mode() & 0o777;
+            if mode & 0o022 != 0 {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("directory writable by group or others with mode {mode:o}"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, compiled: &[u8]) -> Result<()> {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&self.dir)?;
+        self.check_dir()?;
+        // write a temporary file and rename it, so readers never see a partial entry
+        let suffix = TEMP_SUFFIX.fetch_add(1, Ordering::Relaxed);
+        let temp = path.with_extension(format!("{}.{suffix}.tmp", process::id()));
+        fs::write(&temp, compiled)?;
+        if let Err(err) = fs::rename(&temp, path) {
+            let _ = fs::remove_file(&temp);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<PathBuf>> {
+        self.files(|path| path.extension().is_some_and(|ext| ext == EXTENSION))
+    }
+
+    fn files<F>(&self, filter: F) -> Result<Vec<PathBuf>>
+    where
+        F: Fn(&Path) -> bool,
+    {
+        let read_dir = match fs::read_dir(&self.dir) {
+            Ok(read_dir) => read_dir,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut files = Vec::new();
+        for entry in read_dir {
+            let path = entry?.path();
+            if filter(&path) && path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Whether the file is a temporary file of [`BytecodeCache::write`], named `<key>.<pid>.<n>.tmp`.
+fn is_temp(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let parts = name.split('.').collect::<Vec<_>>();
+    let [key, pid, suffix, "tmp"] = parts.as_slice() else {
+        return false;
+    };
+    let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    key.len() == 64
+        && key.bytes().all(|b| b.is_ascii_hexdigit())
+        && is_digits(pid)
+        && is_digits(suffix)
+}
+
+/// Versions of lmb and Luau, which the bytecode format depends on.
+fn version() -> Option<String> {
+    let luau = mlua::ffi::luau_version()?;
+    Some(format!("{}+luau-{luau}", env!("APP_VERSION")))
+}
+
+fn key(script: &str, compiler_options: &str, version: &str) -> String {
+    let mut hasher = Sha256::new();
+    for part in [version, compiler_options, script] {
+        // prefixed with the length, so parts cannot be shifted into each other
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    base16ct::lower::encode_string(&hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use serde_json::json;
+    use std::{fs, io::empty};
+
+    use mlua::prelude::*;
+
+    use super::{key, version};
+    use crate::{compiler, BytecodeCache, CacheStats, Evaluation};
+
+    #[test]
+    fn key_changes() {
+        let k = key("return 1", "O1g1", "1.0.0");
+        assert_eq!(k, key("return 1", "O1g1", "1.0.0"));
+        assert_ne!(k, key("return 2", "O1g1", "1.0.0"));
+        assert_ne!(k, key("return 1", "O2g1", "1.0.0"));
+        assert_ne!(k, key("return 1", "O1g1", "1.0.1"));
+        assert_ne!(key("ab", "c", ""), key("a", "bc", ""));
+    }
+
+    #[test]
+    fn version_of_luau() {
+        let version = version().expect("version of Luau");
+        let luau = Lua::new().globals().get::<String>("_VERSION").unwrap();
+        let luau = luau.strip_prefix("Luau ").unwrap();
+        assert!(version.ends_with(&format!("+luau-{luau}")), "{version}");
+    }
+
+    #[test]
+    fn get_put() {
+        let dir = TempDir::new().unwrap();
+        let cache = BytecodeCache::new(dir.path());
+        let bytecode = compiler().compile("return 1").unwrap();
+        assert_eq!(None, cache.get("a"));
+        cache.put("a", &bytecode);
+        assert_eq!(Some(bytecode.clone()), cache.get("a"));
+        assert_eq!(None, cache.get("b"));
+        cache.put("b", &bytecode);
+        assert_eq!(
+            CacheStats {
+                entries: 2,
+                bytes: 2 * bytecode.len() as u64
+            },
+            cache.stats().unwrap()
+        );
+
+        cache.evict("a");
+        assert_eq!(None, cache.get("a"));
+        assert_eq!(1, cache.stats().unwrap().entries);
+    }
+
+    #[test]
+    fn evict_unloadable() {
+        let dir = TempDir::new().unwrap();
+        let cache = BytecodeCache::new(dir.path());
+        let build = || {
+            Evaluation::builder("return 1+1", empty())
+                .cache(cache.clone())
+                .build()
+                .unwrap()
+        };
+        build();
+        let entry = cache.entries().unwrap().remove(0);
+
+        // e.g. written by a build with another bytecode format
+        fs::write(&entry, b"\xffgarbage").unwrap();
+        assert_eq!(json!(2), build().evaluate().call().unwrap().payload);
+        let compiled = fs::read(&entry).unwrap();
+        assert_ne!(b"\xffgarbage".to_vec(), compiled);
+        assert_eq!(1, cache.stats().unwrap().entries);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_dir() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = TempDir::new().unwrap();
+        let cache = BytecodeCache::new(dir.child("a/lmb").path());
+        cache.put("a", &[0]);
+        let mode = fs::metadata(cache.dir()).unwrap().permissions().mode();
+        assert_eq!(0o700, mode & 0o777);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shared_dir() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = TempDir::new().unwrap();
+        let cache = BytecodeCache::new(dir.child("lmb").path());
+        cache.put("a", &[0]);
+        assert_eq!(Some(vec![0]), cache.get("a"));
+
+        // entries may be replaced by others, so they are neither read nor written
+        fs::set_permissions(cache.dir(), fs::Permissions::from_mode(0o777)).unwrap();
+        assert_eq!(None, cache.get("a"));
+        cache.put("b", &[0]);
+        assert_eq!(1, cache.stats().unwrap().entries);
+    }
+
+    #[test]
+    fn clear() {
+        let dir = TempDir::new().unwrap();
+        let cache = BytecodeCache::new(dir.child("lmb").path());
+        assert_eq!(CacheStats::default(), cache.stats().unwrap());
+        assert_eq!(0, cache.clear().unwrap());
+
+        for script in ["return 1", "return 2"] {
+            let e = Evaluation::builder(script, empty())
+                .cache(cache.clone())
+                .build()
+                .unwrap();
+            e.evaluate().call().unwrap();
+        }
+        dir.child("lmb/other.txt").write_str("other").unwrap();
+        dir.child("lmb/other.1.2.tmp").write_str("other").unwrap();
+        // left by an interrupted write
+        let temp = format!("lmb/{}.1234.0.tmp", "0".repeat(64));
+        dir.child(&temp).write_str("partial").unwrap();
+        assert_eq!(2, cache.stats().unwrap().entries);
+        assert_eq!(2, cache.clear().unwrap());
+        assert_eq!(CacheStats::default(), cache.stats().unwrap());
+        dir.child(&temp).assert(predicates::path::missing());
+        dir.child("lmb/other.txt").assert("other");
+        dir.child("lmb/other.1.2.tmp").assert("other");
+    }
+
+    #[test]
+    fn evaluate_cached() {
+        let dir = TempDir::new().unwrap();
+        let cache = BytecodeCache::new(dir.path());
+        let e = Evaluation::builder("return 1+1", empty())
+            .cache(cache.clone())
+            .build()
+            .unwrap();
+        assert_eq!(json!(2), e.evaluate().call().unwrap().payload);
+
+        // the entry is read instead of compiling the script again
+        let stats = cache.stats().unwrap();
+        let e = Evaluation::builder("return 1+1", empty())
+            .cache(cache.clone())
+            .build()
+            .unwrap();
+        assert_eq!(json!(2), e.evaluate().call().unwrap().payload);
+        assert_eq!(stats, cache.stats().unwrap());
+
+        assert!(Evaluation::builder("return +", empty())
+            .cache(cache.clone())
+            .build()
+            .is_err());
+        assert_eq!(stats, cache.stats().unwrap());
+    }
+}
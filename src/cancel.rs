@@ -0,0 +1,86 @@
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Handle to cancel an evaluation from another thread.
+///
+/// The script is stopped at the next interrupt of the Lua virtual machine,
+/// HTTP requests in flight are no longer waited for, and the evaluation fails with
+/// [`crate::Error::Cancelled`]. Connections of such requests are closed once the
+/// timeout of the evaluation elapses.
+///
+/// ```rust
+/// # use std::{io::empty, thread, time::Duration};
+/// use lmb::*;
+///
+/// # fn main() -> Result<()> {
+/// let e = Evaluation::builder("while true do end", empty()).build()?;
+/// let cancel = CancelHandle::default();
+/// let handle = thread::spawn({
+///     let cancel = cancel.clone();
+///     move || e.evaluate().cancel(cancel).call()
+/// });
+/// thread::sleep(Duration::from_millis(10));
+/// cancel.cancel();
+/// assert!(matches!(handle.join().unwrap(), Err(Error::Cancelled)));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// Cancel the evaluation.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Whether the evaluation is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+/// Time left of a running evaluation or call. HTTP requests take the time left as
+/// their timeout, so a request abandoned by a cancelled evaluation does not hold
+/// its thread and connection for longer than the evaluation could have run.
+#[derive(Clone, Debug)]
+pub(crate) struct Deadline {
+    start: Instant,
+    timeout: Duration,
+    idle_timeout: Option<Duration>,
+    /// Once the script emits a value, the idle timeout is measured from the last emitted value.
+    last_emit: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Deadline {
+    pub(crate) fn new(
+        timeout: Duration,
+        idle_timeout: Option<Duration>,
+        last_emit: Arc<Mutex<Option<Instant>>>,
+    ) -> Self {
+        Self {
+            start: Instant::now(),
+            timeout,
+            idle_timeout,
+            last_emit,
+        }
+    }
+
+    /// Time left, zero once the deadline has passed.
+    pub(crate) fn remaining(&self) -> Duration {
+        match (*self.last_emit.lock(), self.idle_timeout) {
+            (Some(last_emit), Some(idle_timeout)) => {
+                idle_timeout.saturating_sub(last_emit.elapsed())
+            }
+            _ => self.timeout.saturating_sub(self.start.elapsed()),
+        }
+    }
+}
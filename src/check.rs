@@ -61,7 +61,7 @@ impl LuaCheck {
                 }
                 full_moon::Error::TokenizerError(e) => e.position().bytes()..e.position().bytes(),
             });
-        let mut report = Report::build(ReportKind::Error, (name, span.unwrap_or_else(|| 0..0)))
+        let mut report = Report::build(ReportKind::Error, (name, span.unwrap_or(0..0)))
             .with_config(
                 Config::default()
                     .with_char_set(CharSet::Ascii)
@@ -106,7 +106,7 @@ mod tests {
         let script = "ret true";
         let check = LuaCheck::builder("", script).build();
         assert!(matches!(
-            check.check().unwrap_err().get(0),
+            check.check().unwrap_err().first(),
             Some(full_moon::Error::AstError { .. })
         ));
 
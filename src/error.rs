@@ -5,9 +5,9 @@ use lazy_regex::{lazy_regex, Lazy, Regex};
 use mlua::prelude::*;
 use thiserror::Error;
 
-use crate::{Evaluation, Result};
+use crate::{Evaluation, RaisedError, Resource, Result};
 
-static LUA_ERROR_REGEX: Lazy<Regex> = lazy_regex!(r"\[[^\]]+\]:(\d+):(.+)");
+static LUA_ERROR_REGEX: Lazy<Regex> = lazy_regex!(r#"\[(?:string ")?([^\]"]+)"?\]:(\d+):(.+)"#);
 
 /// Custom error type for handling various error scenarios.
 #[derive(Debug, Error)]
@@ -15,6 +15,12 @@ pub enum Error {
     /// Error from the [`bat`] library
     #[error("bat error: {0}")]
     Bat(#[from] bat::error::Error),
+    /// The evaluation used more of the resource than its budget
+    #[error("budget of {1} {0} exceeded")]
+    BudgetExceeded(Resource, u64),
+    /// The evaluation is cancelled by a [`crate::CancelHandle`]
+    #[error("evaluation cancelled")]
+    Cancelled,
     /// Error from the `SQLite` database
     #[error("sqlite error: {0}")]
     Database(#[from] rusqlite::Error),
@@ -24,15 +30,33 @@ pub enum Error {
     /// Error in formatting output
     #[error("format error: {0}")]
     Format(#[from] std::fmt::Error),
+    /// Invalid address to bind the server to
+    #[error("invalid bind: {0}")]
+    InvalidBind(String),
     /// Invalid key length for HMAC
     #[error("invalid length: {0}")]
     InvalidLength(#[from] crypto_common::InvalidLength),
+    /// Invalid quota of rate limiting
+    #[error("invalid quota: {0}")]
+    InvalidQuota(String),
+    /// Invalid route, or routes conflicting with each other
+    #[error("invalid route: {0}")]
+    InvalidRoute(String),
     /// IO error
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     /// Error from the Lua engine
     #[error("lua error: {0}")]
     Lua(#[from] LuaError),
+    /// The Lua virtual machine allocated more memory than the limit in bytes
+    #[error("memory limit of {0} bytes exceeded")]
+    MemoryLimitExceeded(usize),
+    /// Error from the metrics registry
+    #[error("metrics error: {0}")]
+    Metrics(#[from] prometheus::Error),
+    /// Error raised by the script with a table e.g. `error({ status = 404 })`
+    #[error("raised error: {0}")]
+    Raised(serde_json::Value),
     /// Error decoding value from `MessagePack` format
     #[error("RMP decode error: {0}")]
     RMPDecode(#[from] rmp_serde::decode::Error),
@@ -42,19 +66,42 @@ pub enum Error {
     /// Error from [`serde_json`] library
     #[error("serde JSON error: {0}")]
     SerdeJSONError(#[from] serde_json::Error),
+    /// The server cannot be built or failed to serve e.g. invalid certificates
+    #[error("serve error: {0}")]
+    Serve(String),
 }
 
 impl Error {
-    /// Render a Lua runtime or syntax error.
+    /// Recover the table raised by the script when it is the error, as tables
+    /// only reach Rust as their string with a traceback.
+    pub(crate) fn from_lua(vm: &Lua, err: LuaError) -> Self {
+        let Some(raised) = vm.remove_app_data::<RaisedError>() else {
+            return Self::Lua(err);
+        };
+        match innermost(&err) {
+            LuaError::RuntimeError(message)
+                if message
+                    .strip_prefix(&raised.message)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('\n')) =>
+            {
+                Self::Raised(raised.value)
+            }
+            _ => Self::Lua(err),
+        }
+    }
+
+    /// Render a Lua runtime or syntax error. Errors in local modules are rendered
+    /// with the source of the module.
     pub fn write_lua_error<R, W>(&self, mut f: W, e: &Evaluation<R>, no_color: bool) -> Result<()>
     where
         for<'lua> R: 'lua + Read + Send,
         W: Write,
     {
-        let message = match self {
-            Self::Lua(LuaError::RuntimeError(message) | LuaError::SyntaxError { message, .. }) => {
-                message
-            }
+        let Self::Lua(err) = self else {
+            return Ok(());
+        };
+        let message = match innermost(err) {
+            LuaError::RuntimeError(message) | LuaError::SyntaxError { message, .. } => message,
             _ => return Ok(()),
         };
 
@@ -64,23 +111,30 @@ impl Error {
         };
 
         let Some(line_number) = captures
-            .get(1)
+            .get(2)
             .and_then(|n| n.as_str().parse::<usize>().ok())
         else {
             return Ok(write!(f, "{}", first_line)?);
         };
 
+        let chunk_name = captures.get(1).map_or("", |s| s.as_str());
+        let (name, script) = match e.module_source(chunk_name) {
+            Some(source) => (chunk_name, source),
+            None => (e.name(), e.script().to_string()),
+        };
+
         let mut colors = ColorGenerator::new();
 
-        let source = Source::from(e.script());
-        let line = source
-            .line(line_number - 1) // index, not line number
-            .expect("cannot find line in source");
+        let source = Source::from(script);
+        let Some(line) = source.line(line_number - 1) else {
+            // index, not line number
+            return Ok(write!(f, "{}", first_line)?);
+        };
         let span = line.span();
 
-        let message = captures.get(2).map_or(first_line, |s| s.as_str().trim());
+        let message = captures.get(3).map_or(first_line, |s| s.as_str().trim());
         let mut buf = Vec::new();
-        Report::build(ReportKind::Error, (e.name(), span.start()..span.end()))
+        Report::build(ReportKind::Error, (name, span.start()..span.end()))
             .with_config(
                 ariadne::Config::default()
                     .with_char_set(CharSet::Ascii)
@@ -88,23 +142,34 @@ impl Error {
                     .with_color(!no_color),
             )
             .with_label(
-                Label::new((e.name(), span))
+                Label::new((name, span))
                     .with_color(colors.next())
                     .with_message(message),
             )
             .with_message(message)
             .finish()
-            .write((e.name(), source), &mut buf)?;
+            .write((name, source), &mut buf)?;
         write!(f, "{}", String::from_utf8_lossy(&buf))?;
         Ok(())
     }
 }
 
+/// Error raised in Lua, through errors of callbacks e.g. `require`.
+fn innermost(err: &LuaError) -> &LuaError {
+    match err {
+        LuaError::CallbackError { cause, .. } => innermost(cause),
+        _ => err,
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use mlua::prelude::*;
+    use serde_json::json;
     use std::io::empty;
+    use test_case::test_case;
 
-    use crate::Evaluation;
+    use crate::{Error, Evaluation};
 
     #[test]
     fn write_error() {
@@ -117,4 +182,58 @@ mod tests {
         err.write_lua_error(&mut buf, &e, true).unwrap();
         assert!(buf.contains("attempt to perform arithmetic (add) on nil and number"));
     }
+
+    #[test]
+    fn raise_table() {
+        let script = "error({ status = 404, message = 'not found' })";
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let Err(Error::Raised(value)) = e.evaluate().call() else {
+            panic!("expect raised error");
+        };
+        assert_eq!(json!({ "status": 404, "message": "not found" }), value);
+
+        let script = r#"
+        local ok, err = pcall(function() error({ status = 404 }) end)
+        return { ok = ok, status = err.status }
+        "#;
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let res = e.evaluate().call().unwrap();
+        assert_eq!(json!({ "ok": false, "status": 404 }), res.payload);
+
+        // the table is raised as it is
+        let script = r#"
+        local t = { status = 404 }
+        local _, err = pcall(error, t)
+        return { same = rawequal(t, err), metatable = getmetatable(t) == nil }
+        "#;
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let res = e.evaluate().call().unwrap();
+        assert_eq!(json!({ "same": true, "metatable": true }), res.payload);
+    }
+
+    #[test_case(r#"error('lmb raised error: {"status":200}', 0)"#; "message")]
+    #[test_case("local t = {}\npcall(error, t)\nerror(tostring(t), 0)"; "caught table")]
+    fn raise_string_not_table(script: &str) {
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let Err(Error::Lua(_)) = e.evaluate().call() else {
+            panic!("expect lua error");
+        };
+    }
+
+    #[test_case("error('boom')", "[string \"a\"]:1: boom")]
+    #[test_case(
+        "local function f() error('boom', 2) end\nf()",
+        "[string \"a\"]:2: boom"
+    )]
+    #[test_case("error('boom', 0)", "boom")]
+    fn raise_string(script: &str, expected: &str) {
+        let e = Evaluation::builder(script, empty())
+            .name("a".to_string())
+            .build()
+            .unwrap();
+        let Err(Error::Lua(LuaError::RuntimeError(message))) = e.evaluate().call() else {
+            panic!("expect runtime error");
+        };
+        assert_eq!(expected, message.lines().next().unwrap());
+    }
 }
@@ -4,25 +4,45 @@ use bat::{
     input::Input as BatInput,
     style::{StyleComponent, StyleComponents},
 };
-use bon::{bon, builder, Builder};
+use bon::{bon, Builder};
 use chrono::Utc;
 use console::Term;
 use mlua::{prelude::*, Compiler};
 use parking_lot::Mutex;
 use serde_json::Value;
 use std::{
+    collections::HashMap,
     fmt::Write,
     io::{stdout, BufReader, IsTerminal as _, Read},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
-    thread,
     time::{Duration, Instant},
 };
 use tracing::{debug, error, trace_span, warn};
 
-use crate::{bind_vm, Input, PrintOptions, Result, ScheduleOptions, State, Store, DEFAULT_TIMEOUT};
+use crate::{
+    bind_vm, clear_modules, Budget, BytecodeCache, CancelHandle, Deadline, Emitter, Input, Meter,
+    Metrics, ModuleOptions, Modules, PrintOptions, RaisedError, Resource, Result, ScheduleOptions,
+    ScriptArgs, State, Store, UploadedFile, Usage, DEFAULT_TIMEOUT,
+};
+
+const OPTIMIZATION_LEVEL: u8 = 1;
+
+const DEBUG_LEVEL: u8 = 1;
+
+/// Compiler of scripts and modules.
+pub(crate) fn compiler() -> Compiler {
+    Compiler::new()
+        .set_optimization_level(OPTIMIZATION_LEVEL)
+        .set_debug_level(DEBUG_LEVEL)
+}
+
+/// Options of [`compiler`], which scripts compiled with are cached by.
+pub(crate) fn compiler_options() -> String {
+    format!("O{OPTIMIZATION_LEVEL}g{DEBUG_LEVEL}")
+}
 
 /// Solution obtained by the function.
 #[derive(Builder, Debug)]
@@ -37,8 +57,19 @@ where
     pub duration: Duration,
     /// Max memory usage in bytes.
     pub max_memory_usage: usize,
+    /// Resources used by the evaluation.
+    #[builder(default)]
+    pub usage: Usage,
     /// Payload returned by the script.
     pub payload: Value,
+    /// Raw bytes when the script returns a string, which may not be valid UTF-8.
+    pub bytes: Option<Vec<u8>>,
+    /// Function returned by the script.
+    /// When serving, it's called repeatedly to produce chunks of the response body.
+    pub iterator: Option<LuaFunction>,
+    /// Table of callbacks returned by the script, with `on_open`, `on_message` or `on_close`.
+    /// When serving, it handles a WebSocket connection.
+    pub handler: Option<LuaTable>,
 }
 
 #[bon]
@@ -52,7 +83,7 @@ where
     where
         W: Write,
     {
-        let json = json.unwrap_or_else(|| false);
+        let json = json.unwrap_or(false);
         if json {
             let res = serde_json::to_string(&self.payload)?;
             Ok(write!(f, "{}", res)?)
@@ -71,8 +102,20 @@ pub struct Evaluation<R>
 where
     for<'lua> R: 'lua + Read,
 {
+    /// Arguments of the script.
+    args: Option<Arc<ScriptArgs>>,
+    /// Environment variables passed to the script.
+    env: Option<Arc<HashMap<String, String>>>,
     /// Input.
     input: Input<R>,
+    /// Counter of resources used by the evaluation.
+    meter: Arc<Meter>,
+    /// Maximum memory in bytes used by the Lua virtual machine.
+    memory_limit: Option<usize>,
+    /// Metrics of evaluations.
+    metrics: Option<Metrics>,
+    /// Local modules required by the script.
+    modules: Option<Arc<Modules>>,
     /// Name of script.
     name: Option<String>,
     /// Script.
@@ -81,39 +124,158 @@ where
     store: Option<Store>,
     /// Timeout.
     timeout: Option<Duration>,
-    /// Lua code compiled by [`mlua::Compiler`].
-    compiled: Vec<u8>,
+    /// Lua code compiled by [`mlua::Compiler`], shared between forked evaluations.
+    compiled: Arc<[u8]>,
     /// Lua virtual machine.
     vm: Lua,
 }
 
+/// Settings shared by forked evaluations.
+struct Settings {
+    args: Option<Arc<ScriptArgs>>,
+    budget: Budget,
+    env: Option<Arc<HashMap<String, String>>>,
+    memory_limit: Option<usize>,
+    metrics: Option<Metrics>,
+    modules: Option<Modules>,
+    name: Option<String>,
+    store: Option<Store>,
+    timeout: Option<Duration>,
+}
+
 #[bon]
 impl<R> Evaluation<R>
 where
     for<'lua> R: 'lua + Read + Send,
 {
     /// Build evaluation.
+    ///
+    /// With a memory limit, evaluations allocating more memory fail with
+    /// [`crate::Error::MemoryLimitExceeded`]. With a [`Budget`], evaluations
+    /// using more resources fail with [`crate::Error::BudgetExceeded`].
+    /// With [`ModuleOptions`], the script can require local modules e.g. `require('./lib/util')`.
+    /// With a [`BytecodeCache`], the compiled script is read from the cache when present.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let e = Evaluation::builder("return string.rep('a', 1024 * 1024)", empty())
+    ///     .memory_limit(512 * 1024)
+    ///     .build()?;
+    /// assert!(matches!(e.evaluate().call(), Err(Error::MemoryLimitExceeded(_))));
+    /// # Ok(())
+    /// # }
+    /// ```
     #[builder]
     pub fn new(
         #[builder(into, start_fn)] script: String,
         #[builder(start_fn)] input: R,
+        args: Option<ScriptArgs>,
+        budget: Option<Budget>,
+        cache: Option<BytecodeCache>,
+        env: Option<HashMap<String, String>>,
+        memory_limit: Option<usize>,
+        metrics: Option<Metrics>,
+        modules: Option<ModuleOptions>,
         name: Option<String>,
         store: Option<Store>,
         timeout: Option<Duration>,
     ) -> Result<Arc<Evaluation<R>>> {
+        let vm = Lua::new();
         let compiled = {
             let _s = trace_span!("compile_script").entered();
-            let compiler = Compiler::new();
-            compiler.compile(&script)?
+            let compile = || {
+                let compiled = compiler().compile(&script)?;
+                if let Some(cache) = &cache {
+                    cache.put(&script, &compiled);
+                }
+                Ok::<_, LuaError>(compiled)
+            };
+            let compiled = match &cache {
+                Some(cache) => match cache.get(&script) {
+                    // Luau does not verify bytecode, so entries e.g. written by another
+                    // build are checked by loading them, then removed and compiled again
+                    Some(compiled) => match vm.load(&compiled).into_function() {
+                        Ok(_) => Ok(compiled),
+                        Err(err) => {
+                            warn!(%err, "failed to load compiled script from cache, compile again");
+                            cache.evict(&script);
+                            compile()
+                        }
+                    },
+                    None => compile(),
+                },
+                None => compile(),
+            };
+            match compiled {
+                Ok(compiled) => compiled,
+                Err(err) => {
+                    if let Some(metrics) = &metrics {
+                        metrics.observe_compile_error(name.as_deref().unwrap_or_default());
+                    }
+                    return Err(err.into());
+                }
+            }
         };
-        let vm = Lua::new();
+        let settings = Settings {
+            args: args.map(Arc::new),
+            budget: budget.unwrap_or_default(),
+            env: env.map(Arc::new),
+            memory_limit,
+            metrics,
+            modules: modules.as_ref().map(Modules::new).transpose()?,
+            name,
+            store,
+            timeout,
+        };
+        Self::with_compiled(vm, script, compiled.into(), input, settings)
+    }
+
+    fn with_compiled(
+        vm: Lua,
+        script: String,
+        compiled: Arc<[u8]>,
+        input: R,
+        settings: Settings,
+    ) -> Result<Arc<Evaluation<R>>> {
+        let Settings {
+            args,
+            budget,
+            env,
+            memory_limit,
+            metrics,
+            modules,
+            name,
+            store,
+            timeout,
+        } = settings;
         vm.sandbox(true)?;
+        let meter = Arc::new(Meter::new(budget));
+        vm.set_app_data(meter.clone());
+        let modules = modules.map(Arc::new);
+        if let Some(modules) = &modules {
+            vm.set_app_data(modules.clone());
+        }
         let input = Arc::new(Mutex::new(BufReader::new(input)));
         bind_vm(&vm, input.clone())
+            .maybe_args(args.clone())
+            .maybe_env(env.clone())
+            .maybe_metrics(metrics.clone())
             .maybe_store(store.clone())
             .call()?;
+        if let Some(limit) = memory_limit {
+            vm.set_memory_limit(limit)?;
+        }
         Ok(Arc::new(Evaluation {
+            args,
+            env,
             input,
+            meter,
+            memory_limit,
+            metrics,
+            modules,
             name,
             script,
             store,
@@ -123,8 +285,81 @@ where
         }))
     }
 
+    /// Create another evaluation with a new Lua virtual machine and input.
+    /// The compiled script and modules are shared, so they are not compiled again.
+    ///
+    /// ```rust
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let e = Evaluation::builder("return io.read('*a')", &b"0"[..]).build()?;
+    /// let forked = e.fork(&b"1"[..])?;
+    /// assert_eq!(json!("1"), forked.evaluate().call()?.payload);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn fork(&self, input: R) -> Result<Arc<Evaluation<R>>> {
+        let settings = Settings {
+            args: self.args.clone(),
+            budget: self.meter.budget(),
+            env: self.env.clone(),
+            memory_limit: self.memory_limit,
+            metrics: self.metrics.clone(),
+            modules: self.modules.as_ref().map(|m| m.fork()),
+            name: self.name.clone(),
+            store: self.store.clone(),
+            timeout: self.timeout,
+        };
+        Self::with_compiled(
+            Lua::new(),
+            self.script.clone(),
+            self.compiled.clone(),
+            input,
+            settings,
+        )
+    }
+
+    /// Reset the Lua virtual machine and replace the input,
+    /// so global variables set by previous evaluations are discarded.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// # use serde_json::json;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let e = Evaluation::builder("n = (n or 0) + 1; return n", empty()).build()?;
+    /// assert_eq!(json!(1), e.evaluate().call()?.payload);
+    /// assert_eq!(json!(2), e.evaluate().call()?.payload);
+    /// e.reset(empty())?;
+    /// assert_eq!(json!(1), e.evaluate().call()?.payload);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reset(&self, input: R) -> Result<()> {
+        let _s = trace_span!("reset_vm").entered();
+        // the interrupt of the previous evaluation would raise once its deadline passes
+        self.vm.remove_interrupt();
+        // toggling the sandbox replaces the global table with a fresh proxy
+        self.vm.sandbox(false)?;
+        self.vm.sandbox(true)?;
+        clear_modules(&self.vm)?;
+        *self.input.lock() = BufReader::new(input);
+        bind_vm(&self.vm, self.input.clone())
+            .maybe_args(self.args.clone())
+            .maybe_env(self.env.clone())
+            .maybe_metrics(self.metrics.clone())
+            .maybe_store(self.store.clone())
+            .call()?;
+        self.vm.gc_collect()?;
+        Ok(())
+    }
+
     /// Evaluate the function with a state.
     ///
+    /// With a [`CancelHandle`], the evaluation can be cancelled from another thread.
+    ///
     /// ```rust
     /// # use std::{io::empty, sync::Arc};
     /// # use serde_json::json;
@@ -140,24 +375,69 @@ where
     /// # }
     /// ```
     #[builder]
-    pub fn evaluate(self: &Arc<Self>, state: Option<Arc<State>>) -> Result<Solution<R>> {
-        if state.is_some() {
+    pub fn evaluate(
+        self: &Arc<Self>,
+        state: Option<Arc<State>>,
+        emitter: Option<Emitter>,
+        idle_timeout: Option<Duration>,
+        files: Option<Vec<UploadedFile>>,
+        cancel: Option<CancelHandle>,
+    ) -> Result<Solution<R>> {
+        // once the script emits a value, the idle timeout replaces the timeout
+        // and is measured from the last emitted value
+        let last_emit = Arc::new(Mutex::new(None::<Instant>));
+        if state.is_some() || emitter.is_some() || files.is_some() {
+            let emitter = emitter.map(|emitter| {
+                let last_emit = Arc::clone(&last_emit);
+                Emitter::new(move |value| {
+                    emitter.emit(value)?;
+                    *last_emit.lock() = Some(Instant::now());
+                    Ok(())
+                })
+            });
             bind_vm(&self.vm, self.input.clone())
+                .maybe_args(self.args.clone())
+                .maybe_env(self.env.clone())
+                .maybe_metrics(self.metrics.clone())
                 .maybe_store(self.store.clone())
                 .maybe_state(state)
+                .maybe_emitter(emitter)
+                .maybe_files(files.map(Arc::from))
                 .call()?;
         }
 
-        let timeout = self.timeout.unwrap_or_else(|| DEFAULT_TIMEOUT);
+        let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT);
         let max_memory = Arc::new(AtomicUsize::new(0));
+        self.meter.reset();
+        let _in_flight = self.metrics.as_ref().map(Metrics::start_evaluation);
+
+        // HTTP requests in flight are abandoned by the handle in the app data,
+        // and time out with the deadline
+        match &cancel {
+            Some(cancel) => self.vm.set_app_data(cancel.clone()),
+            None => self.vm.remove_app_data::<CancelHandle>(),
+        };
+        // tables raised by an earlier call and caught are not the error of this one
+        self.vm.remove_app_data::<RaisedError>();
+        let deadline = Deadline::new(timeout, idle_timeout, last_emit);
+        self.vm.set_app_data(deadline.clone());
 
         let start = Instant::now();
         self.vm.set_interrupt({
+            let cancel = cancel.clone();
             let max_memory = Arc::clone(&max_memory);
+            let meter = Arc::clone(&self.meter);
+            let on_timeout = self.timeout_observer();
             move |vm| {
                 let used_memory = vm.used_memory();
                 max_memory.fetch_max(used_memory, Ordering::Relaxed);
-                if start.elapsed() > timeout {
+                // keep raising, so the error cannot be swallowed by pcall
+                if cancel.as_ref().is_some_and(CancelHandle::is_cancelled) {
+                    return Err(mlua::Error::runtime("cancelled"));
+                }
+                meter.charge(Resource::Instructions, 1)?;
+                if deadline.remaining().is_zero() {
+                    on_timeout();
                     vm.remove_interrupt();
                     return Err(mlua::Error::runtime("timeout"));
                 }
@@ -166,29 +446,154 @@ where
         });
 
         let script_name = &self.name;
-        let chunk = self.vm.load(&self.compiled);
+        let chunk = self.vm.load(&*self.compiled);
         let chunk = match &self.name {
             Some(name) => chunk.set_name(name),
             None => chunk,
         };
 
         let _s = trace_span!("evaluate").entered();
-        let result = self.vm.from_value(chunk.eval()?)?;
-
+        let evaluated = chunk.eval::<LuaValue>();
+        self.vm.remove_app_data::<CancelHandle>();
+        let cancelled = cancel.as_ref().is_some_and(CancelHandle::is_cancelled);
+        if cancelled || self.meter.exceeded().is_some() {
+            // otherwise the virtual machine cannot be reset or reused
+            self.vm.remove_interrupt();
+        }
         let duration = start.elapsed();
         let max_memory = max_memory.load(Ordering::Acquire);
-        debug!(?duration, ?script_name, ?max_memory, "script evaluated");
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_evaluation(self.name(), duration, max_memory);
+        }
+        if cancelled && evaluated.is_err() {
+            debug!(?duration, ?script_name, "script cancelled");
+            return Err(crate::Error::Cancelled);
+        }
+        let (result, bytes, iterator, handler) =
+            match evaluated.map_err(|err| self.lua_error(err))? {
+                LuaValue::String(s) => {
+                    let bytes = s.as_bytes().to_vec();
+                    let result = Value::String(String::from_utf8_lossy(&bytes).into_owned());
+                    (result, Some(bytes), None, None)
+                }
+                LuaValue::Function(f) => (Value::Null, None, Some(f), None),
+                LuaValue::Table(t) if is_handler(&t) => (Value::Null, None, None, Some(t)),
+                v => (self.vm.from_value(v)?, None, None, None),
+            };
+
+        let usage = self.meter.usage();
+        debug!(
+            ?duration,
+            ?script_name,
+            ?max_memory,
+            ?usage,
+            "script evaluated"
+        );
         let solution = Solution::builder(self.clone())
             .duration(duration)
             .max_memory_usage(max_memory)
+            .usage(usage)
             .payload(result)
+            .maybe_bytes(bytes)
+            .maybe_iterator(iterator)
+            .maybe_handler(handler)
             .build();
         Ok(solution)
     }
 
+    /// Call a function returned by the script e.g. a callback of [`Solution::handler`].
+    /// The timeout applies to the call, and the resources used are added to the budget
+    /// of the evaluation.
+    ///
+    /// With a [`CancelHandle`], the call can be cancelled from another thread.
+    ///
+    /// ```rust
+    /// # use std::io::empty;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let e = Evaluation::builder("return { on_message = function(s) return s .. '!' end }", empty())
+    ///     .build()?;
+    /// let handler = e.evaluate().call()?.handler.expect("handler");
+    /// let on_message = handler.get("on_message")?;
+    /// assert_eq!("hi!", e.call_function::<_, String>(&on_message, "hi").call()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[builder]
+    pub fn call_function<A, T>(
+        &self,
+        #[builder(start_fn)] f: &LuaFunction,
+        #[builder(start_fn)] args: A,
+        cancel: Option<CancelHandle>,
+    ) -> Result<T>
+    where
+        A: IntoLuaMulti,
+        T: FromLuaMulti,
+    {
+        let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        let deadline = Deadline::new(timeout, None, Arc::default());
+        self.vm.set_app_data(deadline.clone());
+        match &cancel {
+            Some(cancel) => self.vm.set_app_data(cancel.clone()),
+            None => self.vm.remove_app_data::<CancelHandle>(),
+        };
+        // tables raised by an earlier call and caught are not the error of this one
+        self.vm.remove_app_data::<RaisedError>();
+        let on_timeout = self.timeout_observer();
+        let meter = Arc::clone(&self.meter);
+        self.vm.set_interrupt({
+            let cancel = cancel.clone();
+            move |vm| {
+                if cancel.as_ref().is_some_and(CancelHandle::is_cancelled) {
+                    return Err(mlua::Error::runtime("cancelled"));
+                }
+                meter.charge(Resource::Instructions, 1)?;
+                if deadline.remaining().is_zero() {
+                    on_timeout();
+                    vm.remove_interrupt();
+                    return Err(mlua::Error::runtime("timeout"));
+                }
+                Ok(LuaVmState::Continue)
+            }
+        });
+        let called = f.call(args);
+        self.vm.remove_app_data::<CancelHandle>();
+        if cancel.as_ref().is_some_and(CancelHandle::is_cancelled) {
+            self.vm.remove_interrupt();
+            if called.is_err() {
+                return Err(crate::Error::Cancelled);
+            }
+        }
+        called.map_err(|err| self.lua_error(err))
+    }
+
+    /// Tell errors of exceeded budgets and allocations beyond the memory limit
+    /// from other Lua errors.
+    fn lua_error(&self, err: LuaError) -> crate::Error {
+        if let Some((resource, limit)) = self.meter.exceeded() {
+            return crate::Error::BudgetExceeded(resource, limit);
+        }
+        match self.memory_limit {
+            Some(limit) if is_memory_error(&err) => crate::Error::MemoryLimitExceeded(limit),
+            _ => crate::Error::from_lua(&self.vm, err),
+        }
+    }
+
+    /// Count timeouts of the script when metrics are exported.
+    fn timeout_observer(&self) -> impl Fn() + Send + 'static {
+        let metrics = self.metrics.clone();
+        let name = self.name().to_string();
+        move || {
+            if let Some(metrics) = &metrics {
+                metrics.observe_timeout(&name);
+            }
+        }
+    }
+
     /// Get the name
     pub fn name(&self) -> &str {
-        self.name.as_deref().unwrap_or_else(|| "")
+        self.name.as_deref().unwrap_or("")
     }
 
     /// Get the script
@@ -196,6 +601,11 @@ where
         self.script.as_ref()
     }
 
+    /// Source of the local module loaded as the chunk.
+    pub(crate) fn module_source(&self, chunk_name: &str) -> Option<String> {
+        self.modules.as_ref()?.source(chunk_name)
+    }
+
     /// Schedule the script.
     pub fn schedule(self: &Arc<Self>, options: &ScheduleOptions) {
         let bail = options.bail;
@@ -206,7 +616,11 @@ where
             if let Some(next) = options.schedule.upcoming(Utc).take(1).next() {
                 debug!(%next, "next run");
                 let elapsed = next - now;
-                thread::sleep(elapsed.to_std().expect("failed to fetch next schedule"));
+                let elapsed = elapsed.to_std().expect("failed to fetch next schedule");
+                if options.shutdown.wait_timeout(elapsed) {
+                    debug!("scheduler stopped");
+                    break;
+                }
                 if let Err(err) = self.clone().evaluate().call() {
                     warn!(?err, "failed to evaluate");
                     if bail > 0 {
@@ -274,18 +688,42 @@ where
     }
 }
 
+const HANDLER_CALLBACKS: [&str; 3] = ["on_open", "on_message", "on_close"];
+
+fn is_handler(t: &LuaTable) -> bool {
+    HANDLER_CALLBACKS
+        .iter()
+        .any(|name| matches!(t.raw_get::<LuaValue>(*name), Ok(LuaValue::Function(_))))
+}
+
+fn is_memory_error(err: &LuaError) -> bool {
+    match err {
+        LuaError::MemoryError(_) => true,
+        LuaError::CallbackError { cause, .. } | LuaError::WithContext { cause, .. } => {
+            is_memory_error(cause)
+        }
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use parking_lot::Mutex;
     use serde_json::{json, Value};
     use std::{
         fs,
         io::empty,
+        str::FromStr as _,
         sync::Arc,
+        thread,
         time::{Duration, Instant},
     };
     use test_case::test_case;
 
-    use crate::{Evaluation, State, StateKey, Store};
+    use crate::{
+        CancelHandle, Emitter, Error, Evaluation, Metrics, ScheduleOptions, Shutdown, State,
+        StateKey, Store,
+    };
 
     #[test_case("./lua-examples/error.lua")]
     fn error_in_script(path: &str) {
@@ -327,6 +765,115 @@ mod tests {
         assert!(elapsed < 500, "actual elapsed {elapsed:?}"); // 500% error
     }
 
+    #[test]
+    fn evaluation_metrics() {
+        let metrics = Metrics::default();
+        let e = Evaluation::builder("while true do end", empty())
+            .metrics(metrics.clone())
+            .name("loop".to_string())
+            .timeout(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        assert!(e.evaluate().call().is_err());
+        let res = Evaluation::builder("return +", empty())
+            .metrics(metrics.clone())
+            .name("broken".to_string())
+            .build();
+        assert!(res.is_err());
+
+        let text = metrics.encode().unwrap();
+        assert!(text.contains(r#"lmb_evaluation_timeouts_total{script="loop"} 1"#));
+        assert!(text.contains(r#"lmb_evaluation_duration_seconds_count{script="loop"} 1"#));
+        assert!(text.contains(r#"lmb_compile_errors_total{script="broken"} 1"#));
+        assert!(text.contains("lmb_evaluations_in_flight 0"));
+    }
+
+    #[test]
+    fn emit_values() {
+        let emitted = Arc::new(Mutex::new(Vec::new()));
+        let emitter = Emitter::new({
+            let emitted = emitted.clone();
+            move |value| {
+                emitted.lock().push(value);
+                Ok(())
+            }
+        });
+        let script = "local m = require('@lmb'); m:emit(1); m:emit({ a = 1 }); return m:emit('b')";
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let res = e.evaluate().emitter(emitter).call().unwrap();
+        assert_eq!(json!(true), res.payload);
+        assert_eq!(
+            vec![json!(1), json!({ "a": 1 }), json!("b")],
+            *emitted.lock()
+        );
+    }
+
+    #[test]
+    fn emit_without_emitter() {
+        let e = Evaluation::builder("return require('@lmb'):emit(1)", empty())
+            .build()
+            .unwrap();
+        let res = e.evaluate().call().unwrap();
+        assert_eq!(json!(false), res.payload);
+    }
+
+    #[test]
+    fn emit_error() {
+        let emitter = Emitter::new(|_| Err(mlua::Error::runtime("closed")));
+        let e = Evaluation::builder("require('@lmb'):emit(1); return true", empty())
+            .build()
+            .unwrap();
+        let res = e.evaluate().emitter(emitter).call();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let script = r#"
+        local m = require('@lmb')
+        local start = os.clock()
+        local last = start
+        m:emit(0)
+        while os.clock() - start < 0.3 do
+          if os.clock() - last > 0.05 then
+            last = os.clock()
+            m:emit(last)
+          end
+        end
+        if m.request.hang then
+          while true do end
+        end
+        return true
+        "#;
+        let e = Evaluation::builder(script, empty())
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let emitter = Emitter::new(|_| Ok(()));
+        let state = Arc::new(State::new());
+        state.insert(StateKey::Request, json!({ "hang": false }));
+        let res = e
+            .evaluate()
+            .state(state.clone())
+            .emitter(emitter.clone())
+            .idle_timeout(Duration::from_millis(200))
+            .call()
+            .unwrap();
+        assert_eq!(json!(true), res.payload);
+
+        let timer = Instant::now();
+        state.insert(StateKey::Request, json!({ "hang": true }));
+        let res = e
+            .evaluate()
+            .state(state)
+            .emitter(emitter)
+            .idle_timeout(Duration::from_millis(200))
+            .call();
+        assert!(res.is_err());
+        let elapsed = timer.elapsed().as_millis();
+        assert!(elapsed < 1000, "actual elapsed {elapsed:?}");
+    }
+
     #[test_case("return 1+1", json!(2))]
     #[test_case("return 'a'..1", json!("a1"))]
     #[test_case("return require('@lmb')._VERSION", json!(env!("APP_VERSION")))]
@@ -390,6 +937,143 @@ mod tests {
         }
     }
 
+    #[test]
+    fn binary_string() {
+        let e = Evaluation::builder("return '\\255\\0'", empty())
+            .build()
+            .unwrap();
+        let res = e.evaluate().call().unwrap();
+        assert_eq!(Some(vec![255u8, 0]), res.bytes);
+    }
+
+    #[test]
+    fn return_iterator() {
+        let script = r#"
+        local i = 0
+        return function()
+          i = i + 1
+          if i <= 2 then return tostring(i) end
+        end
+        "#;
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let res = e.evaluate().call().unwrap();
+        assert_eq!(json!(null), res.payload);
+        let f = res.iterator.unwrap();
+        assert_eq!(Some("1".to_string()), f.call::<Option<String>>(()).unwrap());
+        assert_eq!(Some("2".to_string()), f.call::<Option<String>>(()).unwrap());
+        assert_eq!(None, f.call::<Option<String>>(()).unwrap());
+    }
+
+    #[test]
+    fn return_handler() {
+        let script = r#"
+        local count = 0
+        return {
+          on_message = function(message)
+            count = count + 1
+            return message .. count
+          end,
+        }
+        "#;
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let res = e.evaluate().call().unwrap();
+        assert_eq!(json!(null), res.payload);
+        let on_message = res.handler.unwrap().get("on_message").unwrap();
+        let called: String = e.call_function(&on_message, "a").call().unwrap();
+        assert_eq!("a1", called);
+        let called: String = e.call_function(&on_message, "b").call().unwrap();
+        assert_eq!("b2", called);
+
+        let e = Evaluation::builder("return { a = 1 }", empty())
+            .build()
+            .unwrap();
+        let res = e.evaluate().call().unwrap();
+        assert_eq!(json!({ "a": 1 }), res.payload);
+        assert!(res.handler.is_none());
+    }
+
+    #[test]
+    fn call_function_cancel() {
+        let script = "return { on_open = function() while true do pcall(function() while true do end end) end end }";
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let handler = e.evaluate().call().unwrap().handler.unwrap();
+        let on_open: mlua::Function = handler.get("on_open").unwrap();
+        let cancel = CancelHandle::default();
+        let handle = thread::spawn({
+            let e = e.clone();
+            let cancel = cancel.clone();
+            move || e.call_function::<_, ()>(&on_open, ()).cancel(cancel).call()
+        });
+        thread::sleep(Duration::from_millis(50));
+        cancel.cancel();
+        assert!(matches!(handle.join().unwrap(), Err(Error::Cancelled)));
+    }
+
+    #[test]
+    fn call_function_timeout() {
+        let script = "return { on_open = function() while true do end end }";
+        let e = Evaluation::builder(script, empty())
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let on_open = e.evaluate().call().unwrap().handler.unwrap();
+        let on_open = on_open.get("on_open").unwrap();
+        assert!(e.call_function::<_, ()>(&on_open, ()).call().is_err());
+    }
+
+    #[test]
+    fn memory_limit() {
+        let script = "local t = {} for i = 1, 1e7 do t[i] = { i } end return #t";
+        let e = Evaluation::builder(script, empty())
+            .memory_limit(1024 * 1024)
+            .build()
+            .unwrap();
+        let Err(Error::MemoryLimitExceeded(limit)) = e.evaluate().call() else {
+            panic!("expect memory limit exceeded");
+        };
+        assert_eq!(1024 * 1024, limit);
+
+        let forked = e.fork(empty()).unwrap();
+        assert!(matches!(
+            forked.evaluate().call(),
+            Err(Error::MemoryLimitExceeded(_))
+        ));
+
+        let script = "local t = {} for i = 1, 1000 do t[i] = i end return #t";
+        let e = Evaluation::builder(script, empty())
+            .memory_limit(1024 * 1024)
+            .build()
+            .unwrap();
+        assert_eq!(json!(1000), e.evaluate().call().unwrap().payload);
+    }
+
+    #[test]
+    fn cancel() {
+        let script = "while true do pcall(function() while true do end end) end";
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let cancel = CancelHandle::default();
+        let handle = thread::spawn({
+            let e = e.clone();
+            let cancel = cancel.clone();
+            move || e.evaluate().cancel(cancel).call()
+        });
+        thread::sleep(Duration::from_millis(50));
+        cancel.cancel();
+        assert!(matches!(handle.join().unwrap(), Err(Error::Cancelled)));
+        e.reset(empty()).unwrap();
+
+        // the handle of the previous evaluation is not kept
+        let script = "local n = 0 for i = 1, 10 do n = n + i end return n";
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let cancel = CancelHandle::default();
+        cancel.cancel();
+        assert!(matches!(
+            e.evaluate().cancel(cancel).call(),
+            Err(Error::Cancelled)
+        ));
+        assert_eq!(json!(55), e.evaluate().call().unwrap().payload);
+    }
+
     #[test]
     fn write_solution() {
         let script = "return 1+1";
@@ -399,4 +1083,23 @@ mod tests {
         solution.write(&mut buf).call().unwrap();
         assert_eq!("2", buf);
     }
+
+    #[test]
+    fn schedule_shutdown() {
+        let e = Evaluation::builder("return true", empty()).build().unwrap();
+        let schedule = cron::Schedule::from_str("0 0 0 1 1 * *").unwrap();
+        let shutdown = Shutdown::default();
+        let options = ScheduleOptions::builder()
+            .bail(1)
+            .initial_run(false)
+            .schedule(schedule)
+            .shutdown(shutdown.clone())
+            .build();
+        let start = Instant::now();
+        let handle = thread::spawn(move || e.schedule(&options));
+        thread::sleep(Duration::from_millis(50));
+        shutdown.trigger();
+        handle.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
 }
@@ -30,22 +30,63 @@ impl Visitor for Example {
         let TokenType::MultiLineComment { comment, .. } = token.token_type() else {
             return;
         };
-        let comment = comment
-            .split('\n')
-            .map(|s| s.trim_start_matches('-'))
-            .collect::<Vec<_>>()
-            .join("\n");
-        let Ok(parsed) = comment.trim_end_matches('-').to_string().parse::<Table>() else {
+        let Some(parsed) = parse_front_matter(comment) else {
             return;
         };
-        let Value::String(description) = &parsed["description"] else {
+        let Some(Value::String(description)) = parsed.get("description") else {
             return;
         };
-        self.description = description.to_string();
+        self.description.clone_from(description);
         self.done = true;
     }
 }
 
+#[derive(Default)]
+struct FrontMatter {
+    table: Option<Table>,
+    done: bool,
+}
+
+impl Visitor for FrontMatter {
+    fn visit_multi_line_comment(&mut self, token: &full_moon::tokenizer::Token) {
+        if self.done {
+            return;
+        }
+        let TokenType::MultiLineComment { comment, .. } = token.token_type() else {
+            return;
+        };
+        self.table = parse_front_matter(comment);
+        self.done = true;
+    }
+}
+
+fn parse_front_matter(comment: &str) -> Option<Table> {
+    let comment = comment
+        .split('\n')
+        .map(|s| s.trim_start().trim_start_matches('-'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    comment.trim_end_matches('-').parse::<Table>().ok()
+}
+
+/// Parse the front matter of a Lua script,
+/// which is a TOML table written in the first multi-line comment.
+///
+/// ```rust
+/// use lmb::front_matter;
+///
+/// let script = "--[[\n--method = \"POST\"\n--]]\nreturn 1";
+/// let table = front_matter(script).unwrap();
+/// assert_eq!(Some("POST"), table.get("method").and_then(|v| v.as_str()));
+/// assert!(front_matter("return 1").is_none());
+/// ```
+pub fn front_matter(script: &str) -> Option<Table> {
+    let ast = full_moon::parse(script).ok()?;
+    let mut visitor = FrontMatter::default();
+    visitor.visit_ast(&ast);
+    visitor.table
+}
+
 static EXAMPLES_DIR: Dir<'_> = include_dir!("lua-examples");
 
 /// Embedded Lua examples.
@@ -14,27 +14,45 @@ use std::{
     time::Duration,
 };
 
+pub use budget::*;
+pub use cache::*;
+pub use cancel::*;
 pub use check::*;
 pub use error::*;
 pub use eval::*;
 pub use example::*;
 pub use guide::*;
 pub use lua_binding::*;
+pub use metrics::*;
+pub use module::*;
+pub use params::*;
+pub use pool::*;
 pub use schedule::*;
 pub use store::*;
 
+mod budget;
+mod cache;
+mod cancel;
 mod check;
 mod error;
 mod eval;
 mod example;
 mod guide;
 mod lua_binding;
+mod metrics;
+mod module;
+mod params;
+mod pool;
 mod schedule;
+pub mod serve;
 mod store;
 
 /// Default timeout for evaluation in seconds.
 pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 
+/// Default maximum number of idle virtual machines in [`EvaluationPool`].
+pub const DEFAULT_POOL_SIZE: usize = 8;
+
 /// Directory containing migration files.
 static MIGRATIONS_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/migrations");
 
@@ -101,22 +119,18 @@ mod tests {
 
             for event in parser {
                 match event {
-                    Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref lang))) => {
-                        if lang.to_string() == "lua" {
-                            is_code = true;
-                        }
+                    Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref lang)))
+                        if lang.to_string() == "lua" =>
+                    {
+                        is_code = true;
                     }
-                    Event::Text(t) => {
-                        if is_code {
-                            text.push_str(&t);
-                        }
+                    Event::Text(t) if is_code => {
+                        text.push_str(&t);
                     }
-                    Event::End(TagEnd::CodeBlock) => {
-                        if is_code {
-                            blocks.push(text.clone());
-                            text.clear();
-                            is_code = false;
-                        }
+                    Event::End(TagEnd::CodeBlock) if is_code => {
+                        blocks.push(text.clone());
+                        text.clear();
+                        is_code = false;
                     }
                     _ => {}
                 }
@@ -1,19 +1,39 @@
 use std::{
     collections::HashMap,
-    io::{BufReader, Cursor, Read},
-    sync::Arc,
+    io::{self, BufReader, Cursor, Read},
+    sync::{mpsc, Arc, LazyLock},
+    time::Duration,
 };
 
 use http::{Method, StatusCode};
 use mlua::prelude::*;
 use parking_lot::Mutex;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use serde_json::Value;
-use tracing::{trace, trace_span, warn};
+use tracing::{error, trace, trace_span, warn};
 use ureq::Request;
 use url::Url;
 
 use super::{lua_lmb_read, lua_lmb_read_unicode};
-use crate::Input;
+use crate::{charge, CancelHandle, Deadline, Input, Meter, MeteredReader, Resource};
+
+/// Interval to check whether the evaluation is cancelled while waiting for a response.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Maximum number of threads sending requests of cancellable evaluations.
+const MAX_FETCH_THREADS: usize = 32;
+
+/// Threads shared by requests of cancellable evaluations, so requests abandoned by
+/// cancelled evaluations cannot hold more threads and connections than the pool has.
+static FETCH_POOL: LazyLock<ThreadPool> = LazyLock::new(|| {
+    ThreadPoolBuilder::new()
+        .num_threads(MAX_FETCH_THREADS)
+        .thread_name(|i| format!("lmb-fetch-{i}"))
+        // otherwise a panic would abort the process
+        .panic_handler(|_| error!("HTTP request thread panicked"))
+        .build()
+        .expect("failed to build thread pool of HTTP requests")
+});
 
 /// HTTP module
 pub struct LuaModHTTP {}
@@ -47,10 +67,10 @@ impl LuaUserData for LuaModHTTPResponse {
             Ok(value)
         });
         methods.add_method("read", |vm, this, f: Option<LuaValue>| {
-            lua_lmb_read(vm, &this.reader, f)
+            lua_lmb_read(vm, &this.reader, f, None)
         });
         methods.add_method("read_unicode", |vm, this, f: LuaValue| {
-            lua_lmb_read_unicode(vm, &this.reader, f)
+            lua_lmb_read_unicode(vm, &this.reader, f, None)
         });
     }
 }
@@ -85,22 +105,42 @@ fn lua_lmb_fetch(
         .and_then(|t| t.get("headers").ok())
         .and_then(|m| vm.from_value(m).ok())
         .unwrap_or(Value::Null);
-    let _s = trace_span!("send_http_request", %method, %url, ?headers).entered();
-    let res = if method.is_safe() {
-        let req = ureq::request_url(method.as_str(), &url);
-        let req = set_headers(req, &headers);
-        req.call()
+    let body: Option<String> = if method.is_safe() {
+        None
     } else {
-        let body: String = options
-            .map(|t| t.get("body").unwrap_or_default())
-            .unwrap_or_default();
-        let req = ureq::request_url(method.as_str(), &url);
-        let req = set_headers(req, &headers);
-        req.send(Cursor::new(body))
+        Some(
+            options
+                .map(|t| t.get("body").unwrap_or_default())
+                .unwrap_or_default(),
+        )
     };
-    let res = match res {
-        Ok(res) | Err(ureq::Error::Status(_, res)) => res,
-        Err(e) => return Err(e.into_lua_err()),
+    charge(vm, Resource::HttpRequests, 1)?;
+    let _s = trace_span!("send_http_request", %method, %url, ?headers).entered();
+    let req = ureq::request_url(method.as_str(), &url);
+    let req = set_headers(req, &headers);
+    // the connection is closed once the evaluation runs out of time,
+    // even when the evaluation is cancelled and no longer waits for the response
+    let deadline = vm.app_data_ref::<Deadline>().map(|d| d.clone());
+    let send = move || {
+        // the time left is taken when the request starts, as it may wait for a thread
+        let req = match deadline.map(|d| d.remaining()) {
+            Some(remaining) if remaining.is_zero() => return Err(LuaError::runtime("timeout")),
+            Some(remaining) => req.timeout(remaining),
+            None => req,
+        };
+        let res = match body {
+            None => req.call(),
+            Some(body) => req.send(Cursor::new(body)),
+        };
+        match res {
+            Ok(res) | Err(ureq::Error::Status(_, res)) => Ok(res),
+            Err(e) => Err(e.into_lua_err()),
+        }
+    };
+    let cancel = vm.app_data_ref::<CancelHandle>().map(|c| c.clone());
+    let res = match &cancel {
+        Some(cancel) => send_cancellable(&FETCH_POOL, send, cancel)?,
+        None => send()?,
     };
     let charset = res.charset().to_string();
     let content_type = res.content_type().to_string();
@@ -118,7 +158,23 @@ fn lua_lmb_fetch(
     };
     let status_code = StatusCode::from_u16(res.status()).into_lua_err()?;
     trace!(%status_code, charset, content_type, "response");
-    let reader = Arc::new(Mutex::new(BufReader::new(res.into_reader())));
+    let reader = match cancel {
+        Some(cancel) => Box::new(CancellableReader {
+            cancel,
+            inner: res.into_reader(),
+        }),
+        None => res.into_reader(),
+    };
+    // Count bytes fetched over the network, including those buffered but not read yet.
+    let reader = match vm.app_data_ref::<Arc<Meter>>() {
+        Some(meter) => Box::new(MeteredReader::new(
+            reader,
+            meter.clone(),
+            Resource::FetchedBytes,
+        )),
+        None => reader,
+    };
+    let reader = Arc::new(Mutex::new(BufReader::new(reader)));
     Ok(LuaModHTTPResponse {
         charset,
         content_type,
@@ -128,6 +184,55 @@ fn lua_lmb_fetch(
     })
 }
 
+/// Send the request on a thread of the pool, and stop waiting for the response
+/// once the evaluation is cancelled. The thread is released when the request times out,
+/// and requests cancelled before a thread is available are never sent.
+fn send_cancellable<F, T>(pool: &ThreadPool, send: F, cancel: &CancelHandle) -> LuaResult<T>
+where
+    F: FnOnce() -> LuaResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    pool.spawn({
+        let cancel = cancel.clone();
+        move || {
+            if !cancel.is_cancelled() {
+                let _ = tx.send(send());
+            }
+        }
+    });
+    loop {
+        if cancel.is_cancelled() {
+            return Err(LuaError::runtime("cancelled"));
+        }
+        match rx.recv_timeout(CANCEL_POLL_INTERVAL) {
+            Ok(res) => return res,
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                if cancel.is_cancelled() {
+                    return Err(LuaError::runtime("cancelled"));
+                }
+                return Err(LuaError::runtime("HTTP request thread panicked"));
+            }
+        }
+    }
+}
+
+/// Reader of the response body, which fails once the evaluation is cancelled.
+struct CancellableReader {
+    cancel: CancelHandle,
+    inner: Box<dyn Read + Send + Sync + 'static>,
+}
+
+impl Read for CancellableReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancel.is_cancelled() {
+            return Err(io::Error::other("cancelled"));
+        }
+        self.inner.read(buf)
+    }
+}
+
 impl LuaUserData for LuaModHTTP {
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
         methods.add_method("fetch", lua_lmb_fetch);
@@ -136,12 +241,24 @@ impl LuaUserData for LuaModHTTP {
 
 #[cfg(test)]
 mod tests {
-    use std::io::empty;
+    use std::{
+        io::{empty, Read as _},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
 
+    use mlua::prelude::*;
     use mockito::Server;
+    use rayon::ThreadPoolBuilder;
     use serde_json::json;
 
-    use crate::Evaluation;
+    use super::send_cancellable;
+    use crate::{CancelHandle, Error, Evaluation};
 
     #[test]
     fn http_get() {
@@ -276,4 +393,99 @@ mod tests {
 
         post_mock.assert();
     }
+
+    #[test]
+    fn http_cancel() {
+        // connections are accepted by the backlog but never responded
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let script = format!(
+            r#"
+            local m = require('@lmb/http')
+            local res = m:fetch('http://{addr}/')
+            return res:read('*a')
+            "#
+        );
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let cancel = CancelHandle::default();
+        let start = Instant::now();
+        let handle = thread::spawn({
+            let cancel = cancel.clone();
+            move || e.evaluate().cancel(cancel).call()
+        });
+        thread::sleep(Duration::from_millis(100));
+        cancel.cancel();
+        assert!(matches!(handle.join().unwrap(), Err(Error::Cancelled)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn http_cancel_close_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let script = format!(
+            r#"
+            local m = require('@lmb/http')
+            return m:fetch('http://{addr}/').status_code
+            "#
+        );
+        let e = Evaluation::builder(script, empty())
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let cancel = CancelHandle::default();
+        let handle = thread::spawn({
+            let cancel = cancel.clone();
+            move || e.evaluate().cancel(cancel).call()
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        cancel.cancel();
+        assert!(matches!(handle.join().unwrap(), Err(Error::Cancelled)));
+
+        // the abandoned request times out with the evaluation and closes the connection
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let start = Instant::now();
+        let mut buf = [0; 1024];
+        while stream.read(&mut buf).unwrap() > 0 {}
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn cancel_release_thread() {
+        let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let cancel = CancelHandle::default();
+        thread::scope(|s| {
+            let handle = s.spawn(|| {
+                let wait = move || Ok(release_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+                send_cancellable(&pool, wait, &cancel)
+            });
+            thread::sleep(Duration::from_millis(50));
+            cancel.cancel();
+            let res = handle.join().unwrap();
+            assert!(matches!(res, Err(LuaError::RuntimeError(m)) if m == "cancelled"));
+        });
+
+        // the only thread is held by the abandoned request,
+        // and a request cancelled while waiting for it is never sent
+        let sent = Arc::new(AtomicBool::new(false));
+        let cancel = CancelHandle::default();
+        cancel.cancel();
+        let send = {
+            let sent = sent.clone();
+            move || {
+                sent.store(true, Ordering::Release);
+                Ok(())
+            }
+        };
+        assert!(send_cancellable(&pool, send, &cancel).is_err());
+
+        // the thread is released once the abandoned request ends
+        release_tx.send(()).unwrap();
+        let res = send_cancellable(&pool, || Ok(1), &CancelHandle::default());
+        assert_eq!(1, res.unwrap());
+        assert!(!sent.load(Ordering::Acquire));
+    }
 }
@@ -0,0 +1,178 @@
+use mlua::prelude::*;
+use std::collections::HashMap;
+
+use crate::{metrics::CustomMetric, Metrics};
+
+/// Metrics module
+pub struct LuaModMetrics {
+    pub metrics: Option<Metrics>,
+}
+
+impl LuaModMetrics {
+    fn register(
+        &self,
+        name: &str,
+        help: Option<String>,
+        labels: Option<Vec<String>>,
+        counter: bool,
+    ) -> LuaResult<LuaMetric> {
+        // metrics are discarded when they are not exported
+        let Some(metrics) = &self.metrics else {
+            return Ok(LuaMetric { metric: None });
+        };
+        let help = help.unwrap_or_else(|| name.to_string());
+        let metric = metrics
+            .register_custom(name, &help, &labels.unwrap_or_default(), counter)
+            .into_lua_err()?;
+        Ok(LuaMetric {
+            metric: Some(metric),
+        })
+    }
+}
+
+impl LuaUserData for LuaModMetrics {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method(
+            "counter",
+            |_, this, (name, help, labels): (String, Option<String>, Option<Vec<String>>)| {
+                this.register(&name, help, labels, true)
+            },
+        );
+        methods.add_method(
+            "gauge",
+            |_, this, (name, help, labels): (String, Option<String>, Option<Vec<String>>)| {
+                this.register(&name, help, labels, false)
+            },
+        );
+    }
+}
+
+/// Counter or gauge registered by the script.
+struct LuaMetric {
+    metric: Option<CustomMetric>,
+}
+
+type Labels = Option<HashMap<String, String>>;
+
+impl LuaUserData for LuaMetric {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("inc", |_, this, (value, labels): (Option<f64>, Labels)| {
+            let value = value.unwrap_or(1.0);
+            match &this.metric {
+                Some(CustomMetric::Counter(c)) => {
+                    if value < 0.0 {
+                        return Err(LuaError::runtime("counters cannot decrease"));
+                    }
+                    c.get_metric_with(&label_map(&labels))
+                        .into_lua_err()?
+                        .inc_by(value);
+                }
+                Some(CustomMetric::Gauge(g)) => {
+                    g.get_metric_with(&label_map(&labels))
+                        .into_lua_err()?
+                        .add(value);
+                }
+                None => {}
+            }
+            Ok(())
+        });
+        methods.add_method("dec", |_, this, (value, labels): (Option<f64>, Labels)| {
+            match &this.metric {
+                Some(CustomMetric::Counter(_)) => {
+                    return Err(LuaError::runtime("counters cannot decrease"));
+                }
+                Some(CustomMetric::Gauge(g)) => {
+                    g.get_metric_with(&label_map(&labels))
+                        .into_lua_err()?
+                        .sub(value.unwrap_or(1.0));
+                }
+                None => {}
+            }
+            Ok(())
+        });
+        methods.add_method("set", |_, this, (value, labels): (f64, Labels)| {
+            match &this.metric {
+                Some(CustomMetric::Counter(_)) => {
+                    return Err(LuaError::runtime("counters cannot be set"));
+                }
+                Some(CustomMetric::Gauge(g)) => {
+                    g.get_metric_with(&label_map(&labels))
+                        .into_lua_err()?
+                        .set(value);
+                }
+                None => {}
+            }
+            Ok(())
+        });
+    }
+}
+
+fn label_map(labels: &Labels) -> HashMap<&str, &str> {
+    labels
+        .iter()
+        .flatten()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::io::empty;
+    use test_case::test_case;
+
+    use crate::{Evaluation, Metrics};
+
+    #[test]
+    fn counter_and_gauge() {
+        let script = r#"
+        local m = require('@lmb/metrics')
+        local jobs = m:counter('jobs_total', 'Jobs processed', { 'kind' })
+        jobs:inc(1, { kind = 'a' })
+        jobs:inc(2, { kind = 'b' })
+        local queue = m:gauge('queue_size')
+        queue:set(5)
+        queue:dec()
+        queue:inc(3)
+        return m:counter('jobs_total', 'Jobs processed', { 'kind' }) ~= nil
+        "#;
+        let metrics = Metrics::default();
+        let e = Evaluation::builder(script, empty())
+            .metrics(metrics.clone())
+            .build()
+            .unwrap();
+        let res = e.evaluate().call().unwrap();
+        assert_eq!(json!(true), res.payload);
+        let text = metrics.encode().unwrap();
+        assert!(text.contains(r#"jobs_total{kind="a"} 1"#));
+        assert!(text.contains(r#"jobs_total{kind="b"} 2"#));
+        assert!(text.contains("queue_size 7"));
+    }
+
+    #[test]
+    fn without_metrics() {
+        let script = r#"
+        local m = require('@lmb/metrics')
+        m:counter('jobs_total'):inc()
+        m:gauge('queue_size'):set(1)
+        return true
+        "#;
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let res = e.evaluate().call().unwrap();
+        assert_eq!(json!(true), res.payload);
+    }
+
+    #[test_case("m:counter('c'):dec()")]
+    #[test_case("m:counter('c'):inc(-1)")]
+    #[test_case("m:counter('c'):set(1)")]
+    #[test_case("m:counter('c', 'C', { 'kind' }):inc()")]
+    #[test_case("m:counter('c'); m:gauge('c')")]
+    fn invalid_operation(statement: &str) {
+        let script = format!("local m = require('@lmb/metrics'); {statement}");
+        let e = Evaluation::builder(script, empty())
+            .metrics(Metrics::default())
+            .build()
+            .unwrap();
+        assert!(e.evaluate().call().is_err());
+    }
+}
@@ -1,33 +1,112 @@
 use bon::{builder, Builder};
 use mlua::prelude::*;
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::{
+    collections::HashMap,
+    fmt,
     io::{stderr, stdout, Read, Write as _},
     sync::Arc,
+    time::Instant,
 };
 
-use crate::{Input, Result, State, StateKey, Store};
+use crate::{
+    charge, require_module, Input, Metrics, Resource, Result, ScriptArgs, State, StateKey, Store,
+};
 
 use crypto::*;
 use http::*;
 use json::*;
+use metrics::*;
 use read::*;
 
 mod crypto;
 mod http;
 mod json;
+mod metrics;
 mod read;
 
 // ref: https://www.lua.org/pil/8.1.html
 const K_LOADED: &str = "_LOADED";
 
+const K_RAW_ERROR: &str = "lmb.raw_error";
+
+const K_RAW_REQUIRE: &str = "lmb.raw_require";
+
+// Tables raised by `error` are remembered before raising them unchanged, so they can be
+// recovered as `Error::Raised` rather than parsed from the message. Other values forget
+// the table, so a caught table cannot be mistaken for a later error.
+// The level is increased by one to skip this wrapper.
+const ERROR_WRAPPER: &str = r#"
+local raw_error, remember = ...
+return function(e, level)
+  remember(e)
+  if level == 0 then
+    raw_error(e, 0)
+  end
+  raw_error(e, (level or 1) + 1)
+end
+"#;
+
+/// Last table raised by the script, kept in the app data of the virtual machine.
+pub(crate) struct RaisedError {
+    /// The table converted into a string, which starts the message of the error.
+    pub(crate) message: String,
+    pub(crate) value: Value,
+}
+
+/// Callback receiving values emitted by the script with `m:emit(value)`.
+#[derive(Clone)]
+pub struct Emitter(Arc<dyn Fn(Value) -> LuaResult<()> + Send + Sync>);
+
+impl Emitter {
+    /// Create an emitter from a callback. An error returned by the callback
+    /// is raised in the script.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(Value) -> LuaResult<()> + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    /// Pass a value to the callback.
+    pub fn emit(&self, value: Value) -> LuaResult<()> {
+        (self.0)(value)
+    }
+}
+
+impl fmt::Debug for Emitter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Emitter").finish_non_exhaustive()
+    }
+}
+
+/// File uploaded in a multipart request body, available in `m.request.body.files`.
+/// Files are kept apart from the state because their content may not be valid UTF-8.
+#[derive(Builder, Clone, Debug)]
+pub struct UploadedFile {
+    /// Name of the form field.
+    #[builder(into)]
+    pub name: String,
+    /// File name sent by the client.
+    pub filename: Option<String>,
+    /// Content type sent by the client.
+    pub content_type: Option<String>,
+    /// Content of the file.
+    pub bytes: Vec<u8>,
+}
+
 /// Interface between Lua and Rust.
 #[derive(Builder, Debug)]
 pub struct LuaBinding<R>
 where
     R: Read,
 {
+    args: Option<Arc<ScriptArgs>>,
+    emitter: Option<Emitter>,
+    env: Option<Arc<HashMap<String, String>>>,
+    files: Option<Arc<[UploadedFile]>>,
     input: Input<R>,
+    metrics: Option<Metrics>,
     state: Option<Arc<State>>,
     store: Option<Store>,
 }
@@ -39,6 +118,11 @@ pub fn bind_vm<R>(
     #[builder(start_fn)] input: Input<R>,
     store: Option<Store>,
     state: Option<Arc<State>>,
+    emitter: Option<Emitter>,
+    files: Option<Arc<[UploadedFile]>>,
+    metrics: Option<Metrics>,
+    args: Option<Arc<ScriptArgs>>,
+    env: Option<Arc<HashMap<String, String>>>,
 ) -> Result<()>
 where
     for<'lua> R: 'lua + Read + Send,
@@ -47,7 +131,7 @@ where
 
     let read_fn = vm.create_function({
         let input = input.clone();
-        move |vm, f: Option<LuaValue>| lua_lmb_read(vm, &input, f)
+        move |vm, f: Option<LuaValue>| lua_lmb_read(vm, &input, f, Some(Resource::InputBytes))
     })?;
     io_table.set("read", read_fn)?;
 
@@ -64,10 +148,17 @@ where
 
     let globals = vm.globals();
     globals.set("io", io_table)?;
+    globals.set("error", lua_error_wrapper(vm)?)?;
+    globals.set("require", lua_require_wrapper(vm)?)?;
 
     let loaded = vm.named_registry_value::<LuaTable>(K_LOADED)?;
     let binding = LuaBinding::builder()
+        .maybe_args(args)
+        .maybe_emitter(emitter)
+        .maybe_env(env)
+        .maybe_files(files)
         .input(input)
+        .maybe_metrics(metrics.clone())
         .maybe_store(store)
         .maybe_state(state)
         .build();
@@ -75,11 +166,31 @@ where
     loaded.set("@lmb/crypto", LuaModCrypto {})?;
     loaded.set("@lmb/http", LuaModHTTP {})?;
     loaded.set("@lmb/json", LuaModJSON {})?;
+    loaded.set("@lmb/metrics", LuaModMetrics { metrics })?;
     vm.set_named_registry_value(K_LOADED, loaded)?;
 
     Ok(())
 }
 
+fn lua_require_wrapper(vm: &Lua) -> LuaResult<LuaFunction> {
+    // keep the builtin, since the global is replaced by the wrapper
+    let raw_require =
+        if let Some(f) = vm.named_registry_value::<Option<LuaFunction>>(K_RAW_REQUIRE)? {
+            f
+        } else {
+            let f = vm.globals().get::<LuaFunction>("require")?;
+            vm.set_named_registry_value(K_RAW_REQUIRE, &f)?;
+            f
+        };
+    vm.create_function(move |vm, name: String| {
+        if name.starts_with("./") || name.starts_with("../") {
+            require_module(vm, &name)
+        } else {
+            raw_require.call::<LuaValue>(name)
+        }
+    })
+}
+
 struct LuaStderr {}
 
 impl LuaUserData for LuaStderr {
@@ -98,10 +209,126 @@ impl LuaUserData for LuaStderr {
     }
 }
 
+fn lua_error_wrapper(vm: &Lua) -> LuaResult<LuaFunction> {
+    // keep the builtin, since the global is replaced by the wrapper
+    let raw_error = if let Some(f) = vm.named_registry_value::<Option<LuaFunction>>(K_RAW_ERROR)? {
+        f
+    } else {
+        let f = vm.globals().get::<LuaFunction>("error")?;
+        vm.set_named_registry_value(K_RAW_ERROR, &f)?;
+        f
+    };
+    let remember = vm.create_function(|vm, e: LuaValue| {
+        if !e.is_table() {
+            vm.remove_app_data::<RaisedError>();
+            return Ok(());
+        }
+        let message = e.to_string()?;
+        let value = vm.from_value::<Value>(e).unwrap_or(Value::Null);
+        vm.set_app_data(RaisedError { message, value });
+        Ok(())
+    })?;
+    vm.load(ERROR_WRAPPER)
+        .set_name("=error")
+        .call((raw_error, remember))
+}
+
+fn lua_uploaded_files(vm: &Lua, files: &[UploadedFile]) -> LuaResult<LuaTable> {
+    let t = vm.create_table()?;
+    for file in files {
+        let f = vm.create_table()?;
+        f.set("name", file.name.as_str())?;
+        f.set("filename", file.filename.as_deref())?;
+        f.set("content_type", file.content_type.as_deref())?;
+        f.set("bytes", vm.create_string(&file.bytes)?)?;
+        t.push(f)?;
+    }
+    Ok(t)
+}
+
+/// Methods of `m.response`, which write to the state directly.
+fn lua_response_metatable(vm: &Lua, state: Arc<State>) -> LuaResult<LuaTable> {
+    let methods = vm.create_table()?;
+    let set_cookie = vm.create_function(
+        move |vm, (_, name, value, options): (LuaValue, String, String, Option<LuaTable>)| {
+            let mut cookie = match options {
+                Some(options) => match vm.from_value(LuaValue::Table(options))? {
+                    Value::Object(options) => options,
+                    _ => Map::new(),
+                },
+                None => Map::new(),
+            };
+            // the server only passes signed cookies to scripts when it has a secret to sign them
+            let signed = cookie.get("signed").and_then(Value::as_bool) == Some(true);
+            if signed && !signs_cookies(&state) {
+                return Err(LuaError::runtime(format!(
+                    "cookie secret is required to sign cookie {name}"
+                )));
+            }
+            cookie.insert("name".into(), name.into());
+            cookie.insert("value".into(), value.into());
+            let mut response = state
+                .entry(StateKey::Response)
+                .or_insert_with(|| Value::Object(Map::new()));
+            let Value::Object(response) = &mut *response else {
+                return Err(LuaError::runtime("response must be a table"));
+            };
+            let cookies = response
+                .entry("cookies")
+                .or_insert_with(|| Value::Array(vec![]));
+            let Value::Array(cookies) = cookies else {
+                return Err(LuaError::runtime("cookies of response must be an array"));
+            };
+            cookies.push(Value::Object(cookie));
+            Ok(())
+        },
+    )?;
+    methods.set("set_cookie", set_cookie)?;
+    let metatable = vm.create_table()?;
+    metatable.set("__index", methods)?;
+    Ok(metatable)
+}
+
+/// Whether signed cookies are available, i.e. the request has `signed_cookies`.
+fn signs_cookies(state: &State) -> bool {
+    state
+        .get(&StateKey::Request)
+        .is_some_and(|r| r.get("signed_cookies").is_some())
+}
+
+/// Raise an error when signed cookies are read from a request without them,
+/// instead of leaving them nil.
+fn lua_request_metatable(vm: &Lua) -> LuaResult<LuaTable> {
+    let index = vm.create_function(|_, (_, key): (LuaTable, LuaValue)| {
+        if key.as_str().is_some_and(|k| k == "signed_cookies") {
+            return Err::<LuaValue, _>(LuaError::runtime(
+                "cookie secret is required to read signed cookies",
+            ));
+        }
+        Ok(LuaNil)
+    })?;
+    let metatable = vm.create_table()?;
+    metatable.set("__index", index)?;
+    Ok(metatable)
+}
+
 struct LuaStoreBinding {
+    metrics: Option<Metrics>,
     store: Option<Store>,
 }
 
+impl LuaStoreBinding {
+    /// Run a store operation, observing its duration when metrics are exported.
+    fn observe<T>(&self, operation: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_store(operation, start.elapsed());
+        }
+        result
+    }
+}
+
 impl LuaUserData for LuaStoreBinding {
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
         methods.add_method(
@@ -110,6 +337,7 @@ impl LuaUserData for LuaStoreBinding {
                 let Some(store) = &this.store else {
                     return Ok(LuaNil);
                 };
+                charge(vm, Resource::StoreWrites, keys.len() as u64)?;
                 let update_fn = |old: &mut Vec<Value>| -> LuaResult<()> {
                     let old_v = vm.to_value(old)?;
                     let new = f.call::<LuaValue>(old_v)?;
@@ -120,8 +348,8 @@ impl LuaUserData for LuaStoreBinding {
                     Some(v) => Some(vm.from_value(v)?),
                     None => None,
                 };
-                let value = store
-                    .update(&keys, update_fn, default_values)
+                let value = this
+                    .observe("update", || store.update(&keys, update_fn, default_values))
                     .into_lua_err()?;
                 vm.to_value(&value)
             },
@@ -130,7 +358,9 @@ impl LuaUserData for LuaStoreBinding {
             let Some(store) = &this.store else {
                 return Ok(LuaNil);
             };
-            let value = store.get(key.as_str()).into_lua_err()?;
+            let value = this
+                .observe("get", || store.get(key.as_str()))
+                .into_lua_err()?;
             match value {
                 Value::Null => Ok(LuaNil),
                 _ => vm.to_value(&value),
@@ -142,8 +372,10 @@ impl LuaUserData for LuaStoreBinding {
                 let Some(store) = &this.store else {
                     return Ok(LuaNil);
                 };
+                charge(vm, Resource::StoreWrites, 1)?;
                 let serialized = serde_json::to_value(&value).into_lua_err()?;
-                store.put(key, &serialized).into_lua_err()?;
+                this.observe("put", || store.put(key, &serialized))
+                    .into_lua_err()?;
                 vm.to_value(&value)
             },
         );
@@ -156,8 +388,17 @@ where
 {
     fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {
         fields.add_field("_VERSION", env!("APP_VERSION"));
+        fields.add_field_method_get("args", |vm, this| match &this.args {
+            Some(args) => args.to_lua(vm),
+            None => vm.create_table(),
+        });
+        fields.add_field_method_get("env", |vm, this| match &this.env {
+            Some(env) => vm.create_table_from(env.iter().map(|(k, v)| (k.as_str(), v.as_str()))),
+            None => vm.create_table(),
+        });
         fields.add_field_method_get("store", |_, this| {
             Ok(LuaStoreBinding {
+                metrics: this.metrics.clone(),
                 store: this.store.clone(),
             })
         });
@@ -165,25 +406,58 @@ where
             let Some(v) = this.state.as_ref().and_then(|m| m.get(&StateKey::Request)) else {
                 return Ok(LuaNil);
             };
-            vm.to_value(&*v)
+            let request = vm.to_value(&*v)?;
+            if let LuaValue::Table(request) = &request {
+                if v.get("signed_cookies").is_none() {
+                    request.set_metatable(Some(lua_request_metatable(vm)?));
+                }
+            }
+            if let (LuaValue::Table(request), Some(files)) = (&request, &this.files) {
+                if let Some(body) = request.get::<Option<LuaTable>>("body")? {
+                    body.set("files", lua_uploaded_files(vm, files)?)?;
+                }
+            }
+            Ok(request)
         });
         fields.add_field_method_get("response", |vm, this| {
-            let Some(v) = this.state.as_ref().and_then(|m| m.get(&StateKey::Response)) else {
+            let Some(state) = &this.state else {
                 return Ok(LuaNil);
             };
-            vm.to_value(&*v)
+            let response = match state.get(&StateKey::Response) {
+                Some(v) => vm.to_value(&*v)?,
+                None => LuaValue::Table(vm.create_table()?),
+            };
+            if let LuaValue::Table(t) = &response {
+                t.set_metatable(Some(lua_response_metatable(vm, state.clone())?));
+            }
+            Ok(response)
         });
         fields.add_field_method_set("response", |vm, this, value: LuaValue| {
             if let Some(v) = this.state.as_ref() {
-                v.insert(StateKey::Response, vm.from_value(value)?);
+                let mut value: Value = vm.from_value(value)?;
+                // keep cookies set by `m.response:set_cookie` when the response is replaced
+                let cookies = v
+                    .get(&StateKey::Response)
+                    .and_then(|old| old.get("cookies").cloned());
+                if let (Value::Object(new), Some(cookies)) = (&mut value, cookies) {
+                    new.entry("cookies").or_insert(cookies);
+                }
+                v.insert(StateKey::Response, value);
             }
             Ok(())
         });
     }
 
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("emit", |vm, this, value: LuaValue| {
+            let Some(emitter) = &this.emitter else {
+                return Ok(false);
+            };
+            emitter.emit(vm.from_value(value)?)?;
+            Ok(true)
+        });
         methods.add_method("read_unicode", |vm, this, f| {
-            lua_lmb_read_unicode(vm, &this.input, f)
+            lua_lmb_read_unicode(vm, &this.input, f, Some(Resource::InputBytes))
         });
     }
 }
@@ -191,10 +465,10 @@ where
 #[cfg(test)]
 mod tests {
     use serde_json::{json, Value};
-    use std::io::empty;
+    use std::{io::empty, sync::Arc};
     use test_case::test_case;
 
-    use crate::Evaluation;
+    use crate::{Evaluation, State, StateKey, UploadedFile};
 
     #[test]
     fn read_binary() {
@@ -342,4 +616,53 @@ mod tests {
         let res = e.evaluate().call().unwrap();
         assert_eq!(json!(null), res.payload);
     }
+
+    #[test]
+    fn set_cookie() {
+        let script = r#"
+        local m = require('@lmb')
+        m.response:set_cookie('a', '1')
+        m.response:set_cookie('b', '2', { http_only = true, max_age = 60 })
+        m.response = { status_code = 201 }
+        return m.response.status_code
+        "#;
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let state = Arc::new(State::new());
+        let res = e.evaluate().state(state.clone()).call().unwrap();
+        assert_eq!(json!(201), res.payload);
+        let expected = json!({
+            "status_code": 201,
+            "cookies": [
+                { "name": "a", "value": "1" },
+                { "name": "b", "value": "2", "http_only": true, "max_age": 60 },
+            ],
+        });
+        assert_eq!(expected, *state.get(&StateKey::Response).unwrap());
+    }
+
+    #[test]
+    fn uploaded_files() {
+        let script = r#"
+        local body = require('@lmb').request.body
+        local file = body.files[1]
+        return { body.fields.a[1], file.name, file.filename, file.content_type, #file.bytes }
+        "#;
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        let state = Arc::new(State::new());
+        state.insert(
+            StateKey::Request,
+            json!({ "body": { "fields": { "a": ["1"] } } }),
+        );
+        let file = UploadedFile::builder()
+            .name("f")
+            .filename("a.bin".to_string())
+            .content_type("application/octet-stream".to_string())
+            .bytes(vec![0, 159, 146, 150])
+            .build();
+        let res = e.evaluate().state(state).files(vec![file]).call().unwrap();
+        assert_eq!(
+            json!(["1", "f", "a.bin", "application/octet-stream", 4]),
+            res.payload
+        );
+    }
 }
@@ -2,15 +2,25 @@ use std::io::{BufRead as _, Read};
 
 use mlua::prelude::*;
 
-use crate::Input;
+use crate::{charge, Input, Resource};
+
+// Bytes are charged as the script consumes them rather than as the reader buffers them,
+// so reading a short line from a large input only counts the line.
+fn consumed(vm: &Lua, resource: Option<Resource>, count: usize) -> LuaResult<()> {
+    match resource {
+        Some(resource) => charge(vm, resource, count as u64),
+        None => Ok(()),
+    }
+}
 
 // This function intentionally uses Lua values instead of JSON values to pass bytes as partial,
 // invalid strings, allowing Lua to handle the bytes.
 // For a demonstration, see "count-bytes.lua".
-pub(crate) fn lua_lmb_read<'lua, R>(
-    vm: &'lua Lua,
+pub(crate) fn lua_lmb_read<R>(
+    vm: &Lua,
     input: &Input<R>,
     f: Option<LuaValue>,
+    resource: Option<Resource>,
 ) -> LuaResult<LuaValue>
 where
     R: Read,
@@ -20,6 +30,7 @@ where
         // https://www.lua.org/pil/21.1.html
         let mut buf = String::new();
         let count = input.lock().read_line(&mut buf)?;
+        consumed(vm, resource, count)?;
         if count == 0 {
             return Ok(LuaNil);
         }
@@ -35,6 +46,7 @@ where
         match f.as_ref() {
             "*a" | "*all" => {
                 let count = input.lock().read_to_string(&mut buf)?;
+                consumed(vm, resource, count)?;
                 if count == 0 {
                     return Ok(LuaNil);
                 }
@@ -42,6 +54,7 @@ where
             }
             "*l" | "*line" => {
                 let count = input.lock().read_line(&mut buf)?;
+                consumed(vm, resource, count)?;
                 if count == 0 {
                     return Ok(LuaNil);
                 }
@@ -50,6 +63,7 @@ where
             }
             "*n" | "*number" => {
                 let count = input.lock().read_to_string(&mut buf)?;
+                consumed(vm, resource, count)?;
                 if count == 0 {
                     return Ok(LuaNil);
                 }
@@ -64,6 +78,7 @@ where
     if let Some(i) = f.as_usize() {
         let mut buf = vec![0; i];
         let count = input.lock().read(&mut buf)?;
+        consumed(vm, resource, count)?;
         if count == 0 {
             return Ok(LuaNil);
         }
@@ -77,10 +92,11 @@ where
     Err(LuaError::runtime(format!("unexpected format {f}")))
 }
 
-pub(crate) fn lua_lmb_read_unicode<'lua, R>(
-    vm: &'lua Lua,
+pub(crate) fn lua_lmb_read_unicode<R>(
+    vm: &Lua,
     input: &Input<R>,
     f: LuaValue,
+    resource: Option<Resource>,
 ) -> LuaResult<LuaValue>
 where
     R: Read,
@@ -89,12 +105,14 @@ where
         match f.as_ref() {
             "*a" | "*all" => {
                 let mut s = vec![];
-                input.lock().read_to_end(&mut s).into_lua_err()?;
+                let count = input.lock().read_to_end(&mut s).into_lua_err()?;
+                consumed(vm, resource, count)?;
                 return Ok(LuaValue::String(vm.create_string(s)?));
             }
             "*l" | "*line" => {
                 let mut s = String::new();
-                input.lock().read_line(&mut s).into_lua_err()?;
+                let count = input.lock().read_line(&mut s).into_lua_err()?;
+                consumed(vm, resource, count)?;
                 return Ok(LuaValue::String(vm.create_string(s.trim())?));
             }
             _ => {}
@@ -107,6 +125,7 @@ where
         let mut single = 0;
         while remaining > 0 {
             let count = input.lock().read(std::slice::from_mut(&mut single))?;
+            consumed(vm, resource, count)?;
             if count == 0 {
                 break;
             }
@@ -3,34 +3,50 @@ use clap::{Parser, Subcommand};
 use clio::*;
 use comfy_table::{presets, Table};
 use cron::Schedule;
+use http::{HeaderName, Method};
 use lmb::{
-    Error, Evaluation, LuaCheck, PrintOptions, ScheduleOptions, Store, StoreOptions,
-    DEFAULT_TIMEOUT, EXAMPLES, GUIDES,
+    allowed_env,
+    serve::{
+        self, AuthOptions, Bind, CorsOptions, JwtOptions, Quota, RateLimitKey, RateLimitOptions,
+        ScriptRoute, ServeOptions, TlsOptions, DEFAULT_GRACE_PERIOD, DEFAULT_MAX_CONCURRENCY,
+        DEFAULT_MAX_FILES, DEFAULT_MAX_PARSED_BODY_SIZE, DEFAULT_SSE_KEEP_ALIVE,
+        DEFAULT_WEBSOCKET_IDLE_TIMEOUT,
+    },
+    BytecodeCache, Error, Evaluation, LuaCheck, ModuleOptions, PrintOptions, ScheduleOptions,
+    ScriptArgs, Shutdown, Store, StoreOptions, DEFAULT_POOL_SIZE, DEFAULT_TIMEOUT, EXAMPLES,
+    GUIDES,
 };
 use mlua::prelude::*;
 use rayon::prelude::*;
-use serde_json::json;
-use serve::ServeOptions;
+use serde_json::{json, Value};
 use std::{
+    fs, future,
     io::{self, Read},
-    net::SocketAddr,
     path::PathBuf,
     process::ExitCode,
     str::FromStr,
     time::Duration,
 };
 use termimad::MadSkin;
-use tracing::Level;
+use tokio::{signal, task};
+use tracing::{info, warn, Level};
 use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
-mod serve;
-
 static VERSION: &str = env!("APP_VERSION");
 
 /// lmb is a Lua function runner.
 #[derive(Parser)]
 #[command(about, author, version=VERSION)]
 struct Cli {
+    /// Cache compiled scripts, so unchanged scripts are not compiled again.
+    /// Disabled by default, and cached in `$XDG_CACHE_HOME/lmb` or `$HOME/.cache/lmb`
+    #[arg(long, env = "LMB_CACHE")]
+    cache: bool,
+
+    /// Directory of the cache of compiled scripts, which enables the cache
+    #[arg(long, env = "LMB_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+
     /// Checks the syntax of the function before evaluation or serving,
     /// disabled by default for startup performance
     #[arg(long, env = "LMB_CHECK_SYNTAX")]
@@ -70,8 +86,13 @@ struct Cli {
     command: Commands,
 }
 
+// parsed once, so the size of the serve command does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
+    /// Cache commands
+    #[command(subcommand)]
+    Cache(CacheCommands),
     /// Check syntax of script
     Check {
         /// Script path. Specify "-" or omit to load the script from standard input
@@ -81,9 +102,26 @@ enum Commands {
     /// Evaluate a script file
     #[command(alias = "eval")]
     Evaluate {
+        /// Positional arguments of the script in m.args, after "--"
+        #[arg(last = true)]
+        args: Vec<String>,
+        /// Pass environment variables whose names match the pattern to the script in m.env,
+        /// e.g. "APP_*". Can be repeated
+        #[arg(long, value_name = "PATTERN")]
+        env_allow: Vec<String>,
         /// Script path. Specify "-" or omit to load the script from standard input
         #[arg(long = "file", value_parser, default_value = "-")]
         files: Vec<Input>,
+        /// Maximum memory in bytes used by the Lua virtual machine
+        #[arg(long)]
+        memory_limit: Option<usize>,
+        /// Directory which local modules required with relative paths e.g. require('./lib/util')
+        /// must be inside. Defaults to the directory of the script
+        #[arg(long)]
+        module_root: Option<PathBuf>,
+        /// Named argument of the script in m.args, e.g. "key=value". Can be repeated
+        #[arg(long = "arg", value_name = "KEY=VALUE", value_parser = parse_named_arg)]
+        named_args: Vec<(String, String)>,
         /// Timeout in seconds
         #[arg(long, default_value_t = DEFAULT_TIMEOUT.as_secs())]
         timeout: u64,
@@ -98,36 +136,222 @@ enum Commands {
     ListThemes,
     /// Schedule the script as a cron job
     Schedule {
+        /// Positional arguments of the script in m.args, after "--"
+        #[arg(last = true)]
+        args: Vec<String>,
         /// Exit immediately upon N number of errors. 0 to disable.
         #[arg(long, default_value_t = 1)]
         bail: usize,
         /// Cron
         #[arg(long)]
         cron: String,
+        /// Pass environment variables whose names match the pattern to the script in m.env,
+        /// e.g. "APP_*". Can be repeated
+        #[arg(long, value_name = "PATTERN")]
+        env_allow: Vec<String>,
+        /// On SIGTERM or SIGINT, wait N seconds for running evaluations to finish
+        #[arg(long, default_value_t = DEFAULT_GRACE_PERIOD.as_secs())]
+        grace_period: u64,
         /// Run the script at startup even if the next execution is not due
         #[arg(long)]
         initial_run: bool,
         /// Script path. Specify "-" or omit to load the script from standard input
         #[arg(long = "file", value_parser, default_value = "-")]
         files: Vec<Input>,
+        /// Maximum memory in bytes used by the Lua virtual machine
+        #[arg(long)]
+        memory_limit: Option<usize>,
+        /// Directory which local modules required with relative paths e.g. require('./lib/util')
+        /// must be inside. Defaults to the directory of the script
+        #[arg(long)]
+        module_root: Option<PathBuf>,
+        /// Named argument of the script in m.args, e.g. "key=value". Can be repeated
+        #[arg(long = "arg", value_name = "KEY=VALUE", value_parser = parse_named_arg)]
+        named_args: Vec<(String, String)>,
     },
     /// Handle HTTP requests with the script
     Serve {
-        /// Bind the server to a specific host and port
+        /// Positional arguments of the script in m.args, after "--"
+        #[arg(last = true)]
+        args: Vec<String>,
+        /// htpasswd file of users for basic authentication,
+        /// with passwords hashed with bcrypt or SHA-1
+        #[arg(long, conflicts_with_all = ["auth_tokens", "auth_jwt_secret", "auth_jwks"])]
+        auth_htpasswd: Option<PathBuf>,
+        /// JWKS file of public keys to verify JSON Web Tokens signed with RS256
+        #[arg(long)]
+        auth_jwks: Option<PathBuf>,
+        /// Required audience of JSON Web Tokens
+        #[arg(long)]
+        auth_jwt_audience: Option<String>,
+        /// Required issuer of JSON Web Tokens
+        #[arg(long)]
+        auth_jwt_issuer: Option<String>,
+        /// Secret to verify JSON Web Tokens signed with HS256
+        #[arg(long, env = "LMB_AUTH_JWT_SECRET")]
+        auth_jwt_secret: Option<String>,
+        /// File of bearer tokens, one per line, optionally prefixed with a subject and a colon
+        #[arg(long, conflicts_with_all = ["auth_jwt_secret", "auth_jwks"])]
+        auth_tokens: Option<PathBuf>,
+        /// Bind the server to a specific host and port, or a Unix domain socket
+        /// e.g. `unix:/run/lmb.sock`. A listener passed with systemd socket activation
+        /// (`LISTEN_FDS`) takes precedence
         #[arg(long, default_value = "127.0.0.1:3000")]
         bind: String,
+        /// Compress responses with gzip, brotli or zstd, negotiated by Accept-Encoding
+        #[arg(long)]
+        compression: bool,
+        /// Secret key to sign and verify cookies with HMAC-SHA256,
+        /// required by scripts using signed cookies
+        #[arg(long, env = "LMB_COOKIE_SECRET")]
+        cookie_secret: Option<String>,
+        /// Allow credentials of cross-origin requests e.g. cookies
+        #[arg(long, requires = "cors_origin")]
+        cors_credentials: bool,
+        /// Allowed request header of cross-origin requests. Can be repeated.
+        /// Headers requested by preflight requests are allowed when omitted
+        #[arg(long, requires = "cors_origin")]
+        cors_header: Vec<HeaderName>,
+        /// Cache preflight responses for N seconds
+        #[arg(long, requires = "cors_origin")]
+        cors_max_age: Option<u64>,
+        /// Allowed method of cross-origin requests. Can be repeated.
+        /// Methods requested by preflight requests are allowed when omitted
+        #[arg(long, requires = "cors_origin")]
+        cors_method: Vec<Method>,
+        /// Allowed origin of cross-origin requests, or "*" for any origin. Can be repeated.
+        /// Preflight requests are answered without running scripts
+        #[arg(long)]
+        cors_origin: Vec<String>,
+        /// Respond errors with the message, source excerpt and traceback,
+        /// as HTML or "application/problem+json" by the Accept header
+        #[arg(long)]
+        dev: bool,
+        /// Directory of scripts. Each script handles the route mapped from its path,
+        /// e.g. "users/[id].get.lua" handles "GET /users/:id"
+        #[arg(long, conflicts_with = "file")]
+        dir: Option<PathBuf>,
+        /// Script building responses of errors. The error is available in m.request.error
+        #[arg(long)]
+        error_handler: Option<PathBuf>,
+        /// Pass environment variables whose names match the pattern to the script in m.env,
+        /// e.g. "APP_*". Can be repeated
+        #[arg(long, value_name = "PATTERN")]
+        env_allow: Vec<String>,
         /// Script path. Specify "-" or omit to load the script from standard input
         #[arg(long, value_parser, default_value = "-")]
         file: Input,
+        /// On SIGTERM or SIGINT, stop accepting connections and
+        /// wait N seconds for in-flight requests to finish
+        #[arg(long, default_value_t = DEFAULT_GRACE_PERIOD.as_secs())]
+        grace_period: u64,
+        /// Maximum size in bytes of request bodies.
+        /// Larger bodies are rejected with 413 Payload Too Large
+        #[arg(long)]
+        max_body_size: Option<usize>,
+        /// Maximum number of concurrent evaluations.
+        /// Requests beyond the limit are rejected with 503 Service Unavailable
+        #[arg(long, default_value_t = DEFAULT_MAX_CONCURRENCY)]
+        max_concurrency: usize,
+        /// Maximum size in bytes of each file uploaded in a multipart request body
+        #[arg(long)]
+        max_file_size: Option<usize>,
+        /// Maximum number of files uploaded in a multipart request body
+        #[arg(long, default_value_t = DEFAULT_MAX_FILES)]
+        max_files: usize,
+        /// Maximum size in bytes of form, multipart and JSON request bodies parsed into
+        /// m.request.body. Larger bodies are left unparsed, and can be read with io.read
+        #[arg(long, default_value_t = DEFAULT_MAX_PARSED_BODY_SIZE)]
+        max_parsed_body_size: usize,
+        /// Maximum memory in bytes used by each Lua virtual machine.
+        /// Requests going over the limit are responded with 500 Internal Server Error
+        #[arg(long)]
+        memory_limit: Option<usize>,
+        /// Export metrics on a separate host and port, or a Unix domain socket,
+        /// at --metrics-path or "/metrics"
+        #[arg(long)]
+        metrics_bind: Option<String>,
+        /// Export metrics in the Prometheus text format at the path, e.g. "/metrics".
+        /// The path is reserved, not handled by scripts, and does not require credentials
+        #[arg(long)]
+        metrics_path: Option<String>,
+        /// Directory which local modules required with relative paths e.g. require('./lib/util')
+        /// must be inside. Defaults to --dir, or the directory of the script
+        #[arg(long)]
+        module_root: Option<PathBuf>,
+        /// Named argument of the script in m.args, e.g. "key=value". Can be repeated
+        #[arg(long = "arg", value_name = "KEY=VALUE", value_parser = parse_named_arg)]
+        named_args: Vec<(String, String)>,
+        /// Discard idle Lua virtual machines after N seconds
+        #[arg(long)]
+        pool_idle_timeout: Option<u64>,
+        /// Maximum number of idle Lua virtual machines kept for reuse
+        #[arg(long, default_value_t = DEFAULT_POOL_SIZE)]
+        pool_size: usize,
+        /// Limit requests of each client to N in the period, e.g. "100/1m" with a unit of
+        /// s, m, h or d. Scripts in --dir may set their own quota in the front matter,
+        /// e.g. `rate_limit = "10/1m"`. Requests over the limit are rejected with 429 Too Many Requests
+        #[arg(long)]
+        rate_limit: Option<Quota>,
+        /// Identify clients by the value of the header instead of the IP address, e.g. "x-api-key"
+        #[arg(long, conflicts_with = "rate_limit_key_script")]
+        rate_limit_header: Option<HeaderName>,
+        /// Identify clients by the value returned by the script, which reads m.request.
+        /// Requests are not limited when the script returns nil
+        #[arg(long)]
+        rate_limit_key_script: Option<PathBuf>,
+        /// Keep rate limits in the store, so they survive restarts and are shared between
+        /// processes using the same store file. Requires --store-path and a migrated store
+        #[arg(long)]
+        rate_limit_persist: bool,
+        /// Respond 504 Gateway Timeout when the response does not start in N seconds
+        #[arg(long)]
+        request_timeout: Option<u64>,
+        /// Once the script emits an event, stop it when no event is emitted for N seconds,
+        /// instead of applying the timeout
+        #[arg(long)]
+        sse_idle_timeout: Option<u64>,
+        /// Send a keep-alive comment to event stream clients every N seconds
+        #[arg(long, default_value_t = DEFAULT_SSE_KEEP_ALIVE.as_secs())]
+        sse_keep_alive: u64,
         /// Timeout in seconds
         #[arg(long)]
         timeout: Option<u64>,
+        /// PEM file of the certificate chain to serve HTTPS
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// PEM file of CA certificates to verify client certificates.
+        /// The subject of the verified certificate is available in m.request.tls.peer
+        #[arg(long, requires = "tls_cert")]
+        tls_client_ca: Option<PathBuf>,
+        /// PEM file of the private key to serve HTTPS
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Permissions of the Unix domain socket in octal, e.g. 660
+        #[arg(long, value_parser = parse_mode)]
+        unix_socket_mode: Option<u32>,
+        /// Reload scripts when Lua scripts in their directories change.
+        /// Scripts are also reloaded on SIGHUP
+        #[arg(long)]
+        watch: bool,
+        /// Ping WebSocket connections without messages for N seconds,
+        /// and close them when the ping is not answered in N seconds either
+        #[arg(long, default_value_t = DEFAULT_WEBSOCKET_IDLE_TIMEOUT.as_secs())]
+        websocket_idle_timeout: u64,
     },
     /// Store commands
     #[command(subcommand)]
     Store(StoreCommands),
 }
 
+#[derive(Parser)]
+enum CacheCommands {
+    /// Remove all compiled scripts from the cache
+    Clear,
+    /// Show the number and total size of compiled scripts in the cache
+    Stats,
+}
+
 #[derive(Parser)]
 enum ExampleCommands {
     /// Print script of example
@@ -148,9 +372,40 @@ enum ExampleCommands {
         /// Bind the server to a specific host and port
         #[arg(long, default_value = "127.0.0.1:3000")]
         bind: String,
+        /// Secret key to sign and verify cookies with HMAC-SHA256,
+        /// required by scripts using signed cookies
+        #[arg(long, env = "LMB_COOKIE_SECRET")]
+        cookie_secret: Option<String>,
+        /// Maximum number of concurrent evaluations.
+        /// Requests beyond the limit are rejected with 503 Service Unavailable
+        #[arg(long, default_value_t = DEFAULT_MAX_CONCURRENCY)]
+        max_concurrency: usize,
+        /// Maximum size in bytes of each file uploaded in a multipart request body
+        #[arg(long)]
+        max_file_size: Option<usize>,
+        /// Maximum number of files uploaded in a multipart request body
+        #[arg(long, default_value_t = DEFAULT_MAX_FILES)]
+        max_files: usize,
+        /// Maximum size in bytes of form, multipart and JSON request bodies parsed into
+        /// m.request.body. Larger bodies are left unparsed, and can be read with io.read
+        #[arg(long, default_value_t = DEFAULT_MAX_PARSED_BODY_SIZE)]
+        max_parsed_body_size: usize,
         /// Example name
         #[arg(long)]
         name: String,
+        /// Discard idle Lua virtual machines after N seconds
+        #[arg(long)]
+        pool_idle_timeout: Option<u64>,
+        /// Maximum number of idle Lua virtual machines kept for reuse
+        #[arg(long, default_value_t = DEFAULT_POOL_SIZE)]
+        pool_size: usize,
+        /// Once the script emits an event, stop it when no event is emitted for N seconds,
+        /// instead of applying the timeout
+        #[arg(long)]
+        sse_idle_timeout: Option<u64>,
+        /// Send a keep-alive comment to event stream clients every N seconds
+        #[arg(long, default_value_t = DEFAULT_SSE_KEEP_ALIVE.as_secs())]
+        sse_keep_alive: u64,
         /// Timeout in seconds
         #[arg(long)]
         timeout: Option<u64>,
@@ -223,6 +478,29 @@ where
     Ok(())
 }
 
+fn parse_mode(s: &str) -> std::result::Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|err| format!("invalid mode {s}: {err}"))
+}
+
+fn parse_named_arg(s: &str) -> std::result::Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid argument {s}, expect key=value")),
+    }
+}
+
+fn script_args(positional: Vec<String>, named: Vec<(String, String)>) -> ScriptArgs {
+    ScriptArgs::builder()
+        .positional(positional.into_iter().map(Value::String).collect())
+        .named(
+            named
+                .into_iter()
+                .map(|(k, v)| (k, Value::String(v)))
+                .collect(),
+        )
+        .build()
+}
+
 fn read_script(input: &mut Input) -> anyhow::Result<(String, String)> {
     let name = input.path().to_string_lossy().to_string();
     let mut script = String::new();
@@ -232,7 +510,7 @@ fn read_script(input: &mut Input) -> anyhow::Result<(String, String)> {
 
 fn prepare_store(options: &StoreOptions) -> anyhow::Result<Store> {
     let store = if let Some(store_path) = &options.store_path {
-        let store = Store::new(&store_path)?;
+        let store = Store::new(store_path)?;
         if options.run_migrations {
             store.migrate(None)?;
         }
@@ -279,19 +557,76 @@ async fn try_main() -> anyhow::Result<()> {
         .maybe_store_path(cli.store_path)
         .run_migrations(cli.run_migrations)
         .build();
+    let use_cache = cli.cache || cli.cache_dir.is_some();
+    let cache_dir = cli.cache_dir.or_else(BytecodeCache::default_dir);
+    if use_cache && cache_dir.is_none() {
+        bail!("--cache-dir is required when $HOME is not set");
+    }
+    let cache = cache_dir
+        .as_ref()
+        .filter(|_| use_cache)
+        .map(BytecodeCache::new);
     match cli.command {
+        Commands::Cache(c) => {
+            let Some(cache_dir) = cache_dir else {
+                bail!("cache_dir is required");
+            };
+            let cache = BytecodeCache::new(cache_dir);
+            match c {
+                CacheCommands::Clear => {
+                    let removed = cache.clear()?;
+                    println!("{removed}");
+                    Ok(())
+                }
+                CacheCommands::Stats => {
+                    let stats = cache.stats()?;
+                    if cli.json {
+                        println!("{}", serde_json::to_string(&stats)?);
+                    } else {
+                        let mut table = Table::new();
+                        table.load_preset(presets::NOTHING);
+                        table.set_header(["directory", "entries", "size"]);
+                        table.add_row([
+                            &cache.dir().display().to_string(),
+                            &stats.entries.to_string(),
+                            &stats.bytes.to_string(),
+                        ]);
+                        println!("{table}");
+                    }
+                    Ok(())
+                }
+            }
+        }
         Commands::Check { files } => files.into_par_iter().try_for_each(|mut file| {
             let (name, script) = read_script(&mut file)?;
             do_check_syntax(cli.no_color, &name, &script)
         }),
-        Commands::Evaluate { files, timeout } => {
+        Commands::Evaluate {
+            args,
+            env_allow,
+            files,
+            memory_limit,
+            module_root,
+            named_args,
+            timeout,
+        } => {
             let store = prepare_store(&store_options)?;
+            let args = script_args(args, named_args);
+            let env = allowed_env(&env_allow);
             files.into_par_iter().try_for_each(|mut file| {
+                let modules = ModuleOptions::builder(file.path().to_path_buf())
+                    .maybe_root(module_root.clone())
+                    .build();
                 let (name, script) = read_script(&mut file)?;
                 if cli.check_syntax {
                     do_check_syntax(cli.no_color, &name, &script)?;
                 }
                 let e = Evaluation::builder(&script, io::stdin())
+                    .args(args.clone())
+                    .maybe_cache(cache.clone())
+                    .env(env.clone())
+                    .maybe_memory_limit(memory_limit)
+                    .modules(modules)
                     .name(name)
                     .store(store.clone())
                     .timeout(Duration::from_secs(timeout))
@@ -358,7 +693,16 @@ async fn try_main() -> anyhow::Result<()> {
         }
         Commands::Example(ExampleCommands::Serve {
             bind,
+            cookie_secret,
+            max_concurrency,
+            max_file_size,
+            max_files,
+            max_parsed_body_size,
             name,
+            pool_idle_timeout,
+            pool_size,
+            sse_idle_timeout,
+            sse_keep_alive,
             timeout,
         }) => {
             let Some(found) = EXAMPLES.iter().find(|e| e.name == name) else {
@@ -367,14 +711,24 @@ async fn try_main() -> anyhow::Result<()> {
             if cli.check_syntax {
                 do_check_syntax(cli.no_color, name.as_str(), &found.script)?;
             }
-            let bind = bind.parse::<SocketAddr>()?;
+            let bind = bind.parse::<Bind>()?;
             let timeout = timeout.map(Duration::from_secs);
-            let options = ServeOptions::builder(bind, &found.name, &found.script)
+            let routes = vec![ScriptRoute::builder(&found.name, &found.script).build()];
+            let options = ServeOptions::builder(bind, routes)
+                .maybe_cookie_secret(cookie_secret)
                 .json(cli.json)
+                .max_concurrency(max_concurrency)
+                .maybe_max_file_size(max_file_size)
+                .max_files(max_files)
+                .max_parsed_body_size(max_parsed_body_size)
+                .maybe_pool_idle_timeout(pool_idle_timeout.map(Duration::from_secs))
+                .pool_size(pool_size)
+                .maybe_sse_idle_timeout(sse_idle_timeout.map(Duration::from_secs))
+                .sse_keep_alive(Duration::from_secs(sse_keep_alive))
                 .store_options(store_options)
                 .maybe_timeout(timeout)
                 .build();
-            serve::serve_file(&options).await?;
+            serve::serve_file(&options, shutdown_signal()).await?;
             Ok(())
         }
         Commands::Guide(GuideCommands::List) => {
@@ -403,45 +757,236 @@ async fn try_main() -> anyhow::Result<()> {
             Ok(())
         }
         Commands::Schedule {
+            args,
             bail,
             cron,
+            env_allow,
             files,
+            grace_period,
             initial_run,
+            memory_limit,
+            module_root,
+            named_args,
         } => {
             let store = prepare_store(&store_options)?;
+            let args = script_args(args, named_args);
+            let env = allowed_env(&env_allow);
             let schedule = Schedule::from_str(&cron)?;
-            files.into_par_iter().try_for_each(|mut file| {
-                let (name, script) = read_script(&mut file)?;
-                let options = ScheduleOptions::builder()
-                    .bail(bail)
-                    .initial_run(initial_run)
-                    .schedule(schedule.clone())
-                    .build();
-                let e = Evaluation::builder(script, io::stdin())
-                    .name(name)
-                    .store(store.clone())
-                    .build()?;
-                e.schedule(&options);
-                Ok(())
-            })
+            let shutdown = Shutdown::default();
+            let mut schedulers = {
+                let shutdown = shutdown.clone();
+                let store = store.clone();
+                task::spawn_blocking(move || {
+                    files.into_par_iter().try_for_each(|mut file| {
+                        let modules = ModuleOptions::builder(file.path().to_path_buf())
+                            .maybe_root(module_root.clone())
+                            .build();
+                        let (name, script) = read_script(&mut file)?;
+                        let options = ScheduleOptions::builder()
+                            .bail(bail)
+                            .initial_run(initial_run)
+                            .schedule(schedule.clone())
+                            .shutdown(shutdown.clone())
+                            .build();
+                        let e = Evaluation::builder(script, io::stdin())
+                            .args(args.clone())
+                            .maybe_cache(cache.clone())
+                            .env(env.clone())
+                            .maybe_memory_limit(memory_limit)
+                            .modules(modules)
+                            .name(name)
+                            .store(store.clone())
+                            .build()?;
+                        e.schedule(&options);
+                        anyhow::Ok(())
+                    })
+                })
+            };
+            tokio::select! {
+                res = &mut schedulers => res??,
+                () = shutdown_signal() => {
+                    let grace_period = Duration::from_secs(grace_period);
+                    info!(?grace_period, "stop schedulers, wait for running evaluations");
+                    shutdown.trigger();
+                    match tokio::time::timeout(grace_period, &mut schedulers).await {
+                        Ok(res) => res??,
+                        Err(_elapsed) => warn!("grace period elapsed, drop running evaluations"),
+                    }
+                }
+            }
+            store.checkpoint()?;
+            Ok(())
         }
         Commands::Serve {
+            args,
+            auth_htpasswd,
+            auth_jwks,
+            auth_jwt_audience,
+            auth_jwt_issuer,
+            auth_jwt_secret,
+            auth_tokens,
             bind,
+            compression,
+            cookie_secret,
+            cors_credentials,
+            cors_header,
+            cors_max_age,
+            cors_method,
+            cors_origin,
+            dev,
+            dir,
+            env_allow,
+            error_handler,
             mut file,
+            grace_period,
+            max_body_size,
+            max_concurrency,
+            max_file_size,
+            max_files,
+            max_parsed_body_size,
+            memory_limit,
+            metrics_bind,
+            metrics_path,
+            module_root,
+            named_args,
+            pool_idle_timeout,
+            pool_size,
+            rate_limit,
+            rate_limit_header,
+            rate_limit_key_script,
+            rate_limit_persist,
+            request_timeout,
+            sse_idle_timeout,
+            sse_keep_alive,
             timeout,
+            tls_cert,
+            tls_client_ca,
+            tls_key,
+            unix_socket_mode,
+            watch,
+            websocket_idle_timeout,
         } => {
-            let (name, script) = read_script(&mut file)?;
+            let routes = if let Some(dir) = &dir {
+                ScriptRoute::scan_dir(dir)?
+            } else {
+                if watch && file.is_std() {
+                    bail!("--watch requires --file or --dir");
+                }
+                let path = (!file.is_std()).then(|| file.path().to_path_buf());
+                let (name, script) = read_script(&mut file)?;
+                vec![ScriptRoute::builder(name, script).maybe_file(path).build()]
+            };
+            let error_handler = match error_handler {
+                Some(path) => {
+                    let script = fs::read_to_string(&path)?;
+                    Some(
+                        ScriptRoute::builder(path.to_string_lossy(), script)
+                            .file(path)
+                            .build(),
+                    )
+                }
+                None => None,
+            };
+            let key_script = match rate_limit_key_script {
+                Some(path) => {
+                    let script = fs::read_to_string(&path)?;
+                    Some(
+                        ScriptRoute::builder(path.to_string_lossy(), script)
+                            .file(path)
+                            .build(),
+                    )
+                }
+                None => None,
+            };
             if cli.check_syntax {
-                do_check_syntax(cli.no_color, &name, &script)?;
+                for route in routes.iter().chain(&error_handler).chain(&key_script) {
+                    do_check_syntax(cli.no_color, route.name(), route.script())?;
+                }
             }
+            let tls = match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => Some(
+                    TlsOptions::builder(cert, key)
+                        .maybe_client_ca(tls_client_ca)
+                        .build(),
+                ),
+                _ => None,
+            };
+            let auth = if let Some(path) = auth_tokens {
+                Some(AuthOptions::Tokens(path))
+            } else if let Some(path) = auth_htpasswd {
+                Some(AuthOptions::Htpasswd(path))
+            } else if auth_jwt_secret.is_some() || auth_jwks.is_some() {
+                Some(AuthOptions::Jwt(
+                    JwtOptions::builder()
+                        .maybe_audience(auth_jwt_audience)
+                        .maybe_issuer(auth_jwt_issuer)
+                        .maybe_jwks(auth_jwks)
+                        .maybe_secret(auth_jwt_secret)
+                        .build(),
+                ))
+            } else {
+                if auth_jwt_audience.is_some() || auth_jwt_issuer.is_some() {
+                    bail!("--auth-jwt-secret or --auth-jwks is required to verify JSON Web Tokens");
+                }
+                None
+            };
+            let cors = (!cors_origin.is_empty()).then(|| {
+                CorsOptions::builder(cors_origin)
+                    .credentials(cors_credentials)
+                    .headers(cors_header)
+                    .maybe_max_age(cors_max_age.map(Duration::from_secs))
+                    .methods(cors_method)
+                    .build()
+            });
+            let rate_limit_key = match (rate_limit_header, key_script) {
+                (Some(name), _) => RateLimitKey::Header(name),
+                (None, Some(route)) => RateLimitKey::Script(route),
+                (None, None) => RateLimitKey::Ip,
+            };
+            let rate_limit = RateLimitOptions::builder()
+                .maybe_quota(rate_limit)
+                .key(rate_limit_key)
+                .persist(rate_limit_persist)
+                .build();
             let timeout = timeout.map(Duration::from_secs);
-            let bind = bind.parse::<SocketAddr>()?;
-            let options = ServeOptions::builder(bind, name, script)
+            let bind = bind.parse::<Bind>()?;
+            let metrics_bind = metrics_bind.map(|b| b.parse::<Bind>()).transpose()?;
+            let options = ServeOptions::builder(bind, routes)
+                .args(script_args(args, named_args))
+                .maybe_auth(auth)
+                .maybe_cache(cache)
+                .compression(compression)
+                .maybe_cookie_secret(cookie_secret)
+                .maybe_cors(cors)
+                .dev(dev)
+                .maybe_dir(dir)
+                .env(allowed_env(&env_allow))
+                .maybe_error_handler(error_handler)
+                .grace_period(Duration::from_secs(grace_period))
                 .json(cli.json)
+                .maybe_max_body_size(max_body_size)
+                .max_concurrency(max_concurrency)
+                .maybe_max_file_size(max_file_size)
+                .max_files(max_files)
+                .max_parsed_body_size(max_parsed_body_size)
+                .maybe_memory_limit(memory_limit)
+                .maybe_metrics_bind(metrics_bind)
+                .maybe_metrics_path(metrics_path)
+                .maybe_module_root(module_root)
+                .maybe_pool_idle_timeout(pool_idle_timeout.map(Duration::from_secs))
+                .pool_size(pool_size)
+                .rate_limit(rate_limit)
+                .maybe_request_timeout(request_timeout.map(Duration::from_secs))
+                .maybe_sse_idle_timeout(sse_idle_timeout.map(Duration::from_secs))
+                .sse_keep_alive(Duration::from_secs(sse_keep_alive))
                 .store_options(store_options)
                 .maybe_timeout(timeout)
+                .maybe_tls(tls)
+                .maybe_unix_socket_mode(unix_socket_mode)
+                .watch(watch)
+                .websocket_idle_timeout(Duration::from_secs(websocket_idle_timeout))
                 .build();
-            serve::serve_file(&options).await?;
+            serve::serve_file(&options, shutdown_signal()).await?;
             Ok(())
         }
         Commands::Store(c) => {
@@ -511,9 +1056,49 @@ async fn try_main() -> anyhow::Result<()> {
     }
 }
 
-#[tokio::main]
-async fn main() -> ExitCode {
-    if let Err(e) = try_main().await {
+/// Wait for SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = signal::ctrl_c().await {
+            warn!(?err, "failed to listen to SIGINT");
+            future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                warn!(?err, "failed to listen to SIGTERM");
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+    tokio::select! {
+        () = interrupt => info!("received SIGINT"),
+        () = terminate => info!("received SIGTERM"),
+    }
+}
+
+fn main() -> ExitCode {
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let res = runtime.block_on(try_main());
+    // evaluations still running after the grace period are not waited for
+    runtime.shutdown_background();
+    if let Err(e) = res {
         match e.downcast_ref::<Error>() {
             // the following errors are handled, do nothing
             Some(&Error::Lua(LuaError::RuntimeError(_) | LuaError::SyntaxError { .. })) => {}
@@ -0,0 +1,271 @@
+use parking_lot::Mutex;
+use prometheus::{
+    CounterVec, Encoder as _, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::Result;
+
+/// Content type of metrics encoded by [`Metrics::encode`].
+pub const METRICS_CONTENT_TYPE: &str = prometheus::TEXT_FORMAT;
+
+/// Metrics of evaluations, exported in the Prometheus text format.
+/// Clones share the same registry.
+///
+/// ```rust
+/// # use std::io::empty;
+/// use lmb::*;
+///
+/// # fn main() -> Result<()> {
+/// let metrics = Metrics::default();
+/// let e = Evaluation::builder("return 1", empty())
+///     .name("one".to_string())
+///     .metrics(metrics.clone())
+///     .build()?;
+/// e.evaluate().call()?;
+/// let text = metrics.encode()?;
+/// assert!(text.contains(r#"lmb_evaluation_duration_seconds_count{script="one"} 1"#));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    inner: Arc<MetricsInner>,
+}
+
+#[derive(Debug)]
+struct MetricsInner {
+    registry: Registry,
+    compile_errors: IntCounterVec,
+    evaluation_duration: HistogramVec,
+    evaluation_timeouts: IntCounterVec,
+    evaluations_in_flight: IntGauge,
+    peak_memory: IntGaugeVec,
+    requests: IntCounterVec,
+    store_duration: HistogramVec,
+    custom: Mutex<HashMap<String, CustomMetric>>,
+}
+
+/// Metric registered by scripts with `@lmb/metrics`.
+#[derive(Clone, Debug)]
+pub(crate) enum CustomMetric {
+    Counter(CounterVec),
+    Gauge(GaugeVec),
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new().expect("failed to register metrics")
+    }
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let compile_errors = IntCounterVec::new(
+            Opts::new("lmb_compile_errors_total", "Scripts failed to compile"),
+            &["script"],
+        )?;
+        let evaluation_duration = HistogramVec::new(
+            HistogramOpts::new("lmb_evaluation_duration_seconds", "Duration of evaluations"),
+            &["script"],
+        )?;
+        let evaluation_timeouts = IntCounterVec::new(
+            Opts::new("lmb_evaluation_timeouts_total", "Evaluations timed out"),
+            &["script"],
+        )?;
+        let evaluations_in_flight =
+            IntGauge::new("lmb_evaluations_in_flight", "Evaluations in progress")?;
+        let peak_memory = IntGaugeVec::new(
+            Opts::new(
+                "lmb_evaluation_peak_memory_bytes",
+                "Peak memory used by the Lua virtual machine in an evaluation",
+            ),
+            &["script"],
+        )?;
+        let requests = IntCounterVec::new(
+            Opts::new("lmb_http_requests_total", "HTTP requests handled"),
+            &["route", "method", "status"],
+        )?;
+        let store_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "lmb_store_operation_duration_seconds",
+                "Duration of store operations",
+            ),
+            &["operation"],
+        )?;
+        registry.register(Box::new(compile_errors.clone()))?;
+        registry.register(Box::new(evaluation_duration.clone()))?;
+        registry.register(Box::new(evaluation_timeouts.clone()))?;
+        registry.register(Box::new(evaluations_in_flight.clone()))?;
+        registry.register(Box::new(peak_memory.clone()))?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(store_duration.clone()))?;
+        Ok(Self {
+            inner: Arc::new(MetricsInner {
+                registry,
+                compile_errors,
+                evaluation_duration,
+                evaluation_timeouts,
+                evaluations_in_flight,
+                peak_memory,
+                requests,
+                store_duration,
+                custom: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// Encode all metrics in the Prometheus text format.
+    pub fn encode(&self) -> Result<String> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.inner.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    /// Count a script which fails to compile.
+    pub fn observe_compile_error(&self, script: &str) {
+        self.inner.compile_errors.with_label_values(&[script]).inc();
+    }
+
+    /// Count an HTTP request handled by the route with the status code of the response.
+    pub fn observe_request(&self, route: &str, method: &str, status: u16) {
+        self.inner
+            .requests
+            .with_label_values(&[route, method, &status.to_string()])
+            .inc();
+    }
+
+    pub(crate) fn observe_evaluation(&self, script: &str, duration: Duration, memory: usize) {
+        self.inner
+            .evaluation_duration
+            .with_label_values(&[script])
+            .observe(duration.as_secs_f64());
+        let peak = self.inner.peak_memory.with_label_values(&[script]);
+        let memory = i64::try_from(memory).unwrap_or(i64::MAX);
+        if memory > peak.get() {
+            peak.set(memory);
+        }
+    }
+
+    pub(crate) fn observe_timeout(&self, script: &str) {
+        self.inner
+            .evaluation_timeouts
+            .with_label_values(&[script])
+            .inc();
+    }
+
+    pub(crate) fn observe_store(&self, operation: &str, duration: Duration) {
+        self.inner
+            .store_duration
+            .with_label_values(&[operation])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Count an evaluation in progress until the guard is dropped.
+    pub(crate) fn start_evaluation(&self) -> InFlight {
+        self.inner.evaluations_in_flight.inc();
+        InFlight(self.inner.evaluations_in_flight.clone())
+    }
+
+    /// Register a metric of a script, or return the one registered with the name.
+    /// The kind and labels must match the registered one.
+    pub(crate) fn register_custom(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[String],
+        counter: bool,
+    ) -> Result<CustomMetric> {
+        let mut custom = self.inner.custom.lock();
+        if let Some(metric) = custom.get(name) {
+            let (registered, desc) = match metric {
+                CustomMetric::Counter(c) => (true, prometheus::core::Collector::desc(c)),
+                CustomMetric::Gauge(g) => (false, prometheus::core::Collector::desc(g)),
+            };
+            let same_labels = desc
+                .first()
+                .is_some_and(|d| d.variable_labels.as_slice() == labels);
+            if registered != counter || !same_labels {
+                return Err(prometheus::Error::AlreadyReg.into());
+            }
+            return Ok(metric.clone());
+        }
+        let labels = labels.iter().map(String::as_str).collect::<Vec<_>>();
+        let opts = Opts::new(name, help);
+        let metric = if counter {
+            let c = CounterVec::new(opts, &labels)?;
+            self.inner.registry.register(Box::new(c.clone()))?;
+            CustomMetric::Counter(c)
+        } else {
+            let g = GaugeVec::new(opts, &labels)?;
+            self.inner.registry.register(Box::new(g.clone()))?;
+            CustomMetric::Gauge(g)
+        };
+        custom.insert(name.to_string(), metric.clone());
+        Ok(metric)
+    }
+}
+
+/// Guard of an evaluation in progress.
+pub(crate) struct InFlight(IntGauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::Metrics;
+
+    #[test]
+    fn encode() {
+        let metrics = Metrics::default();
+        metrics.observe_request("index.lua", "GET", 200);
+        metrics.observe_compile_error("broken.lua");
+        metrics.observe_store("get", Duration::from_millis(1));
+        metrics.observe_evaluation("index.lua", Duration::from_millis(1), 2048);
+        metrics.observe_evaluation("index.lua", Duration::from_millis(1), 1024);
+        {
+            let _in_flight = metrics.start_evaluation();
+            let text = metrics.encode().unwrap();
+            assert!(text.contains("lmb_evaluations_in_flight 1"));
+        }
+        let text = metrics.encode().unwrap();
+        assert!(text
+            .contains(r#"lmb_http_requests_total{method="GET",route="index.lua",status="200"} 1"#));
+        assert!(text.contains(r#"lmb_compile_errors_total{script="broken.lua"} 1"#));
+        assert!(text.contains(r#"lmb_store_operation_duration_seconds_count{operation="get"} 1"#));
+        assert!(text.contains(r#"lmb_evaluation_peak_memory_bytes{script="index.lua"} 2048"#));
+        assert!(text.contains("lmb_evaluations_in_flight 0"));
+    }
+
+    #[test]
+    fn register_custom() {
+        let metrics = Metrics::default();
+        let labels = vec!["kind".to_string()];
+        metrics
+            .register_custom("jobs_total", "Jobs", &labels, true)
+            .unwrap();
+        metrics
+            .register_custom("jobs_total", "Jobs", &labels, true)
+            .unwrap();
+        assert!(metrics
+            .register_custom("jobs_total", "Jobs", &labels, false)
+            .is_err());
+        assert!(metrics
+            .register_custom("jobs_total", "Jobs", &[], true)
+            .is_err());
+        assert!(metrics
+            .register_custom("lmb_evaluations_in_flight", "In flight", &[], false)
+            .is_err());
+        assert!(metrics
+            .register_custom("invalid name", "Invalid", &[], true)
+            .is_err());
+    }
+}
@@ -0,0 +1,426 @@
+use bon::Builder;
+use mlua::prelude::*;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use crate::{compiler, Result};
+
+const K_MODULES: &str = "lmb.modules";
+
+/// Options of `require` of local modules, e.g. `require('./lib/util')`.
+///
+/// A module is resolved relative to the file requiring it, as `./lib/util.lua`
+/// or `./lib/util/init.lua`, and must be inside the root directory.
+///
+/// ```rust
+/// # use std::io::empty;
+/// # use assert_fs::{prelude::*, TempDir};
+/// # use serde_json::json;
+/// use lmb::*;
+///
+/// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+/// let dir = TempDir::new()?;
+/// dir.child("lib/util.lua")
+///     .write_str("return { add = function(a, b) return a + b end }")?;
+/// let script = "return require('./lib/util').add(1, 2)";
+/// let modules = ModuleOptions::builder(dir.child("main.lua").path()).build();
+/// let e = Evaluation::builder(script, empty()).modules(modules).build()?;
+/// assert_eq!(json!(3), e.evaluate().call()?.payload);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Builder, Clone, Debug)]
+pub struct ModuleOptions {
+    /// Path of the script. Modules required by the script are resolved relative to its directory.
+    #[builder(start_fn, into)]
+    script: PathBuf,
+    /// Directory which modules must be inside. Defaults to the directory of the script.
+    #[builder(into)]
+    root: Option<PathBuf>,
+}
+
+/// Module compiled from the file modified at the time.
+#[derive(Debug)]
+struct CompiledModule {
+    modified: SystemTime,
+    bytecode: Arc<[u8]>,
+}
+
+/// Local modules of a Lua virtual machine.
+#[derive(Debug)]
+pub(crate) struct Modules {
+    /// Compiled modules by files, shared with forks, so a module is compiled again
+    /// only when its file is modified.
+    compiled: Arc<Mutex<HashMap<PathBuf, CompiledModule>>>,
+    /// Directory of the script.
+    dir: PathBuf,
+    /// Files of modules being loaded, to resolve modules relative to them and detect circular requires.
+    loading: Mutex<Vec<PathBuf>>,
+    root: PathBuf,
+    /// Sources of loaded modules by chunk names, to render errors in modules.
+    sources: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Modules {
+    pub(crate) fn new(options: &ModuleOptions) -> Result<Self> {
+        let dir = match options.script.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = dir.canonicalize()?;
+        let root = match &options.root {
+            Some(root) => root.canonicalize()?,
+            None => dir.clone(),
+        };
+        Ok(Self {
+            compiled: Arc::default(),
+            dir,
+            loading: Mutex::new(Vec::new()),
+            root,
+            sources: Arc::default(),
+        })
+    }
+
+    /// Modules of another virtual machine, sharing the compiled modules.
+    pub(crate) fn fork(&self) -> Self {
+        Self {
+            compiled: self.compiled.clone(),
+            dir: self.dir.clone(),
+            loading: Mutex::new(Vec::new()),
+            root: self.root.clone(),
+            sources: self.sources.clone(),
+        }
+    }
+
+    /// Source of the module loaded as the chunk.
+    pub(crate) fn source(&self, chunk_name: &str) -> Option<String> {
+        self.sources.lock().get(chunk_name).cloned()
+    }
+
+    fn resolve(&self, name: &str) -> LuaResult<PathBuf> {
+        let dir = match self.loading.lock().last() {
+            Some(file) => file.parent().map(Path::to_path_buf).unwrap_or_default(),
+            None => self.dir.clone(),
+        };
+        let base = dir.join(name);
+        let candidates = [
+            PathBuf::from(format!("{}.lua", base.display())),
+            base.join("init.lua"),
+        ];
+        let Some(file) = candidates.iter().find(|c| c.is_file()) else {
+            return Err(LuaError::runtime(format!("module '{name}' not found")));
+        };
+        let file = file.canonicalize().into_lua_err()?;
+        if !file.starts_with(&self.root) {
+            return Err(LuaError::runtime(format!(
+                "module '{name}' is outside of the root directory"
+            )));
+        }
+        Ok(file)
+    }
+
+    fn chunk_name(&self, file: &Path) -> String {
+        file.strip_prefix(&self.root)
+            .unwrap_or(file)
+            .display()
+            .to_string()
+    }
+
+    fn load(&self, vm: &Lua, name: &str) -> LuaResult<LuaValue> {
+        let file = self.resolve(name)?;
+        let key = file.display().to_string();
+        let loaded = if let Some(t) = vm.named_registry_value::<Option<LuaTable>>(K_MODULES)? {
+            t
+        } else {
+            let t = vm.create_table()?;
+            vm.set_named_registry_value(K_MODULES, &t)?;
+            t
+        };
+        let value = loaded.raw_get::<LuaValue>(key.as_str())?;
+        if !value.is_nil() {
+            return Ok(value);
+        }
+
+        let chunk_name = self.chunk_name(&file);
+        {
+            let mut loading = self.loading.lock();
+            if loading.contains(&file) {
+                let chain = loading
+                    .iter()
+                    .skip_while(|f| **f != file)
+                    .chain([&file])
+                    .map(|f| self.chunk_name(f))
+                    .collect::<Vec<_>>();
+                return Err(LuaError::runtime(format!(
+                    "circular require of '{name}': {}",
+                    chain.join(" -> ")
+                )));
+            }
+            loading.push(file.clone());
+        }
+        let loaded_value = self
+            .compile(vm, &file, &chunk_name)
+            .and_then(|f| f.call::<LuaValue>(()));
+        self.loading.lock().pop();
+
+        let value = match loaded_value? {
+            LuaValue::Nil => LuaValue::Boolean(true),
+            value => value,
+        };
+        loaded.raw_set(key, &value)?;
+        Ok(value)
+    }
+
+    /// Function of the module, which is compiled again only when the file is modified.
+    fn compile(&self, vm: &Lua, file: &Path, chunk_name: &str) -> LuaResult<LuaFunction> {
+        let modified = fs::metadata(file)
+            .and_then(|m| m.modified())
+            .into_lua_err()?;
+        let cached = self
+            .compiled
+            .lock()
+            .get(file)
+            .filter(|c| c.modified == modified)
+            .map(|c| c.bytecode.clone());
+        if let Some(bytecode) = cached {
+            return vm.load(&*bytecode).set_name(chunk_name).into_function();
+        }
+        let source = fs::read_to_string(file).into_lua_err()?;
+        self.sources
+            .lock()
+            .insert(chunk_name.to_string(), source.clone());
+        let Ok(bytecode) = compiler().compile(&source) else {
+            // loaded from the source, so the syntax error is reported with the chunk name
+            return vm
+                .load(source)
+                .set_name(chunk_name)
+                .set_compiler(compiler())
+                .into_function();
+        };
+        let bytecode: Arc<[u8]> = bytecode.into();
+        self.compiled.lock().insert(
+            file.to_path_buf(),
+            CompiledModule {
+                modified,
+                bytecode: bytecode.clone(),
+            },
+        );
+        vm.load(&*bytecode).set_name(chunk_name).into_function()
+    }
+}
+
+/// Discard local modules loaded by previous evaluations.
+pub(crate) fn clear_modules(vm: &Lua) -> LuaResult<()> {
+    vm.unset_named_registry_value(K_MODULES)
+}
+
+/// Load the local module with the modules of the virtual machine.
+pub(crate) fn require_module(vm: &Lua, name: &str) -> LuaResult<LuaValue> {
+    let Some(modules) = vm.app_data_ref::<Arc<Modules>>().map(|m| m.clone()) else {
+        return Err(LuaError::runtime(format!(
+            "module '{name}' not found, local modules are not enabled"
+        )));
+    };
+    modules.load(vm, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use serde_json::json;
+    use std::{
+        fs::{self, File},
+        io::empty,
+        time::Duration,
+    };
+
+    use crate::{Error, Evaluation, ModuleOptions};
+
+    fn evaluate(dir: &TempDir, script: &str) -> crate::Result<serde_json::Value> {
+        let modules = ModuleOptions::builder(dir.child("main.lua").path()).build();
+        let e = Evaluation::builder(script, empty())
+            .name("main.lua".to_string())
+            .modules(modules)
+            .build()?;
+        Ok(e.evaluate().call()?.payload)
+    }
+
+    #[test]
+    fn require_relative() {
+        let dir = TempDir::new().unwrap();
+        dir.child("lib/a.lua")
+            .write_str("local b = require('./b') return { name = 'a', b = b.name }")
+            .unwrap();
+        dir.child("lib/b.lua")
+            .write_str("return { name = 'b' }")
+            .unwrap();
+        dir.child("lib/c/init.lua").write_str("return 'c'").unwrap();
+        dir.child("lib/d.lua")
+            .write_str("count = (count or 0) + 1 return count")
+            .unwrap();
+        let script = r#"
+        local a = require('./lib/a')
+        local d = require('./lib/d')
+        require('./lib/d')
+        return { a.name, a.b, require('./lib/c'), d, count }
+        "#;
+        assert_eq!(
+            json!(["a", "b", "c", 1, 1]),
+            evaluate(&dir, script).unwrap()
+        );
+    }
+
+    #[test]
+    fn reset_modules() {
+        let dir = TempDir::new().unwrap();
+        dir.child("counter.lua")
+            .write_str("count = (count or 0) + 1 return { count = count }")
+            .unwrap();
+        let modules = ModuleOptions::builder(dir.child("main.lua").path()).build();
+        let script = "local c = require('./counter') c.count = c.count + 1 return c.count";
+        let e = Evaluation::builder(script, empty())
+            .modules(modules)
+            .build()
+            .unwrap();
+        assert_eq!(json!(2), e.evaluate().call().unwrap().payload);
+        assert_eq!(json!(3), e.evaluate().call().unwrap().payload);
+        e.reset(empty()).unwrap();
+        assert_eq!(json!(2), e.evaluate().call().unwrap().payload);
+    }
+
+    #[test]
+    fn reuse_compiled_modules() {
+        let dir = TempDir::new().unwrap();
+        let module = dir.child("version.lua");
+        module.write_str("return 1").unwrap();
+        let modified = fs::metadata(module.path()).unwrap().modified().unwrap();
+        let modules = ModuleOptions::builder(dir.child("main.lua").path()).build();
+        let e = Evaluation::builder("return require('./version')", empty())
+            .modules(modules)
+            .build()
+            .unwrap();
+        assert_eq!(json!(1), e.evaluate().call().unwrap().payload);
+
+        // the module is not compiled again while the file is not modified
+        let set_modified = |time| {
+            let file = File::options().write(true).open(module.path()).unwrap();
+            file.set_modified(time).unwrap();
+        };
+        module.write_str("return 2").unwrap();
+        set_modified(modified);
+        e.reset(empty()).unwrap();
+        assert_eq!(json!(1), e.evaluate().call().unwrap().payload);
+        let forked = e.fork(empty()).unwrap();
+        assert_eq!(json!(1), forked.evaluate().call().unwrap().payload);
+
+        set_modified(modified + Duration::from_secs(1));
+        e.reset(empty()).unwrap();
+        assert_eq!(json!(2), e.evaluate().call().unwrap().payload);
+    }
+
+    #[test]
+    fn require_circular() {
+        let dir = TempDir::new().unwrap();
+        dir.child("a.lua")
+            .write_str("return require('./b')")
+            .unwrap();
+        dir.child("b.lua")
+            .write_str("return require('./a')")
+            .unwrap();
+        let Err(err) = evaluate(&dir, "return require('./a')") else {
+            panic!("expect error");
+        };
+        assert!(err
+            .to_string()
+            .contains("circular require of './a': a.lua -> b.lua -> a.lua"));
+    }
+
+    #[test]
+    fn require_outside_root() {
+        let parent = TempDir::new().unwrap();
+        parent
+            .child("secret.lua")
+            .write_str("return 'secret'")
+            .unwrap();
+        let dir = parent.child("app");
+        dir.create_dir_all().unwrap();
+        let modules = ModuleOptions::builder(dir.child("main.lua").path()).build();
+        let e = Evaluation::builder("return require('../secret')", empty())
+            .modules(modules)
+            .build()
+            .unwrap();
+        let Err(err) = e.evaluate().call() else {
+            panic!("expect error");
+        };
+        assert!(err
+            .to_string()
+            .contains("module '../secret' is outside of the root directory"));
+
+        let modules = ModuleOptions::builder(dir.child("main.lua").path())
+            .root(parent.path())
+            .build();
+        let e = Evaluation::builder("return require('../secret')", empty())
+            .modules(modules)
+            .build()
+            .unwrap();
+        assert_eq!(json!("secret"), e.evaluate().call().unwrap().payload);
+    }
+
+    #[test]
+    fn require_not_found() {
+        let dir = TempDir::new().unwrap();
+        let Err(err) = evaluate(&dir, "return require('./missing')") else {
+            panic!("expect error");
+        };
+        assert!(err.to_string().contains("module './missing' not found"));
+
+        let e = Evaluation::builder("return require('./missing')", empty())
+            .build()
+            .unwrap();
+        let Err(err) = e.evaluate().call() else {
+            panic!("expect error");
+        };
+        assert!(err.to_string().contains("local modules are not enabled"));
+    }
+
+    #[test]
+    fn error_in_module() {
+        let dir = TempDir::new().unwrap();
+        dir.child("lib/util.lua")
+            .write_str("local M = {}\nfunction M.fail()\n  error('boom')\nend\nreturn M")
+            .unwrap();
+        let modules = ModuleOptions::builder(dir.child("main.lua").path()).build();
+        let script = "local util = require('./lib/util')\nutil.fail()";
+        let e = Evaluation::builder(script, empty())
+            .name("main.lua".to_string())
+            .modules(modules)
+            .build()
+            .unwrap();
+        let Err(err) = e.evaluate().call() else {
+            panic!("expect error");
+        };
+        let mut buf = String::new();
+        err.write_lua_error(&mut buf, &e, true).unwrap();
+        assert!(buf.contains("lib/util.lua:3:"), "{buf}");
+        assert!(buf.contains("error('boom')"), "{buf}");
+
+        dir.child("lib/broken.lua").write_str("return !").unwrap();
+        let e = Evaluation::builder("return require('./lib/broken')", empty())
+            .modules(ModuleOptions::builder(dir.child("main.lua").path()).build())
+            .build()
+            .unwrap();
+        let Err(err @ Error::Lua(_)) = e.evaluate().call() else {
+            panic!("expect Lua error");
+        };
+        let mut buf = String::new();
+        err.write_lua_error(&mut buf, &e, true).unwrap();
+        assert!(buf.contains("lib/broken.lua:1:"), "{buf}");
+        assert!(buf.contains("return !"), "{buf}");
+    }
+}
@@ -0,0 +1,137 @@
+use bon::Builder;
+use mlua::prelude::*;
+use serde_json::{Map, Value};
+use std::{collections::HashMap, env};
+
+/// Arguments of a script, available in `m.args` with positional values
+/// in the array part and named values as fields.
+///
+/// ```rust
+/// # use std::io::empty;
+/// # use serde_json::{json, Map};
+/// use lmb::*;
+///
+/// # fn main() -> Result<()> {
+/// let mut named = Map::new();
+/// named.insert("n".into(), json!(2));
+/// let args = ScriptArgs::builder()
+///     .positional(vec![json!("a")])
+///     .named(named)
+///     .build();
+/// let e = Evaluation::builder("local m = require('@lmb') return m.args[1] .. m.args.n", empty())
+///     .args(args)
+///     .build()?;
+/// assert_eq!(json!("a2"), e.evaluate().call()?.payload);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Builder, Clone, Debug, Default, PartialEq)]
+pub struct ScriptArgs {
+    /// Positional arguments.
+    #[builder(default)]
+    pub positional: Vec<Value>,
+    /// Named arguments.
+    #[builder(default)]
+    pub named: Map<String, Value>,
+}
+
+impl ScriptArgs {
+    pub(crate) fn to_lua(&self, vm: &Lua) -> LuaResult<LuaTable> {
+        let t = vm.create_table()?;
+        for (k, v) in &self.named {
+            t.set(k.as_str(), vm.to_value(v)?)?;
+        }
+        for (i, v) in self.positional.iter().enumerate() {
+            t.set(i + 1, vm.to_value(v)?)?;
+        }
+        Ok(t)
+    }
+}
+
+/// Environment variables whose names match any of the patterns, to be passed
+/// to scripts as `m.env`. A pattern matches names literally, except that `*`
+/// matches any sequence of characters e.g. `APP_*`.
+pub fn allowed_env<S: AsRef<str>>(patterns: &[S]) -> HashMap<String, String> {
+    env::vars()
+        .filter(|(name, _)| patterns.iter().any(|p| matches_pattern(p.as_ref(), name)))
+        .collect()
+}
+
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        // no wildcard
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map};
+    use std::{collections::HashMap, io::empty};
+    use test_case::test_case;
+
+    use super::matches_pattern;
+    use crate::{Evaluation, ScriptArgs};
+
+    #[test_case("HOME", "HOME", true)]
+    #[test_case("HOME", "HOMES", false)]
+    #[test_case("APP_*", "APP_NAME", true)]
+    #[test_case("APP_*", "APP_", true)]
+    #[test_case("APP_*", "MY_APP_NAME", false)]
+    #[test_case("*_TOKEN", "GITHUB_TOKEN", true)]
+    #[test_case("A*B*C", "AxxBxxC", true)]
+    #[test_case("A*B*C", "AxxCxxB", false)]
+    #[test_case("AB*BC", "ABC", false)]
+    #[test_case("*", "ANYTHING", true)]
+    fn match_pattern(pattern: &str, name: &str, expected: bool) {
+        assert_eq!(expected, matches_pattern(pattern, name));
+    }
+
+    #[test]
+    fn args_and_env() {
+        let mut named = Map::new();
+        named.insert("key".into(), json!("value"));
+        named.insert("n".into(), json!(1));
+        let args = ScriptArgs::builder()
+            .positional(vec![json!("--foo"), json!("bar")])
+            .named(named)
+            .build();
+        let env = HashMap::from([("APP_NAME".to_string(), "lmb".to_string())]);
+        let script = r#"
+        local m = require('@lmb')
+        return { m.args[1], m.args[2], #m.args, m.args.key, m.args.n, m.env }
+        "#;
+        let e = Evaluation::builder(script, empty())
+            .args(args)
+            .env(env)
+            .build()
+            .unwrap();
+        let expected = json!(["--foo", "bar", 2, "value", 1, { "APP_NAME": "lmb" }]);
+        assert_eq!(expected, e.evaluate().call().unwrap().payload);
+
+        let forked = e.fork(empty()).unwrap();
+        assert_eq!(expected, forked.evaluate().call().unwrap().payload);
+        e.reset(empty()).unwrap();
+        assert_eq!(expected, e.evaluate().call().unwrap().payload);
+    }
+
+    #[test]
+    fn empty_args_and_env() {
+        let script = "local m = require('@lmb') return { #m.args, m.args.key, next(m.env) }";
+        let e = Evaluation::builder(script, empty()).build().unwrap();
+        assert_eq!(json!([0]), e.evaluate().call().unwrap().payload);
+    }
+}
@@ -0,0 +1,207 @@
+use bon::Builder;
+use parking_lot::Mutex;
+use std::{
+    io::Read,
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, trace};
+
+use crate::{Evaluation, Result, DEFAULT_POOL_SIZE};
+
+/// Options for [`EvaluationPool`].
+#[derive(Builder, Clone, Debug)]
+pub struct PoolOptions {
+    /// Maximum number of idle virtual machines kept in the pool.
+    #[builder(default = DEFAULT_POOL_SIZE)]
+    pub max_idle: usize,
+    /// Idle virtual machines are discarded after this duration.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Bounded pool of warm Lua virtual machines sharing one compiled script.
+///
+/// ```rust
+/// # use std::io::{empty, Cursor};
+/// # use serde_json::json;
+/// use lmb::*;
+///
+/// # fn main() -> Result<()> {
+/// let e = Evaluation::builder("return io.read('*a')", Cursor::new("")).build()?;
+/// let pool = EvaluationPool::new(e, PoolOptions::builder().build());
+/// {
+///     let e = pool.get(Cursor::new("1"))?;
+///     assert_eq!(json!("1"), e.evaluate().call()?.payload);
+/// }
+/// assert_eq!(1, pool.idle());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct EvaluationPool<R>
+where
+    for<'lua> R: 'lua + Read,
+{
+    evaluation: Arc<Evaluation<R>>,
+    idle: Mutex<Vec<(Instant, Arc<Evaluation<R>>)>>,
+    options: PoolOptions,
+}
+
+impl<R> EvaluationPool<R>
+where
+    for<'lua> R: 'lua + Read + Send,
+{
+    /// Create a pool from an evaluation. Virtual machines in the pool are forked from it,
+    /// so the script is compiled only once.
+    pub fn new(evaluation: Arc<Evaluation<R>>, options: PoolOptions) -> Arc<Self> {
+        Arc::new(Self {
+            evaluation,
+            idle: Mutex::new(Vec::new()),
+            options,
+        })
+    }
+
+    /// Take a virtual machine from the pool with the input.
+    /// A new one is forked when no idle virtual machine is available.
+    /// The virtual machine returns to the pool when the guard is dropped.
+    pub fn get(self: &Arc<Self>, input: R) -> Result<PooledEvaluation<R>> {
+        let reused = {
+            let mut idle = self.idle.lock();
+            if let Some(idle_timeout) = self.options.idle_timeout {
+                let before = idle.len();
+                idle.retain(|(since, _)| since.elapsed() < idle_timeout);
+                trace!(
+                    expired = before - idle.len(),
+                    "discard expired virtual machines"
+                );
+            }
+            idle.pop().map(|(_, e)| e)
+        };
+        let evaluation = if let Some(e) = reused {
+            e.reset(input)?;
+            e
+        } else {
+            debug!("fork virtual machine");
+            self.evaluation.fork(input)?
+        };
+        Ok(PooledEvaluation {
+            evaluation: Some(evaluation),
+            pool: self.clone(),
+        })
+    }
+
+    /// Number of idle virtual machines in the pool.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().len()
+    }
+
+    /// Evaluation which virtual machines are forked from.
+    pub fn evaluation(&self) -> &Arc<Evaluation<R>> {
+        &self.evaluation
+    }
+
+    fn put(&self, evaluation: Arc<Evaluation<R>>) {
+        let mut idle = self.idle.lock();
+        if idle.len() < self.options.max_idle {
+            idle.push((Instant::now(), evaluation));
+        }
+    }
+}
+
+/// Virtual machine taken from [`EvaluationPool`].
+#[derive(Debug)]
+pub struct PooledEvaluation<R>
+where
+    for<'lua> R: 'lua + Read + Send,
+{
+    evaluation: Option<Arc<Evaluation<R>>>,
+    pool: Arc<EvaluationPool<R>>,
+}
+
+impl<R> Deref for PooledEvaluation<R>
+where
+    for<'lua> R: 'lua + Read + Send,
+{
+    type Target = Arc<Evaluation<R>>;
+
+    fn deref(&self) -> &Self::Target {
+        self.evaluation
+            .as_ref()
+            .expect("evaluation is taken before drop")
+    }
+}
+
+impl<R> Drop for PooledEvaluation<R>
+where
+    for<'lua> R: 'lua + Read + Send,
+{
+    fn drop(&mut self) {
+        if let Some(e) = self.evaluation.take() {
+            self.pool.put(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::{io::Cursor, thread, time::Duration};
+
+    use crate::{Evaluation, EvaluationPool, PoolOptions};
+
+    #[test]
+    fn discard_global_state() {
+        let script = "n = (n or 0) + 1; return n";
+        let e = Evaluation::builder(script, Cursor::new(""))
+            .build()
+            .unwrap();
+        let pool = EvaluationPool::new(e, PoolOptions::builder().build());
+        for _ in 0..3 {
+            let e = pool.get(Cursor::new("")).unwrap();
+            assert_eq!(json!(1), e.evaluate().call().unwrap().payload);
+        }
+        assert_eq!(1, pool.idle());
+    }
+
+    #[test]
+    fn max_idle() {
+        let e = Evaluation::builder("return true", Cursor::new(""))
+            .build()
+            .unwrap();
+        let pool = EvaluationPool::new(e, PoolOptions::builder().max_idle(1).build());
+        {
+            let _a = pool.get(Cursor::new("")).unwrap();
+            let _b = pool.get(Cursor::new("")).unwrap();
+        }
+        assert_eq!(1, pool.idle());
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let e = Evaluation::builder("return true", Cursor::new(""))
+            .build()
+            .unwrap();
+        let options = PoolOptions::builder()
+            .idle_timeout(Duration::from_millis(10))
+            .build();
+        let pool = EvaluationPool::new(e, options);
+        drop(pool.get(Cursor::new("")).unwrap());
+        assert_eq!(1, pool.idle());
+        thread::sleep(Duration::from_millis(20));
+        let _e = pool.get(Cursor::new("")).unwrap();
+        assert_eq!(0, pool.idle());
+    }
+
+    #[test]
+    fn replace_input() {
+        let e = Evaluation::builder("return io.read('*a')", Cursor::new(""))
+            .build()
+            .unwrap();
+        let pool = EvaluationPool::new(e, PoolOptions::builder().build());
+        for input in ["a", "b"] {
+            let e = pool.get(Cursor::new(input)).unwrap();
+            assert_eq!(json!(input), e.evaluate().call().unwrap().payload);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use bon::Builder;
 use cron::Schedule;
+use parking_lot::{Condvar, Mutex};
+use std::{sync::Arc, time::Duration};
 
 use crate::Store;
 
@@ -12,6 +14,52 @@ pub struct ScheduleOptions {
     pub initial_run: bool,
     /// Cron expression.
     pub schedule: Schedule,
+    /// Stop the scheduler when triggered.
+    #[builder(default)]
+    pub shutdown: Shutdown,
     /// Store.
     pub store: Option<Store>,
 }
+
+/// Signal to stop schedulers, which wakes them up if they are sleeping.
+///
+/// ```rust
+/// # use std::{thread, time::Duration};
+/// use lmb::*;
+///
+/// let shutdown = Shutdown::default();
+/// let waiting = shutdown.clone();
+/// let handle = thread::spawn(move || waiting.wait_timeout(Duration::from_secs(60)));
+/// shutdown.trigger();
+/// assert!(handle.join().unwrap());
+/// assert!(shutdown.is_triggered());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Shutdown {
+    /// Trigger the signal and wake up all waiting threads.
+    pub fn trigger(&self) {
+        let (triggered, condvar) = &*self.inner;
+        *triggered.lock() = true;
+        condvar.notify_all();
+    }
+
+    /// Whether the signal is triggered.
+    pub fn is_triggered(&self) -> bool {
+        *self.inner.0.lock()
+    }
+
+    /// Block the current thread for the duration or until the signal is triggered.
+    /// Returns `true` if the signal is triggered.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (triggered, condvar) = &*self.inner;
+        let mut triggered = triggered.lock();
+        if !*triggered {
+            condvar.wait_while_for(&mut triggered, |t| !*t, timeout);
+        }
+        *triggered
+    }
+}
@@ -1,298 +1,3261 @@
-use crate::StoreOptions;
+//! HTTP server handling requests with Lua scripts.
+//!
+//! [`router`] builds an [`axum::Router`] which can be nested in other axum apps
+//! and wrapped with more layers, while [`serve_file`] binds and serves it.
+//!
+//! ```rust
+//! use lmb::serve::{router, ScriptRoute, ServeOptions};
+//! use lmb::StoreOptions;
+//! use std::net::SocketAddr;
+//!
+//! # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+//! let routes = vec![ScriptRoute::builder("hello.lua", "return 'hello'").build()];
+//! let options = ServeOptions::builder("127.0.0.1:3000".parse::<SocketAddr>()?, routes)
+//!     .store_options(StoreOptions::builder().build())
+//!     .build();
+//! let app = axum::Router::new().nest("/lua", router(&options).build()?);
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::bail;
+use arc_swap::ArcSwap;
 use axum::{
-    body::Bytes,
-    extract::{Path, State as AxumState},
-    http::{HeaderMap, Method, StatusCode},
-    response::IntoResponse,
-    routing::any,
+    body::{Body, Bytes},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, DefaultBodyLimit, FromRequestParts as _, Path, State as AxumState,
+    },
+    http::{header::RETRY_AFTER, request::Parts, HeaderMap, Method, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{any, get, on, MethodFilter, MethodRouter},
     Router,
 };
-use bon::Builder;
-use http::{HeaderName, HeaderValue};
-use lmb::{Evaluation, State, StateKey, Store};
+use base64::prelude::*;
+use bon::{builder, Builder};
+use cookie::{Cookie, SameSite};
+use futures_util::{stream, SinkExt as _, StreamExt as _};
+use hmac::{Hmac, Mac};
+use http::{
+    header::{ACCEPT, CONTENT_TYPE, COOKIE, HOST, SET_COOKIE},
+    HeaderName, HeaderValue, Request,
+};
+use listenfd::ListenFd;
+use mlua::prelude::*;
+use multer::{Constraints, Multipart, SizeLimit};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher as _};
+use parking_lot::Mutex;
 use serde_json::{Map, Value};
+use sha2::Sha256;
 use std::{
-    collections::HashMap, io::Cursor, net::SocketAddr, str::FromStr as _, sync::Arc, time::Duration,
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    fs,
+    future::{self, Future},
+    io::Cursor,
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    str::FromStr as _,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    runtime::Handle,
+    sync::{
+        mpsc::{self, error::SendTimeoutError},
+        oneshot, OwnedSemaphorePermit, Semaphore,
+    },
+    task,
+};
+use tower::ServiceExt as _;
+use tower_http::{
+    compression::CompressionLayer,
+    limit::RequestBodyLimitLayer,
+    trace::{self, TraceLayer},
 };
-use tower_http::trace::{self, TraceLayer};
-use tracing::{error, info, warn, Level};
+use tracing::{debug, error, info, warn, Level};
+use url::form_urlencoded;
+
+use crate::{
+    front_matter, BytecodeCache, CancelHandle, Emitter, Error, Evaluation, EvaluationPool,
+    LuaCheck, Metrics, ModuleOptions, PoolOptions, PooledEvaluation, ScriptArgs, State, StateKey,
+    Store, StoreOptions, UploadedFile, DEFAULT_POOL_SIZE, DEFAULT_TIMEOUT, METRICS_CONTENT_TYPE,
+};
+
+use auth::{authenticate, AuthClaims, Authenticator};
+pub use auth::{AuthOptions, JwtOptions};
+pub use cors::CorsOptions;
+pub use listener::Bind;
+use listener::{serve_until, Listener};
+use rate_limit::{check_store, Buckets, KeyOf, RateLimiter};
+pub use rate_limit::{Quota, RateLimitKey, RateLimitOptions};
+pub use tls::TlsOptions;
+use tls::{ReloadableTls, TlsInfo};
+
+mod auth;
+mod cors;
+mod listener;
+mod rate_limit;
+mod tls;
+
+/// Default maximum number of concurrent evaluations.
+pub const DEFAULT_MAX_CONCURRENCY: usize = 64;
+
+/// Default interval of keep-alive comments sent to event stream clients.
+pub const DEFAULT_SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Default time after which idle WebSocket connections are pinged, and closed
+/// when the ping is not answered in the same time.
+pub const DEFAULT_WEBSOCKET_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default maximum size in bytes of request bodies parsed into `m.request.body`.
+pub const DEFAULT_MAX_PARSED_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Default maximum number of files uploaded in a multipart request body.
+pub const DEFAULT_MAX_FILES: usize = 16;
+
+/// Default period to wait for in-flight requests on shutdown.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Default path where metrics are exported.
+pub const DEFAULT_METRICS_PATH: &str = "/metrics";
+
+/// Wait for further changes before reloading scripts.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Build the evaluation of a script, e.g. to set more options of [`Evaluation`].
+/// By default it is built by [`EvaluationContext::evaluation`].
+pub type EvaluationFactory = Arc<
+    dyn Fn(&ScriptRoute, &EvaluationContext) -> crate::Result<Arc<Evaluation<Cursor<Bytes>>>>
+        + Send
+        + Sync,
+>;
+
+/// Map a request to `m.request` after the default fields are set,
+/// e.g. to add fields from extensions inserted by layers of the embedder.
+pub type RequestMapper = Arc<dyn Fn(&Parts, &mut Map<String, Value>) + Send + Sync>;
+
+/// What evaluations of scripts are built with.
+#[derive(Clone, Debug)]
+pub struct EvaluationContext {
+    /// Arguments of scripts.
+    pub args: Option<ScriptArgs>,
+    /// Cache of compiled scripts.
+    pub cache: Option<BytecodeCache>,
+    /// Environment variables passed to scripts.
+    pub env: Option<HashMap<String, String>>,
+    /// Maximum memory in bytes used by each Lua virtual machine.
+    pub memory_limit: Option<usize>,
+    /// Metrics of evaluations, when metrics are exported.
+    pub metrics: Option<Metrics>,
+    /// Directory which local modules required by scripts must be inside.
+    /// Defaults to the directory of each script.
+    pub module_root: Option<PathBuf>,
+    /// Store shared by scripts.
+    pub store: Store,
+    /// Timeout of evaluations.
+    pub timeout: Option<Duration>,
+}
+
+impl EvaluationContext {
+    /// Build the evaluation of the script with the arguments, environment variables,
+    /// memory limit, metrics, store and timeout. Scripts read from files can require
+    /// local modules.
+    pub fn evaluation(&self, route: &ScriptRoute) -> crate::Result<Arc<Evaluation<Cursor<Bytes>>>> {
+        let modules = route.file.as_ref().map(|file| {
+            ModuleOptions::builder(file)
+                .maybe_root(self.module_root.clone())
+                .build()
+        });
+        Evaluation::builder(&route.script, Cursor::new(Bytes::new()))
+            .maybe_args(self.args.clone())
+            .maybe_cache(self.cache.clone())
+            .maybe_env(self.env.clone())
+            .maybe_memory_limit(self.memory_limit)
+            .maybe_metrics(self.metrics.clone())
+            .maybe_modules(modules)
+            .name(route.name.clone())
+            .maybe_timeout(self.timeout)
+            .store(self.store.clone())
+            .build()
+    }
+}
 
 #[derive(Builder, Clone)]
 struct AppState {
+    body_limits: BodyLimits,
+    cookie_secret: Option<String>,
+    errors: ErrorPages,
     json: bool,
-    #[builder(into)]
-    name: String,
-    #[builder(into)]
-    script: String,
-    store: Store,
-    timeout: Option<Duration>,
+    metrics: Option<Metrics>,
+    permits: Arc<Semaphore>,
+    pool: Arc<EvaluationPool<Cursor<Bytes>>>,
+    rate_limiter: Option<RateLimiter>,
+    request_mapper: Option<RequestMapper>,
+    /// Name of the script, to label metrics of requests.
+    route: String,
+    /// Stop streaming to clients which do not read a chunk or an event in time.
+    send_timeout: Duration,
+    sse_idle_timeout: Option<Duration>,
+    sse_keep_alive: Duration,
+    websocket_idle_timeout: Duration,
 }
 
-#[derive(Builder)]
-pub struct ServeOptions {
-    #[builder(start_fn, into)]
-    bind: SocketAddr,
+/// Script handling requests of a route.
+#[derive(Builder, Clone, Debug)]
+pub struct ScriptRoute {
     #[builder(start_fn, into)]
     name: String,
     #[builder(start_fn, into)]
     script: String,
+    /// Only handle requests with the method. All methods are handled when omitted
+    method: Option<Method>,
+    /// Route path e.g. `/users/:id`. All paths are handled when omitted
+    #[builder(into)]
+    path: Option<String>,
+    /// Limit requests of the route instead of the quota of the server
+    rate_limit: Option<Quota>,
+    /// File which the script is read from, to read it again when reloading
+    file: Option<PathBuf>,
+}
+
+impl ScriptRoute {
+    /// Name of the script.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The script.
+    pub fn script(&self) -> &str {
+        &self.script
+    }
+
+    /// Read the script again from the file. Scripts not read from files are kept.
+    fn reread(&self) -> anyhow::Result<ScriptRoute> {
+        let mut route = self.clone();
+        if let Some(file) = &self.file {
+            route.script = fs::read_to_string(file)?;
+        }
+        Ok(route)
+    }
+
+    /// Scan Lua scripts in the directory and map each of them to a route.
+    ///
+    /// The path of the file relative to the directory becomes the route path, where
+    /// `index.lua` maps to the directory itself, `[id].lua` maps to a path parameter `:id`,
+    /// and `[...rest].lua` maps to a wildcard `*rest`.
+    /// The method is taken from a suffix e.g. `users.post.lua`,
+    /// or from the front matter e.g. `method = "POST"`.
+    /// The quota of rate limiting is taken from the front matter e.g. `rate_limit = "10/1m"`.
+    pub fn scan_dir(dir: &FsPath) -> crate::Result<Vec<ScriptRoute>> {
+        let mut files = vec![];
+        collect_lua_files(dir, &mut files)?;
+        files.sort();
+
+        let mut routes: Vec<ScriptRoute> = vec![];
+        for file in files {
+            let relative = file.strip_prefix(dir).unwrap_or(&file);
+            let (path, suffix_method) = route_path(relative)?;
+            let script = fs::read_to_string(&file)?;
+            let front_matter = front_matter(&script);
+            let method = match front_matter
+                .as_ref()
+                .and_then(|t| t.get("method"))
+                .and_then(|m| m.as_str())
+            {
+                Some(m) => Some(Method::from_str(&m.to_uppercase()).map_err(|err| {
+                    Error::InvalidRoute(format!("invalid method of {}: {err}", file.display()))
+                })?),
+                None => suffix_method,
+            };
+            let rate_limit = front_matter
+                .as_ref()
+                .and_then(|t| t.get("rate_limit"))
+                .and_then(|q| q.as_str())
+                .map(|q| {
+                    Quota::from_str(q).map_err(|err| {
+                        Error::InvalidRoute(format!(
+                            "invalid rate_limit of {}: {err}",
+                            file.display()
+                        ))
+                    })
+                })
+                .transpose()?;
+            info!(path, ?method, file = %file.display(), "route");
+            routes.push(
+                ScriptRoute::builder(file.to_string_lossy(), script)
+                    .file(file.clone())
+                    .maybe_method(method)
+                    .path(path)
+                    .maybe_rate_limit(rate_limit)
+                    .build(),
+            );
+        }
+        check_routes(&routes)?;
+        Ok(routes)
+    }
+}
+
+/// Reject routes the router cannot hold together, instead of panicking when building it.
+fn check_routes(routes: &[ScriptRoute]) -> crate::Result<()> {
+    for (i, a) in routes.iter().enumerate() {
+        let Some(a_path) = &a.path else {
+            continue;
+        };
+        for b in &routes[i + 1..] {
+            let Some(b_path) = &b.path else {
+                continue;
+            };
+            if a_path == b_path {
+                if a.method.is_none() || b.method.is_none() || a.method == b.method {
+                    return Err(Error::InvalidRoute(format!(
+                        "{} and {} are mapped to the same route {a_path}",
+                        a.name, b.name
+                    )));
+                }
+            } else if paths_conflict(a_path, b_path) {
+                return Err(Error::InvalidRoute(format!(
+                    "{} and {} are mapped to conflicting routes {a_path} and {b_path}",
+                    a.name, b.name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether different paths conflict, i.e. they only differ in names of parameters,
+/// or they have a parameter and a wildcard in the same segment after the same prefix.
+fn paths_conflict(a: &str, b: &str) -> bool {
+    // names of parameters and wildcards are not part of the shape of the path
+    let shape = |segment: &str| match segment.chars().next() {
+        Some(c @ (':' | '*')) => c.to_string(),
+        _ => segment.to_string(),
+    };
+    let a_segments = a.split('/').map(shape).collect::<Vec<_>>();
+    let b_segments = b.split('/').map(shape).collect::<Vec<_>>();
+    if a_segments == b_segments {
+        return true;
+    }
+    for (a_segment, b_segment) in a_segments.iter().zip(&b_segments) {
+        match (a_segment.as_str(), b_segment.as_str()) {
+            (":", "*") | ("*", ":") => return true,
+            _ if a_segment != b_segment => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn collect_lua_files(dir: &FsPath, files: &mut Vec<PathBuf>) -> crate::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_lua_files(&path, files)?;
+        } else if path.extension().is_some_and(|e| e == "lua") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn route_path(relative: &FsPath) -> crate::Result<(String, Option<Method>)> {
+    let Some(stem) = relative.file_stem().map(|s| s.to_string_lossy()) else {
+        return Err(Error::InvalidRoute(format!(
+            "invalid file name {}",
+            relative.display()
+        )));
+    };
+    // other suffixes e.g. `feed.rss.lua` are part of the path
+    let (stem, method) = match stem.rsplit_once('.') {
+        Some((rest, suffix)) => match suffix_method(suffix) {
+            Some(method) => (rest.to_string(), Some(method)),
+            None => (stem.to_string(), None),
+        },
+        None => (stem.to_string(), None),
+    };
+
+    let mut segments = vec![];
+    if let Some(parent) = relative.parent() {
+        for component in parent.iter() {
+            segments.push(route_segment(&component.to_string_lossy()));
+        }
+    }
+    if stem != "index" {
+        segments.push(route_segment(&stem));
+    }
+    Ok((format!("/{}", segments.join("/")), method))
+}
+
+/// Standard method of the suffix of a file name, case-insensitive.
+fn suffix_method(suffix: &str) -> Option<Method> {
+    const METHODS: [Method; 9] = [
+        Method::GET,
+        Method::POST,
+        Method::PUT,
+        Method::PATCH,
+        Method::DELETE,
+        Method::HEAD,
+        Method::OPTIONS,
+        Method::TRACE,
+        Method::CONNECT,
+    ];
+    METHODS
+        .into_iter()
+        .find(|m| m.as_str().eq_ignore_ascii_case(suffix))
+}
+
+fn route_segment(segment: &str) -> String {
+    match segment.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        Some(name) => match name.strip_prefix("...") {
+            Some(rest) => format!("*{rest}"),
+            None => format!(":{name}"),
+        },
+        None => segment.to_string(),
+    }
+}
+
+/// Options of the server.
+#[derive(Builder, Clone, Debug)]
+pub struct ServeOptions {
+    #[builder(start_fn, into)]
+    bind: Bind,
+    #[builder(start_fn)]
+    routes: Vec<ScriptRoute>,
+    /// Arguments of scripts, available in `m.args`.
+    args: Option<ScriptArgs>,
+    /// Reject requests without valid credentials with 401 Unauthorized.
+    auth: Option<AuthOptions>,
+    /// Cache compiled scripts, so unchanged scripts are not compiled again on startup or reload.
+    cache: Option<BytecodeCache>,
+    /// Compress responses with gzip, brotli or zstd, negotiated by `Accept-Encoding`.
+    #[builder(default)]
+    compression: bool,
+    /// Secret key to sign and verify cookies with HMAC-SHA256.
+    cookie_secret: Option<String>,
+    /// Allow cross-origin requests.
+    cors: Option<CorsOptions>,
+    /// Environment variables passed to scripts, available in `m.env`.
+    env: Option<HashMap<String, String>>,
+    /// Respond errors with details e.g. the message, source excerpt and traceback.
+    #[builder(default)]
+    dev: bool,
+    /// Directory which routes are scanned from, to scan it again when reloading.
+    dir: Option<PathBuf>,
+    /// Script building responses of errors.
+    error_handler: Option<ScriptRoute>,
+    /// Period to wait for in-flight requests on shutdown.
+    grace_period: Option<Duration>,
+    /// Encode values returned by scripts in JSON.
+    #[builder(default)]
     json: bool,
+    /// Reject request bodies larger than the size in bytes with 413 Payload Too Large.
+    max_body_size: Option<usize>,
+    max_concurrency: Option<usize>,
+    max_file_size: Option<usize>,
+    max_files: Option<usize>,
+    max_parsed_body_size: Option<usize>,
+    /// Maximum memory in bytes used by each Lua virtual machine.
+    memory_limit: Option<usize>,
+    /// Export metrics on a separate listener instead of a path of the server.
+    #[builder(into)]
+    metrics_bind: Option<Bind>,
+    /// Export metrics in the Prometheus text format at the path.
+    /// Metrics do not require credentials of [`ServeOptionsBuilder::auth`].
+    #[builder(into)]
+    metrics_path: Option<String>,
+    /// Directory which local modules required by scripts must be inside.
+    /// Defaults to the scanned directory, or the directory of each script.
+    #[builder(into)]
+    module_root: Option<PathBuf>,
+    pool_idle_timeout: Option<Duration>,
+    pool_size: Option<usize>,
+    /// Respond 429 Too Many Requests when clients exceed the quota.
+    rate_limit: Option<RateLimitOptions>,
+    /// Respond 504 Gateway Timeout when the response does not start in time.
+    request_timeout: Option<Duration>,
+    sse_idle_timeout: Option<Duration>,
+    sse_keep_alive: Option<Duration>,
+    /// Ping WebSocket connections idle for the duration, and close them when not answered.
+    websocket_idle_timeout: Option<Duration>,
     store_options: StoreOptions,
     timeout: Option<Duration>,
+    /// Serve HTTPS with the certificate.
+    tls: Option<TlsOptions>,
+    /// Permissions of the Unix domain socket e.g. `0o660`.
+    unix_socket_mode: Option<u32>,
+    /// Reload routes when Lua scripts in the directories of the scripts change.
+    #[builder(default)]
+    watch: bool,
 }
 
-fn do_handle_request<S>(
+async fn handle_request(
     state: AppState,
-    method: Method,
-    path: S,
-    headers: HeaderMap,
+    path: String,
+    params: Option<HashMap<String, String>>,
+    parts: Parts,
     body: Bytes,
-) -> impl IntoResponse
-where
-    S: AsRef<str>,
-{
-    let e = match Evaluation::builder(state.script, Cursor::new(body))
-        .name(state.name)
-        .maybe_timeout(state.timeout)
-        .store(state.store.clone())
-        .build()
-    {
-        Ok(e) => e,
-        Err(err) => {
-            error!(?err, "failed to compile Lua code");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                HeaderMap::new(),
-                String::new(),
-            );
-        }
+) -> Response {
+    let observed = state
+        .metrics
+        .clone()
+        .map(|metrics| (metrics, state.route.clone(), parts.method.clone()));
+    let res = limit_request(state, path, params, parts, body).await;
+    if let Some((metrics, route, method)) = observed {
+        metrics.observe_request(&route, method.as_str(), res.status().as_u16());
+    }
+    res
+}
+
+/// Take a token before evaluating the request, and send the quota in `RateLimit-*` headers.
+async fn limit_request(
+    state: AppState,
+    path: String,
+    params: Option<HashMap<String, String>>,
+    parts: Parts,
+    body: Bytes,
+) -> Response {
+    // Reject instead of queuing when all evaluation slots are taken,
+    // so slow scripts cannot pile up requests without bound.
+    // The slot is taken before the key script of rate limiting runs.
+    let Ok(permit) = state.permits.clone().try_acquire_owned() else {
+        warn!("too many concurrent evaluations");
+        return (StatusCode::SERVICE_UNAVAILABLE, [(RETRY_AFTER, "1")]).into_response();
+    };
+    let Some(limiter) = &state.rate_limiter else {
+        return evaluate_request(state, path, params, parts, body, permit).await;
+    };
+    let request = || {
+        build_request(
+            path.clone(),
+            params.clone(),
+            &parts,
+            state.cookie_secret.as_deref(),
+            state.request_mapper.as_ref(),
+        )
     };
+    let decision = match limiter.check(&parts, request).await {
+        Ok(Some(decision)) => decision,
+        Ok(None) => return evaluate_request(state, path, params, parts, body, permit).await,
+        Err(res) => return res,
+    };
+    if let Some(res) = decision.reject() {
+        warn!(route = state.route, "too many requests");
+        return res;
+    }
+    let mut res = evaluate_request(state, path, params, parts, body, permit).await;
+    decision.apply(&mut res);
+    res
+}
 
-    let mut headers_map: Map<_, Value> = Map::new();
-    for (name, value) in headers {
-        if let Some(name) = name {
-            let value = value.to_str().unwrap_or("");
-            headers_map.insert(name.to_string(), value.into());
+async fn evaluate_request(
+    state: AppState,
+    path: String,
+    params: Option<HashMap<String, String>>,
+    mut parts: Parts,
+    body: Bytes,
+    permit: OwnedSemaphorePermit,
+) -> Response {
+    let ws = WebSocketUpgrade::from_request_parts(&mut parts, &state)
+        .await
+        .ok();
+    let parsed = parse_body(&parts.headers, &body, &state.body_limits).await;
+    let body = RequestBody { raw: body, parsed };
+    let (tx, rx) = oneshot::channel();
+    let cancel = CancelHandle::default();
+    let mut guard = CancelOnDrop(Some(cancel.clone()));
+    // The task outlives the response when the body is streamed,
+    // so the permit is released only after the last chunk.
+    task::spawn_blocking(move || {
+        let pending = PendingResponse { tx, cancel, permit };
+        do_handle_request(state, path, params, parts, body, ws, pending);
+    });
+    let res = rx.await.unwrap_or_else(|_| {
+        error!("evaluation task ended without a response");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    });
+    guard.0.take();
+    res
+}
+
+/// Channel of the response awaited by the client, the handle to cancel the evaluation,
+/// and the evaluation slot held until the request is handled.
+struct PendingResponse {
+    tx: oneshot::Sender<Response>,
+    cancel: CancelHandle,
+    permit: OwnedSemaphorePermit,
+}
+
+/// Cancel the evaluation when dropped before the response starts,
+/// e.g. the client disconnects or the request times out.
+struct CancelOnDrop(Option<CancelHandle>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.0.take() {
+            cancel.cancel();
         }
     }
+}
 
-    let mut request_map: Map<_, Value> = Map::new();
-    request_map.insert("method".into(), method.as_str().into());
-    request_map.insert("path".into(), path.as_ref().into());
-    request_map.insert("headers".into(), headers_map.into());
+fn do_handle_request(
+    state: AppState,
+    path: String,
+    params: Option<HashMap<String, String>>,
+    parts: Parts,
+    body: RequestBody,
+    ws: Option<WebSocketUpgrade>,
+    pending: PendingResponse,
+) {
+    let PendingResponse { tx, cancel, permit } = pending;
+    // The response is sent either when the script emits the first event,
+    // or when the script returns.
+    let response_tx = Arc::new(Mutex::new(Some(tx)));
+    let respond = |res: Response| match response_tx.lock().take() {
+        Some(tx) => tx.send(res).is_ok(),
+        None => false,
+    };
+
+    let mut request_map = build_request(
+        path,
+        params,
+        &parts,
+        state.cookie_secret.as_deref(),
+        state.request_mapper.as_ref(),
+    );
+    let html = prefers_html(&parts.headers);
+    let fail = |request: Value, detail: ErrorDetail| {
+        respond(state.errors.respond(request, &detail, html));
+    };
+
+    // the raw body is still available to `io.read`
+    let RequestBody { raw, parsed } = body;
+    let e = match state.pool.get(Cursor::new(raw)) {
+        Ok(e) => e,
+        Err(err) => {
+            error!(?err, "failed to prepare Lua virtual machine");
+            fail(request_map.into(), ErrorDetail::from_error(&err, None));
+            return;
+        }
+    };
 
+    let files = parsed.map(|parsed| {
+        request_map.insert("body".into(), parsed.value);
+        parsed.files
+    });
     let eval_state = Arc::new(State::new());
     eval_state.insert(StateKey::Request, request_map.into());
+    let request = || {
+        eval_state
+            .get(&StateKey::Request)
+            .map(|r| r.clone())
+            .unwrap_or_default()
+    };
+
+    let (event_tx, event_rx) = mpsc::channel::<Event>(1);
+    let event_tx = Arc::new(Mutex::new(Some(event_tx)));
+    let emitter = build_emitter(
+        &eval_state,
+        response_tx.clone(),
+        event_tx.clone(),
+        event_rx,
+        state.sse_keep_alive,
+        state.send_timeout,
+        state.cookie_secret.clone(),
+    );
+    let solution = e
+        .evaluate()
+        .state(eval_state.clone())
+        .emitter(emitter)
+        .maybe_idle_timeout(state.sse_idle_timeout)
+        .maybe_files(files.flatten())
+        .cancel(cancel)
+        .call();
+    // the event stream ends when the script returns
+    event_tx.lock().take();
+
+    let solution = match solution {
+        Ok(solution) => solution,
+        Err(crate::Error::Cancelled) => {
+            warn!("evaluation cancelled");
+            return;
+        }
+        Err(err) => {
+            error!(%err, "failed to run Lua script");
+            fail(request(), ErrorDetail::from_error(&err, Some(&e)));
+            return;
+        }
+    };
+    if response_tx.lock().is_none() {
+        // events have been sent, so the returned value is discarded
+        return;
+    }
+    if let Some(handler) = solution.handler {
+        let Some(ws) = ws else {
+            respond(StatusCode::UPGRADE_REQUIRED.into_response());
+            return;
+        };
+        // the connection keeps the slot of the upgrade request, as it keeps the virtual machine
+        let timeouts = WebSocketTimeouts {
+            idle: state.websocket_idle_timeout,
+            send: state.send_timeout,
+        };
+        respond(
+            ws.on_upgrade(move |socket| handle_websocket(socket, e, handler, timeouts, permit)),
+        );
+        return;
+    }
+    let (status_code, header_map) =
+        match build_response_head(&eval_state, state.cookie_secret.as_deref()) {
+            Ok(head) => head,
+            Err(err) => {
+                error!(?err, "failed to build response");
+                fail(request(), ErrorDetail::internal(&err));
+                return;
+            }
+        };
 
-    let e = Arc::new(e);
-    let res = e.evaluate().state(eval_state.clone()).call();
-    match res {
-        Ok(res) => match build_response(state.json, eval_state, &res.payload) {
-            Ok(t) => t,
+    let Some(iterator) = solution.iterator else {
+        match build_body(state.json, solution.payload, solution.bytes) {
+            Ok(body) => {
+                respond((status_code, header_map, body).into_response());
+            }
             Err(err) => {
                 error!(?err, "failed to build response");
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    HeaderMap::new(),
-                    String::new(),
-                )
+                fail(request(), ErrorDetail::internal(&err));
             }
-        },
-        Err(err) => {
-            error!(%err, "failed to run Lua script");
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                HeaderMap::new(),
-                String::new(),
-            )
+        }
+        return;
+    };
+
+    let (chunk_tx, chunk_rx) = mpsc::channel::<LuaResult<Bytes>>(1);
+    let stream = stream::unfold(chunk_rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    let res = (status_code, header_map, Body::from_stream(stream)).into_response();
+    if !respond(res) {
+        return;
+    }
+    loop {
+        // the timeout applies to each chunk, so streams may outlive the evaluation timeout
+        let chunk = match e.call_function::<_, LuaValue>(&iterator, ()).call() {
+            Ok(LuaValue::Nil) => break,
+            Ok(LuaValue::String(s)) => Ok(Bytes::copy_from_slice(&s.as_bytes())),
+            Ok(v) => v.to_string().map(Bytes::from),
+            Err(err) => Err(LuaError::external(err)),
+        };
+        let failed = chunk.is_err();
+        if let Err(err) = &chunk {
+            error!(%err, "failed to produce chunk");
+        }
+        // sending fails when the client disconnects and the body is dropped,
+        // or stops reading, which would hold the evaluation slot forever
+        match send_blocking(&chunk_tx, chunk, state.send_timeout) {
+            Ok(()) => {}
+            Err(SendTimeoutError::Timeout(_)) => {
+                warn!("client stopped reading, stop streaming");
+                break;
+            }
+            Err(SendTimeoutError::Closed(_)) => {
+                debug!("client disconnected, stop streaming");
+                break;
+            }
+        }
+        if failed {
+            break;
         }
     }
 }
 
-fn build_response(
-    json: bool,
-    state: Arc<State>,
-    value: &Value,
-) -> anyhow::Result<(StatusCode, HeaderMap, String)> {
-    let (status_code, headers) = state
-        .view(&StateKey::Response, |_k, res| {
-            let status_code = res
-                .get("status_code")
-                .and_then(|s| s.as_u64())
-                .unwrap_or(200u64);
-            let mut m = HashMap::new();
-            if let Some(h) = res.get("headers").and_then(|h| h.as_object()) {
-                for (name, value) in h.iter() {
-                    m.insert(
-                        name.to_string(),
-                        match value {
-                            Value::String(s) => s.to_string(),
-                            _ => value.to_string(),
-                        },
-                    );
+/// Build the emitter behind `m:emit(event)`. The first event turns the response
+/// into an event stream, with the status code and headers set by the script so far.
+fn build_emitter(
+    eval_state: &Arc<State>,
+    response_tx: Arc<Mutex<Option<oneshot::Sender<Response>>>>,
+    event_tx: Arc<Mutex<Option<mpsc::Sender<Event>>>>,
+    event_rx: mpsc::Receiver<Event>,
+    keep_alive: Duration,
+    send_timeout: Duration,
+    cookie_secret: Option<String>,
+) -> Emitter {
+    let eval_state = eval_state.clone();
+    let event_rx = Mutex::new(Some(event_rx));
+    Emitter::new(move |value| {
+        let event = build_event(value)?;
+        if let Some(tx) = response_tx.lock().take() {
+            let (status_code, header_map) =
+                build_response_head(&eval_state, cookie_secret.as_deref()).into_lua_err()?;
+            let Some(rx) = event_rx.lock().take() else {
+                return Err(LuaError::runtime("event stream is closed"));
+            };
+            let stream = stream::unfold(rx, |mut rx| async move {
+                rx.recv()
+                    .await
+                    .map(|event| (Ok::<_, Infallible>(event), rx))
+            });
+            let sse = Sse::new(stream).keep_alive(KeepAlive::new().interval(keep_alive));
+            if tx
+                .send((status_code, header_map, sse).into_response())
+                .is_err()
+            {
+                return Err(LuaError::runtime("client disconnected"));
+            }
+        }
+        let Some(sender) = event_tx.lock().clone() else {
+            return Err(LuaError::runtime("event stream is closed"));
+        };
+        // sending fails when the client disconnects or stops reading, which stops the script
+        send_blocking(&sender, event, send_timeout).map_err(|err| match err {
+            SendTimeoutError::Timeout(_) => {
+                warn!("client stopped reading, stop emitting events");
+                LuaError::runtime("client stopped reading")
+            }
+            SendTimeoutError::Closed(_) => {
+                debug!("client disconnected, stop emitting events");
+                LuaError::runtime("client disconnected")
+            }
+        })
+    })
+}
+
+/// Send from the blocking pool, giving up when the receiver is full for the timeout.
+fn send_blocking<T>(
+    tx: &mpsc::Sender<T>,
+    value: T,
+    timeout: Duration,
+) -> Result<(), SendTimeoutError<T>> {
+    Handle::current().block_on(tx.send_timeout(value, timeout))
+}
+
+fn build_event(value: Value) -> LuaResult<Event> {
+    let mut fields = match value {
+        Value::Object(fields) => fields,
+        data => Map::from_iter([("data".to_string(), data)]),
+    };
+    let mut event = Event::default();
+    for name in ["event", "id", "comment"] {
+        let Some(value) = fields.remove(name) else {
+            continue;
+        };
+        let Value::String(value) = value else {
+            return Err(LuaError::runtime(format!(
+                "{name} of event must be a string"
+            )));
+        };
+        // line breaks would be interpreted as another field
+        if value.contains(['\r', '\n', '\0']) {
+            return Err(LuaError::runtime(format!(
+                "{name} of event must not contain line breaks or null characters"
+            )));
+        }
+        event = match name {
+            "event" => event.event(value),
+            "id" => event.id(value),
+            _ => event.comment(value),
+        };
+    }
+    if let Some(retry) = fields.remove("retry") {
+        let Some(retry) = retry.as_u64() else {
+            return Err(LuaError::runtime("retry of event must be milliseconds"));
+        };
+        event = event.retry(Duration::from_millis(retry));
+    }
+    match fields.remove("data") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(data)) => {
+            if data.contains('\r') {
+                return Err(LuaError::runtime(
+                    "data of event must not contain carriage returns",
+                ));
+            }
+            event = event.data(data);
+        }
+        Some(data) => event = event.json_data(data).into_lua_err()?,
+    }
+    Ok(event)
+}
+
+/// Timeouts of a WebSocket connection.
+#[derive(Clone, Copy)]
+struct WebSocketTimeouts {
+    /// Ping the client when no message is received in time, and close the connection
+    /// when the ping is not answered in time either.
+    idle: Duration,
+    /// Stop callbacks sending to clients which do not read messages in time.
+    send: Duration,
+}
+
+/// Run the callbacks returned by the script for a WebSocket connection.
+/// The virtual machine is held until the connection is closed,
+/// so global variables of the script live as long as the connection.
+async fn handle_websocket(
+    socket: WebSocket,
+    e: PooledEvaluation<Cursor<Bytes>>,
+    handler: LuaTable,
+    timeouts: WebSocketTimeouts,
+    _permit: OwnedSemaphorePermit,
+) {
+    let (mut sink, mut stream) = socket.split();
+    let (message_tx, mut message_rx) = mpsc::channel::<Message>(16);
+    let mut writer = tokio::spawn(async move {
+        while let Some(message) = message_rx.recv().await {
+            let close = matches!(message, Message::Close(_));
+            if sink.send(message).await.is_err() || close {
+                break;
+            }
+        }
+    });
+
+    // callbacks are cancelled when the connection is dropped e.g. on shutdown
+    let cancel = CancelHandle::default();
+    let _guard = CancelOnDrop(Some(cancel.clone()));
+    let handler = Arc::new(WebSocketHandler {
+        cancel,
+        e,
+        handler,
+        socket: LuaWebSocket {
+            send_timeout: timeouts.send,
+            tx: message_tx.clone(),
+        },
+    });
+    let mut open = handler.call("on_open", None).await;
+    let mut pinged = false;
+    while open {
+        let received = match tokio::time::timeout(timeouts.idle, stream.next()).await {
+            Ok(received) => received,
+            Err(_) if pinged => {
+                warn!("WebSocket client did not answer the ping, closing connection");
+                break;
+            }
+            Err(_) => {
+                pinged = true;
+                let ping = Message::Ping(Vec::new());
+                if message_tx.send_timeout(ping, timeouts.send).await.is_err() {
+                    break;
                 }
+                continue;
             }
-            (status_code, m)
+        };
+        pinged = false;
+        let data = match received {
+            Some(Ok(Message::Text(text))) => text.into_bytes(),
+            Some(Ok(Message::Binary(data))) => data,
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+        };
+        open = handler.call("on_message", Some(data)).await;
+    }
+    handler.call("on_close", None).await;
+
+    let _ = message_tx
+        .send_timeout(Message::Close(None), timeouts.send)
+        .await;
+    drop(message_tx);
+    // the writer is stuck when the client does not read
+    match tokio::time::timeout(timeouts.send, &mut writer).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => error!(?err, "failed to close WebSocket connection"),
+        Err(_) => {
+            warn!("WebSocket client stopped reading, dropping connection");
+            writer.abort();
+        }
+    }
+}
+
+struct WebSocketHandler {
+    cancel: CancelHandle,
+    e: PooledEvaluation<Cursor<Bytes>>,
+    handler: LuaTable,
+    socket: LuaWebSocket,
+}
+
+impl WebSocketHandler {
+    /// Call the callback on the blocking pool, and return false when it fails.
+    async fn call(self: &Arc<Self>, name: &'static str, data: Option<Vec<u8>>) -> bool {
+        let this = self.clone();
+        let called = task::spawn_blocking(move || -> crate::Result<()> {
+            let Some(f) = this.handler.get::<Option<LuaFunction>>(name)? else {
+                return Ok(());
+            };
+            let data = data.map(mlua::BString::from);
+            this.e
+                .call_function(&f, (this.socket.clone(), data))
+                .cancel(this.cancel.clone())
+                .call()
         })
-        .unwrap_or_else(|| (200u64, HashMap::new()));
+        .await;
+        match called {
+            Ok(Ok(())) => true,
+            Ok(Err(crate::Error::Cancelled)) => {
+                warn!(name, "WebSocket callback cancelled");
+                false
+            }
+            Ok(Err(err)) => {
+                error!(%err, name, "failed to run WebSocket callback");
+                false
+            }
+            Err(err) => {
+                error!(?err, name, "failed to run WebSocket callback");
+                false
+            }
+        }
+    }
+}
 
-    let status_code = StatusCode::from_u16(u16::try_from(status_code)?)?;
-    let mut header_map = HeaderMap::new();
-    for (name, value) in headers.iter() {
-        header_map.insert(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+/// WebSocket connection passed to the callbacks.
+#[derive(Clone)]
+struct LuaWebSocket {
+    send_timeout: Duration,
+    tx: mpsc::Sender<Message>,
+}
+
+impl LuaWebSocket {
+    /// Send from the callback, returning false when the connection is closed.
+    /// Clients which do not read in time stop the callback, as it cannot be
+    /// interrupted while waiting.
+    fn send(&self, message: Message) -> LuaResult<bool> {
+        match send_blocking(&self.tx, message, self.send_timeout) {
+            Ok(()) => Ok(true),
+            Err(SendTimeoutError::Timeout(_)) => {
+                warn!("WebSocket client stopped reading");
+                Err(LuaError::runtime("client stopped reading"))
+            }
+            Err(SendTimeoutError::Closed(_)) => Ok(false),
+        }
     }
-    let body = if json {
-        serde_json::to_string(&value)?
-    } else {
-        match value {
-            Value::String(s) => s.to_string(),
-            _ => value.to_string(),
+}
+
+impl LuaUserData for LuaWebSocket {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("send", |vm, this, value: LuaValue| {
+            let message = match value {
+                LuaValue::String(s) => match s.to_str() {
+                    Ok(text) => Message::Text(text.to_string()),
+                    Err(_) => Message::Binary(s.as_bytes().to_vec()),
+                },
+                value => {
+                    let value: Value = vm.from_value(value)?;
+                    Message::Text(value.to_string())
+                }
+            };
+            this.send(message)
+        });
+        methods.add_method("close", |_, this, ()| this.send(Message::Close(None)));
+    }
+}
+
+/// Details of an error turned into a response.
+struct ErrorDetail {
+    status_code: StatusCode,
+    message: String,
+    /// Source excerpt rendered by [`crate::Error::write_lua_error`].
+    excerpt: Option<String>,
+    traceback: Option<String>,
+    /// Table raised by the script e.g. `error({ status = 404 })`.
+    value: Option<Value>,
+}
+
+impl ErrorDetail {
+    fn new<S: Into<String>>(status_code: StatusCode, message: S) -> Self {
+        Self {
+            status_code,
+            message: message.into(),
+            excerpt: None,
+            traceback: None,
+            value: None,
         }
-    };
-    Ok((status_code, header_map, body))
+    }
+
+    fn internal(err: &anyhow::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+    }
+
+    fn from_error(err: &crate::Error, e: Option<&Evaluation<Cursor<Bytes>>>) -> Self {
+        if let crate::Error::Raised(value) = err {
+            // only error statuses can be raised
+            let status_code = value
+                .get("status")
+                .and_then(|s| s.as_u64())
+                .and_then(|s| u16::try_from(s).ok())
+                .and_then(|s| StatusCode::from_u16(s).ok())
+                .filter(|s| s.is_client_error() || s.is_server_error())
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            let message = value
+                .get("message")
+                .and_then(|m| m.as_str())
+                .or_else(|| status_code.canonical_reason())
+                .unwrap_or_default();
+            let mut detail = Self::new(status_code, message);
+            detail.value = Some(value.clone());
+            return detail;
+        }
+        let message = err.to_string();
+        let (message, traceback) = match message.split_once("\nstack traceback:\n") {
+            Some((message, traceback)) => (message.to_string(), Some(traceback.to_string())),
+            None => (message, None),
+        };
+        let excerpt = e.and_then(|e| {
+            let mut buf = String::new();
+            err.write_lua_error(&mut buf, e, true).ok()?;
+            (!buf.is_empty()).then_some(buf)
+        });
+        Self {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            message,
+            excerpt,
+            traceback,
+            value: None,
+        }
+    }
+
+    fn to_problem(&self) -> Value {
+        let mut problem = Map::new();
+        problem.insert("type".into(), "about:blank".into());
+        if let Some(title) = self.status_code.canonical_reason() {
+            problem.insert("title".into(), title.into());
+        }
+        problem.insert("status".into(), self.status_code.as_u16().into());
+        problem.insert("detail".into(), self.message.clone().into());
+        if let Some(excerpt) = &self.excerpt {
+            problem.insert("excerpt".into(), excerpt.clone().into());
+        }
+        if let Some(traceback) = &self.traceback {
+            problem.insert("traceback".into(), traceback.clone().into());
+        }
+        if let Some(value) = &self.value {
+            problem.insert("error".into(), value.clone());
+        }
+        problem.into()
+    }
+
+    fn to_html(&self) -> String {
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<pre>{1}</pre>\n",
+            escape_html(&self.status_code.to_string()),
+            escape_html(&self.message),
+        );
+        if let Some(excerpt) = &self.excerpt {
+            html.push_str(&format!(
+                "<h2>Source</h2>\n<pre>{}</pre>\n",
+                escape_html(excerpt)
+            ));
+        }
+        if let Some(traceback) = &self.traceback {
+            html.push_str(&format!(
+                "<h2>Traceback</h2>\n<pre>{}</pre>\n",
+                escape_html(traceback)
+            ));
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
 }
 
-async fn index_route(
-    AxumState(state): AxumState<AppState>,
-    method: Method,
-    headers: HeaderMap,
-    body: Bytes,
-) -> impl IntoResponse {
-    do_handle_request(state, method, "/", headers, body)
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
 }
 
-async fn match_all_route(
-    AxumState(state): AxumState<AppState>,
-    method: Method,
-    Path(path): Path<String>,
-    headers: HeaderMap,
-    body: Bytes,
-) -> impl IntoResponse {
-    let path = format!("/{path}");
-    do_handle_request(state, method, path, headers, body)
+fn prefers_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// How errors are turned into responses.
+#[derive(Builder, Clone)]
+struct ErrorPages {
+    cookie_secret: Option<String>,
+    dev: bool,
+    handler: Option<Arc<EvaluationPool<Cursor<Bytes>>>>,
+    json: bool,
+    request_mapper: Option<RequestMapper>,
+}
+
+impl ErrorPages {
+    /// Respond with details in dev mode, or with the response built by the error handler.
+    /// Only the status code is sent when neither is available.
+    fn respond(&self, request: Value, detail: &ErrorDetail, html: bool) -> Response {
+        if self.dev {
+            return if html {
+                (detail.status_code, Html(detail.to_html())).into_response()
+            } else {
+                let body = detail.to_problem().to_string();
+                let content_type = [(CONTENT_TYPE, "application/problem+json")];
+                (detail.status_code, content_type, body).into_response()
+            };
+        }
+        if let Some(handler) = &self.handler {
+            match self.run_handler(handler, request, detail) {
+                Ok(res) => return res,
+                Err(err) => error!(?err, "failed to run error handler"),
+            }
+        }
+        detail.status_code.into_response()
+    }
+
+    /// The error is available in `m.request.error`, and the status code of the response
+    /// defaults to the status code of the error.
+    fn run_handler(
+        &self,
+        handler: &Arc<EvaluationPool<Cursor<Bytes>>>,
+        mut request: Value,
+        detail: &ErrorDetail,
+    ) -> anyhow::Result<Response> {
+        let e = handler.get(Cursor::new(Bytes::new()))?;
+        if let Value::Object(request) = &mut request {
+            let mut error = Map::new();
+            error.insert("status".into(), detail.status_code.as_u16().into());
+            error.insert("message".into(), detail.message.clone().into());
+            if let Some(value) = &detail.value {
+                error.insert("value".into(), value.clone());
+            }
+            request.insert("error".into(), error.into());
+        }
+        let eval_state = Arc::new(State::new());
+        eval_state.insert(StateKey::Request, request);
+        let mut response = Map::new();
+        response.insert("status_code".into(), detail.status_code.as_u16().into());
+        eval_state.insert(StateKey::Response, response.into());
+        let solution = e.evaluate().state(eval_state.clone()).call()?;
+        let (status_code, header_map) =
+            build_response_head(&eval_state, self.cookie_secret.as_deref())?;
+        let body = build_body(self.json, solution.payload, solution.bytes)?;
+        Ok((status_code, header_map, body).into_response())
+    }
 }
 
-pub fn init_route(opts: &ServeOptions) -> anyhow::Result<Router> {
-    let store = if let Some(path) = &opts.store_options.store_path {
-        let store = Store::new(path.as_path())?;
-        if opts.store_options.run_migrations {
-            store.migrate(None)?;
+#[derive(Clone, Copy)]
+struct BodyLimits {
+    max_file_size: Option<usize>,
+    max_files: usize,
+    max_size: usize,
+}
+
+struct RequestBody {
+    raw: Bytes,
+    parsed: Option<ParsedBody>,
+}
+
+struct ParsedBody {
+    value: Value,
+    /// Files are only uploaded with multipart bodies.
+    files: Option<Vec<UploadedFile>>,
+}
+
+/// Parse the body by the content type. Bodies of other content types, bodies over the limits
+/// and malformed bodies are not parsed, so scripts can still read them with `io.read`.
+async fn parse_body(headers: &HeaderMap, body: &Bytes, limits: &BodyLimits) -> Option<ParsedBody> {
+    let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok())?;
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let is_json = mime == "application/json" || mime.ends_with("+json");
+    let is_form = mime == "application/x-www-form-urlencoded";
+    let is_multipart = mime == "multipart/form-data";
+    if !is_json && !is_form && !is_multipart {
+        return None;
+    }
+    if body.len() > limits.max_size {
+        debug!(size = body.len(), "request body is too large to be parsed");
+        return None;
+    }
+
+    if is_json {
+        if body.is_empty() {
+            return None;
+        }
+        let value = serde_json::from_slice(body)
+            .inspect_err(|err| debug!(%err, "failed to parse JSON request body"))
+            .ok()?;
+        return Some(ParsedBody { value, files: None });
+    }
+
+    // values are always lists so repeated keys are kept, the same as the query
+    let mut fields: Map<_, Value> = Map::new();
+    let mut push_field = |name: String, value: String| {
+        let values = fields.entry(name).or_insert_with(|| Value::Array(vec![]));
+        if let Value::Array(values) = values {
+            values.push(value.into());
+        }
+    };
+
+    if is_form {
+        for (key, value) in form_urlencoded::parse(body) {
+            push_field(key.into_owned(), value.into_owned());
+        }
+        return Some(ParsedBody {
+            value: fields.into(),
+            files: None,
+        });
+    }
+
+    let boundary = multer::parse_boundary(content_type)
+        .inspect_err(|err| debug!(%err, "failed to parse boundary of multipart request body"))
+        .ok()?;
+    let size_limit = SizeLimit::new()
+        .whole_stream(limits.max_size as u64)
+        .per_field(limits.max_file_size.unwrap_or(limits.max_size) as u64);
+    let mut multipart = Multipart::with_constraints(
+        stream::once({
+            let body = body.clone();
+            async move { Ok::<_, Infallible>(body) }
+        }),
+        boundary,
+        Constraints::new().size_limit(size_limit),
+    );
+    let log = |err: &multer::Error| debug!(%err, "failed to parse multipart request body");
+    let mut files = vec![];
+    while let Some(field) = multipart.next_field().await.inspect_err(log).ok()? {
+        let name = field.name().unwrap_or("").to_string();
+        let Some(filename) = field.file_name().map(str::to_string) else {
+            push_field(name, field.text().await.inspect_err(log).ok()?);
+            continue;
+        };
+        if files.len() >= limits.max_files {
+            debug!(
+                max_files = limits.max_files,
+                "too many uploaded files to be parsed"
+            );
+            return None;
+        }
+        let content_type = field.content_type().map(|m| m.to_string());
+        let bytes = field.bytes().await.inspect_err(log).ok()?;
+        files.push(
+            UploadedFile::builder()
+                .name(name)
+                .filename(filename)
+                .maybe_content_type(content_type)
+                .bytes(bytes.to_vec())
+                .build(),
+        );
+    }
+    let mut value = Map::new();
+    value.insert("fields".into(), fields.into());
+    Some(ParsedBody {
+        value: value.into(),
+        files: Some(files),
+    })
+}
+
+fn build_request(
+    path: String,
+    params: Option<HashMap<String, String>>,
+    parts: &Parts,
+    cookie_secret: Option<&str>,
+    mapper: Option<&RequestMapper>,
+) -> Map<String, Value> {
+    let mut headers_map: Map<_, Value> = Map::new();
+    for (name, value) in &parts.headers {
+        let value = value.to_str().unwrap_or("");
+        headers_map.insert(name.to_string(), value.into());
+    }
+
+    // values are always lists so repeated keys e.g. "?a=1&a=2" are kept
+    let mut query_map: Map<_, Value> = Map::new();
+    let raw_query = parts.uri.query();
+    for (key, value) in form_urlencoded::parse(raw_query.unwrap_or("").as_bytes()) {
+        let values = query_map
+            .entry(key.into_owned())
+            .or_insert_with(|| Value::Array(vec![]));
+        if let Value::Array(values) = values {
+            values.push(value.into_owned().into());
+        }
+    }
+
+    // the first cookie wins when a name is repeated
+    let mut cookies_map: Map<_, Value> = Map::new();
+    let mut signed_cookies_map: Map<_, Value> = Map::new();
+    for header in parts.headers.get_all(COOKIE) {
+        let Ok(header) = header.to_str() else {
+            continue;
+        };
+        for cookie in Cookie::split_parse(header).filter_map(Result::ok) {
+            let (name, value) = cookie.name_value();
+            if let Some(value) = cookie_secret.and_then(|s| verify_cookie(s, name, value)) {
+                signed_cookies_map
+                    .entry(name)
+                    .or_insert_with(|| value.into());
+            }
+            cookies_map.entry(name).or_insert_with(|| value.into());
+        }
+    }
+
+    let host = parts
+        .headers
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| parts.uri.host());
+
+    let mut request_map: Map<_, Value> = Map::new();
+    request_map.insert("method".into(), parts.method.as_str().into());
+    request_map.insert("path".into(), path.into());
+    request_map.insert("headers".into(), headers_map.into());
+    request_map.insert("query".into(), query_map.into());
+    request_map.insert("cookies".into(), cookies_map.into());
+    if cookie_secret.is_some() {
+        request_map.insert("signed_cookies".into(), signed_cookies_map.into());
+    }
+    if let Some(raw_query) = raw_query {
+        request_map.insert("raw_query".into(), raw_query.into());
+    }
+    if let Some(host) = host {
+        request_map.insert("host".into(), host.into());
+    }
+    if let Some(ConnectInfo(addr)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+        request_map.insert("remote_addr".into(), addr.to_string().into());
+    }
+    if let Some(tls) = parts.extensions.get::<TlsInfo>() {
+        let mut tls_map: Map<_, Value> = Map::new();
+        if let Some(peer) = &tls.peer {
+            tls_map.insert("peer".into(), peer.as_str().into());
+        }
+        request_map.insert("tls".into(), tls_map.into());
+    }
+    if let Some(AuthClaims(claims)) = parts.extensions.get::<AuthClaims>() {
+        request_map.insert("auth".into(), claims.clone());
+    }
+    request_map.insert("version".into(), format!("{:?}", parts.version).into());
+    if let Some(params) = params {
+        let params: Map<_, Value> = params.into_iter().map(|(k, v)| (k, v.into())).collect();
+        request_map.insert("params".into(), params.into());
+    }
+    if let Some(mapper) = mapper {
+        mapper(parts, &mut request_map);
+    }
+    request_map
+}
+
+fn build_response_head(
+    state: &State,
+    cookie_secret: Option<&str>,
+) -> anyhow::Result<(StatusCode, HeaderMap)> {
+    let Some(res) = state.get(&StateKey::Response) else {
+        return Ok((StatusCode::OK, HeaderMap::new()));
+    };
+    let status_code = res
+        .get("status_code")
+        .and_then(|s| s.as_u64())
+        .unwrap_or(200u64);
+    let status_code = StatusCode::from_u16(u16::try_from(status_code)?)?;
+
+    let mut header_map = HeaderMap::new();
+    if let Some(h) = res.get("headers").and_then(|h| h.as_object()) {
+        for (name, value) in h.iter() {
+            let name = HeaderName::from_str(name)?;
+            // arrays become repeated headers e.g. multiple "set-cookie"
+            let values = match value {
+                Value::Array(values) => values.as_slice(),
+                _ => std::slice::from_ref(value),
+            };
+            for value in values {
+                let value = match value {
+                    Value::String(s) => HeaderValue::from_str(s)?,
+                    _ => HeaderValue::from_str(&value.to_string())?,
+                };
+                header_map.append(&name, value);
+            }
+        }
+    }
+    if let Some(cookies) = res.get("cookies").and_then(|c| c.as_array()) {
+        for cookie in cookies {
+            let cookie = build_cookie(cookie, cookie_secret)?;
+            header_map.append(SET_COOKIE, HeaderValue::from_str(&cookie)?);
+        }
+    }
+    Ok((status_code, header_map))
+}
+
+fn build_cookie(cookie: &Value, cookie_secret: Option<&str>) -> anyhow::Result<String> {
+    let Some(name) = cookie.get("name").and_then(|n| n.as_str()) else {
+        bail!("name of cookie is required");
+    };
+    let value = cookie.get("value").and_then(|v| v.as_str()).unwrap_or("");
+    let signed = cookie.get("signed").and_then(|s| s.as_bool()) == Some(true);
+    let value = match (signed, cookie_secret) {
+        (false, _) => value.to_string(),
+        (true, Some(secret)) => sign_cookie(secret, name, value)?,
+        (true, None) => bail!("cookie secret is required to sign cookie {name}"),
+    };
+    let mut builder = Cookie::build((name.to_string(), value));
+    if let Some(max_age) = cookie.get("max_age").and_then(|m| m.as_i64()) {
+        builder = builder.max_age(cookie::time::Duration::seconds(max_age));
+    }
+    if let Some(path) = cookie.get("path").and_then(|p| p.as_str()) {
+        builder = builder.path(path.to_string());
+    }
+    if let Some(domain) = cookie.get("domain").and_then(|d| d.as_str()) {
+        builder = builder.domain(domain.to_string());
+    }
+    if let Some(http_only) = cookie.get("http_only").and_then(|h| h.as_bool()) {
+        builder = builder.http_only(http_only);
+    }
+    if let Some(secure) = cookie.get("secure").and_then(|s| s.as_bool()) {
+        builder = builder.secure(secure);
+    }
+    if let Some(same_site) = cookie.get("same_site").and_then(|s| s.as_str()) {
+        let same_site = match same_site.to_ascii_lowercase().as_str() {
+            "strict" => SameSite::Strict,
+            "lax" => SameSite::Lax,
+            "none" => SameSite::None,
+            _ => bail!("unsupported same_site {same_site} of cookie {name}"),
+        };
+        builder = builder.same_site(same_site);
+    }
+    Ok(builder.build().to_string())
+}
+
+/// Append HMAC-SHA256 of the name and value to the value.
+fn sign_cookie(secret: &str, name: &str, value: &str) -> anyhow::Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(format!("{name}={value}").as_bytes());
+    let signature = BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    Ok(format!("{value}.{signature}"))
+}
+
+/// Return the value without the signature when the signature is valid.
+fn verify_cookie<'a>(secret: &str, name: &str, signed: &'a str) -> Option<&'a str> {
+    let (value, signature) = signed.rsplit_once('.')?;
+    let signature = BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(format!("{name}={value}").as_bytes());
+    mac.verify_slice(&signature).ok()?;
+    Some(value)
+}
+
+fn build_body(json: bool, value: Value, bytes: Option<Vec<u8>>) -> anyhow::Result<Bytes> {
+    if json {
+        return Ok(serde_json::to_vec(&value)?.into());
+    }
+    // strings are sent as they are, so binary strings are not mangled
+    if let Some(bytes) = bytes {
+        return Ok(bytes.into());
+    }
+    Ok(match value {
+        Value::String(s) => s.into(),
+        _ => value.to_string().into(),
+    })
+}
+
+async fn index_route(
+    AxumState(state): AxumState<AppState>,
+    parts: Parts,
+    body: Bytes,
+) -> impl IntoResponse {
+    handle_request(state, "/".to_string(), None, parts, body).await
+}
+
+async fn match_all_route(
+    AxumState(state): AxumState<AppState>,
+    Path(path): Path<String>,
+    parts: Parts,
+    body: Bytes,
+) -> impl IntoResponse {
+    let path = format!("/{path}");
+    handle_request(state, path, None, parts, body).await
+}
+
+async fn params_route(
+    AxumState(state): AxumState<AppState>,
+    params: Option<Path<HashMap<String, String>>>,
+    parts: Parts,
+    body: Bytes,
+) -> impl IntoResponse {
+    let params = params.map(|Path(p)| p).unwrap_or_default();
+    let path = parts.uri.path().to_string();
+    handle_request(state, path, Some(params), parts, body).await
+}
+
+/// Respond to requests matching no route as errors, so the error handler can build them.
+/// They are counted with an empty route in metrics.
+async fn fallback_route(
+    AxumState((errors, permits, metrics)): AxumState<(ErrorPages, Arc<Semaphore>, Option<Metrics>)>,
+    parts: Parts,
+) -> Response {
+    let method = parts.method.clone();
+    let res = respond_unmatched(errors, permits, parts).await;
+    if let Some(metrics) = metrics {
+        metrics.observe_request("", method.as_str(), res.status().as_u16());
+    }
+    res
+}
+
+async fn respond_unmatched(errors: ErrorPages, permits: Arc<Semaphore>, parts: Parts) -> Response {
+    let Ok(permit) = permits.try_acquire_owned() else {
+        warn!("too many concurrent evaluations");
+        return (StatusCode::SERVICE_UNAVAILABLE, [(RETRY_AFTER, "1")]).into_response();
+    };
+    let detail = ErrorDetail::new(StatusCode::NOT_FOUND, "no route matches the path");
+    task::spawn_blocking(move || {
+        let _permit = permit;
+        let path = parts.uri.path().to_string();
+        let request = build_request(
+            path,
+            None,
+            &parts,
+            errors.cookie_secret.as_deref(),
+            errors.request_mapper.as_ref(),
+        );
+        errors.respond(request.into(), &detail, prefers_html(&parts.headers))
+    })
+    .await
+    .unwrap_or_else(|err| {
+        error!(?err, "failed to respond to unmatched request");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })
+}
+
+/// Respond 504 Gateway Timeout when the response does not start in time.
+/// Streamed bodies are not limited once they start.
+async fn request_timeout(
+    AxumState(timeout): AxumState<Duration>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    match tokio::time::timeout(timeout, next.run(req)).await {
+        Ok(res) => res,
+        Err(_elapsed) => {
+            warn!(?timeout, "request timed out");
+            StatusCode::GATEWAY_TIMEOUT.into_response()
+        }
+    }
+}
+
+async fn metrics_route(AxumState(metrics): AxumState<Metrics>) -> Response {
+    match metrics.encode() {
+        Ok(text) => ([(CONTENT_TYPE, METRICS_CONTENT_TYPE)], text).into_response(),
+        Err(err) => {
+            error!(?err, "failed to encode metrics");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Router exporting metrics at the path, [`DEFAULT_METRICS_PATH`] by default.
+fn metrics_router(metrics: &Metrics, opts: &ServeOptions) -> Router {
+    let path = opts.metrics_path.as_deref().unwrap_or(DEFAULT_METRICS_PATH);
+    Router::new()
+        .route(path, get(metrics_route))
+        .with_state(metrics.clone())
+}
+
+/// States kept across reloads of routes.
+#[derive(Clone)]
+struct SharedState {
+    buckets: Buckets,
+    evaluation: Option<EvaluationFactory>,
+    metrics: Option<Metrics>,
+    permits: Arc<Semaphore>,
+    request_mapper: Option<RequestMapper>,
+    store: Store,
+}
+
+impl SharedState {
+    /// Open the store of the options unless one is given.
+    fn open(opts: &ServeOptions, store: Option<Store>) -> anyhow::Result<Self> {
+        let persist = opts
+            .rate_limit
+            .as_ref()
+            .is_some_and(RateLimitOptions::persist);
+        let store = if let Some(store) = store {
+            store
+        } else if let Some(path) = &opts.store_options.store_path {
+            let store = Store::new(path.as_path())?;
+            if opts.store_options.run_migrations {
+                store.migrate(None)?;
+            }
+            info!(?path, "open store");
+            store
+        } else if persist {
+            bail!("rate limits can only be persisted with a store path");
+        } else {
+            let store = Store::default();
+            warn!("no store path is specified, an in-memory store will be used and values will be lost when process ends");
+            store
+        };
+        if persist {
+            check_store(&store)?;
+        }
+        let max_concurrency = opts.max_concurrency.unwrap_or(DEFAULT_MAX_CONCURRENCY);
+        let permits = Arc::new(Semaphore::new(max_concurrency));
+        let metrics =
+            (opts.metrics_path.is_some() || opts.metrics_bind.is_some()).then(Metrics::default);
+        Ok(Self {
+            buckets: Buckets::default(),
+            evaluation: None,
+            metrics,
+            permits,
+            request_mapper: None,
+            store,
+        })
+    }
+}
+
+/// Build a router handling requests with the routes of the options.
+/// It can be nested in other axum apps and wrapped with more layers.
+/// Scripts are not reloaded, unlike [`serve_file`].
+///
+/// ```rust
+/// use axum::{body::Bytes, http::request::Parts};
+/// use lmb::serve::{router, EvaluationContext, ScriptRoute, ServeOptions};
+/// use lmb::{Store, StoreOptions};
+/// use serde_json::{Map, Value};
+/// use std::{net::SocketAddr, sync::Arc};
+///
+/// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+/// let routes = vec![ScriptRoute::builder("hello.lua", "return 'hello'").build()];
+/// let options = ServeOptions::builder("127.0.0.1:3000".parse::<SocketAddr>()?, routes)
+///     .store_options(StoreOptions::builder().build())
+///     .build();
+/// let app = router(&options)
+///     .evaluation(Arc::new(|route: &ScriptRoute, cx: &EvaluationContext| {
+///         cx.evaluation(route)
+///     }))
+///     .request_mapper(Arc::new(|parts: &Parts, request: &mut Map<String, Value>| {
+///         request.insert("tenant".into(), parts.uri.host().unwrap_or_default().into());
+///     }))
+///     .store(Store::default())
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[builder(finish_fn = build)]
+pub fn router(
+    #[builder(start_fn)] options: &ServeOptions,
+    /// Build evaluations of scripts instead of [`EvaluationContext::evaluation`].
+    evaluation: Option<EvaluationFactory>,
+    /// Map requests to `m.request` after the default fields are set.
+    request_mapper: Option<RequestMapper>,
+    /// Store shared by scripts instead of the one opened with the store options.
+    store: Option<Store>,
+) -> crate::Result<Router> {
+    let build = || {
+        let shared = SharedState {
+            evaluation,
+            request_mapper,
+            ..SharedState::open(options, store)?
+        };
+        Reloader::with_shared(options, shared)?.router()
+    };
+    build().map_err(serve_error)
+}
+
+/// Errors of the server are built with [`anyhow`] internally, and converted to
+/// [`crate::Error`] at the public functions, keeping errors of the crate as they are.
+fn serve_error(err: anyhow::Error) -> Error {
+    match err.downcast::<Error>() {
+        Ok(err) => err,
+        Err(err) => Error::Serve(format!("{err:#}")),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn init_route(opts: &ServeOptions) -> crate::Result<Router> {
+    router(opts).build()
+}
+
+fn build_router(opts: &ServeOptions, shared: &SharedState) -> anyhow::Result<Router> {
+    check_routes(&opts.routes)?;
+    let SharedState {
+        buckets,
+        evaluation,
+        metrics,
+        permits,
+        request_mapper,
+        store,
+    } = shared;
+    let cx = EvaluationContext {
+        args: opts.args.clone(),
+        cache: opts.cache.clone(),
+        env: opts.env.clone(),
+        memory_limit: opts.memory_limit,
+        metrics: metrics.clone(),
+        module_root: opts.module_root.clone().or_else(|| opts.dir.clone()),
+        store: store.clone(),
+        timeout: opts.timeout,
+    };
+    let build_evaluation = |route: &ScriptRoute| match evaluation {
+        Some(evaluation) => evaluation(route, &cx),
+        None => cx.evaluation(route),
+    };
+    let pool_options = PoolOptions::builder()
+        .max_idle(opts.pool_size.unwrap_or(DEFAULT_POOL_SIZE))
+        .maybe_idle_timeout(opts.pool_idle_timeout)
+        .build();
+    let body_limits = BodyLimits {
+        max_file_size: opts.max_file_size,
+        max_files: opts.max_files.unwrap_or(DEFAULT_MAX_FILES),
+        max_size: opts
+            .max_parsed_body_size
+            .unwrap_or(DEFAULT_MAX_PARSED_BODY_SIZE),
+    };
+
+    let error_handler = match &opts.error_handler {
+        Some(route) => Some(EvaluationPool::new(
+            build_evaluation(route)?,
+            pool_options.clone(),
+        )),
+        None => None,
+    };
+    let errors = ErrorPages::builder()
+        .maybe_cookie_secret(opts.cookie_secret.clone())
+        .dev(opts.dev)
+        .maybe_handler(error_handler)
+        .json(opts.json)
+        .maybe_request_mapper(request_mapper.clone())
+        .build();
+
+    let rate_limit = opts.rate_limit.clone().unwrap_or_default();
+    let key_of = match rate_limit.key() {
+        RateLimitKey::Ip => KeyOf::Ip,
+        RateLimitKey::Header(name) => KeyOf::Header(name.clone()),
+        RateLimitKey::Script(route) => KeyOf::Script(EvaluationPool::new(
+            build_evaluation(route)?,
+            pool_options.clone(),
+        )),
+    };
+
+    let mut app = Router::new().fallback(fallback_route).with_state((
+        errors.clone(),
+        permits.clone(),
+        metrics.clone(),
+    ));
+    let mut method_routers: BTreeMap<&str, MethodRouter> = BTreeMap::new();
+    for route in &opts.routes {
+        let e = build_evaluation(route)?;
+        let rate_limiter = route
+            .rate_limit
+            .or(rate_limit.quota())
+            .map(|quota| RateLimiter {
+                buckets: buckets.clone(),
+                key: key_of.clone(),
+                quota,
+                route: route.name.clone(),
+                store: rate_limit.persist().then(|| store.clone()),
+            });
+        let app_state = AppState::builder()
+            .body_limits(body_limits)
+            .maybe_cookie_secret(opts.cookie_secret.clone())
+            .errors(errors.clone())
+            .json(opts.json)
+            .maybe_metrics(metrics.clone())
+            .permits(permits.clone())
+            .pool(EvaluationPool::new(e, pool_options.clone()))
+            .maybe_rate_limiter(rate_limiter)
+            .maybe_request_mapper(request_mapper.clone())
+            .route(route.name.clone())
+            .send_timeout(opts.timeout.unwrap_or(DEFAULT_TIMEOUT))
+            .maybe_sse_idle_timeout(opts.sse_idle_timeout)
+            .sse_keep_alive(opts.sse_keep_alive.unwrap_or(DEFAULT_SSE_KEEP_ALIVE))
+            .websocket_idle_timeout(
+                opts.websocket_idle_timeout
+                    .unwrap_or(DEFAULT_WEBSOCKET_IDLE_TIMEOUT),
+            )
+            .build();
+        let Some(path) = &route.path else {
+            app = app
+                .route("/", any(index_route).with_state(app_state.clone()))
+                .route("/*path", any(match_all_route).with_state(app_state));
+            continue;
+        };
+        let method_router = match &route.method {
+            Some(method) => {
+                let filter = MethodFilter::try_from(method.clone())?;
+                on(filter, params_route).with_state(app_state)
+            }
+            None => any(params_route).with_state(app_state),
+        };
+        let merged = match method_routers.remove(path.as_str()) {
+            Some(existing) => existing.merge(method_router),
+            None => method_router,
+        };
+        method_routers.insert(path, merged);
+    }
+    for (path, method_router) in method_routers {
+        app = app.route(path, method_router);
+    }
+    // files of credentials are read again when routes are reloaded
+    if let Some(auth) = &opts.auth {
+        let auth = Authenticator::new(auth)?;
+        app = app.layer(middleware::from_fn_with_state(auth, authenticate));
+    }
+    Ok(app)
+}
+
+/// Router which routes can be swapped in while serving.
+#[derive(Clone)]
+struct Reloader {
+    opts: Arc<Mutex<ServeOptions>>,
+    router: Arc<ArcSwap<Router>>,
+    shared: SharedState,
+}
+
+impl Reloader {
+    fn new(opts: &ServeOptions) -> anyhow::Result<Self> {
+        Self::with_shared(opts, SharedState::open(opts, None)?)
+    }
+
+    fn with_shared(opts: &ServeOptions, shared: SharedState) -> anyhow::Result<Self> {
+        let router = build_router(opts, &shared)?;
+        Ok(Self {
+            opts: Arc::new(Mutex::new(opts.clone())),
+            router: Arc::new(ArcSwap::from_pointee(router)),
+            shared,
+        })
+    }
+
+    /// Router dispatching each request to the current routes.
+    /// Metrics are exported at the reserved path unless they have a separate listener.
+    fn router(&self) -> anyhow::Result<Router> {
+        let mut router = Router::new()
+            .fallback(reloadable_route)
+            .with_state(self.router.clone());
+        let opts = self.opts.lock();
+        if let (Some(metrics), None) = (&self.shared.metrics, &opts.metrics_bind) {
+            // merged outside the authentication of routes, so scrapers need no credentials
+            router = router.merge(metrics_router(metrics, &opts));
+        }
+        // layers added later wrap the earlier ones
+        if let Some(max_body_size) = opts.max_body_size {
+            router = router
+                .layer(DefaultBodyLimit::disable())
+                .layer(RequestBodyLimitLayer::new(max_body_size));
+        }
+        if opts.compression {
+            router = router.layer(CompressionLayer::new());
+        }
+        // preflight requests carry no credentials, so they are answered before authentication
+        if let Some(cors) = &opts.cors {
+            router = router.layer(cors.layer()?);
+        }
+        if let Some(timeout) = opts.request_timeout {
+            router = router.layer(middleware::from_fn_with_state(timeout, request_timeout));
+        }
+        Ok(router.layer(
+            TraceLayer::new_for_http()
+                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+                .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
+        ))
+    }
+
+    /// Read scripts again and swap the routes in if all of them parse.
+    /// Otherwise the current routes are kept.
+    fn reload(&self) -> anyhow::Result<()> {
+        let mut opts = self.opts.lock();
+        let routes = match &opts.dir {
+            Some(dir) => ScriptRoute::scan_dir(dir)?,
+            None => opts
+                .routes
+                .iter()
+                .map(ScriptRoute::reread)
+                .collect::<anyhow::Result<_>>()?,
+        };
+        let error_handler = opts
+            .error_handler
+            .as_ref()
+            .map(ScriptRoute::reread)
+            .transpose()?;
+        let rate_limit = opts
+            .rate_limit
+            .as_ref()
+            .map(RateLimitOptions::reread)
+            .transpose()?;
+        let key_script = rate_limit.as_ref().and_then(RateLimitOptions::script);
+        for route in routes.iter().chain(&error_handler).chain(key_script) {
+            let check = LuaCheck::builder(route.name(), route.script()).build();
+            if let Err(err) = check.check() {
+                if let Some(metrics) = &self.shared.metrics {
+                    metrics.observe_compile_error(route.name());
+                }
+                let mut buf = Vec::new();
+                check.write_error(&mut buf, err, true)?;
+                bail!(String::from_utf8_lossy(&buf).trim().to_string());
+            }
+        }
+        let reloaded = ServeOptions {
+            error_handler,
+            rate_limit,
+            routes,
+            ..opts.clone()
+        };
+        let router = build_router(&reloaded, &self.shared)?;
+        self.router.store(Arc::new(router));
+        *opts = reloaded;
+        Ok(())
+    }
+
+    async fn reload_in_background(&self) {
+        let reloader = self.clone();
+        match task::spawn_blocking(move || reloader.reload()).await {
+            Ok(Ok(())) => info!("routes reloaded"),
+            Ok(Err(err)) => {
+                error!("failed to reload routes, keep serving the previous version\n{err}");
+            }
+            Err(err) => error!(?err, "failed to reload routes"),
+        }
+    }
+
+    /// Directories of the scripts, where required modules are also watched.
+    fn watched_dirs(&self) -> Vec<PathBuf> {
+        let opts = self.opts.lock();
+        if let Some(dir) = &opts.dir {
+            return vec![dir.clone()];
+        }
+        let mut dirs: Vec<PathBuf> = opts
+            .routes
+            .iter()
+            .chain(&opts.error_handler)
+            .chain(opts.rate_limit.as_ref().and_then(RateLimitOptions::script))
+            .filter_map(|r| r.file.as_ref())
+            .map(|f| match f.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+                _ => PathBuf::from("."),
+            })
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        dirs
+    }
+
+    /// Reload routes whenever Lua scripts in the watched directories change.
+    fn watch(&self) -> anyhow::Result<RecommendedWatcher> {
+        let (tx, mut rx) = mpsc::channel::<()>(1);
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+                let changed = match res {
+                    Ok(event) => {
+                        !event.kind.is_access()
+                            && event
+                                .paths
+                                .iter()
+                                .any(|p| p.extension().is_some_and(|ext| ext == "lua"))
+                    }
+                    Err(err) => {
+                        warn!(?err, "failed to watch scripts");
+                        false
+                    }
+                };
+                if changed {
+                    // a pending reload already covers this change
+                    let _ = tx.try_send(());
+                }
+            })?;
+        for dir in self.watched_dirs() {
+            info!(dir = %dir.display(), "watch scripts");
+            watcher.watch(&dir, RecursiveMode::Recursive)?;
+        }
+        let reloader = self.clone();
+        tokio::spawn(async move {
+            while rx.recv().await.is_some() {
+                // editors may write a file several times in a row
+                tokio::time::sleep(WATCH_DEBOUNCE).await;
+                while rx.try_recv().is_ok() {}
+                reloader.reload_in_background().await;
+            }
+        });
+        Ok(watcher)
+    }
+}
+
+async fn reloadable_route(
+    AxumState(router): AxumState<Arc<ArcSwap<Router>>>,
+    req: Request<Body>,
+) -> Response {
+    let router = Router::clone(&router.load());
+    match router.oneshot(req).await {
+        Ok(res) => res,
+        Err(err) => match err {},
+    }
+}
+
+#[cfg(unix)]
+fn reload_on_hangup(reloader: Reloader, tls: Option<Arc<ReloadableTls>>) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("reload routes on SIGHUP");
+            reloader.reload_in_background().await;
+            if let Some(tls) = &tls {
+                match tls.reload() {
+                    Ok(()) => info!("certificates reloaded"),
+                    Err(err) => error!(
+                        ?err,
+                        "failed to reload certificates, keep the previous ones"
+                    ),
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reload_on_hangup(_reloader: Reloader, _tls: Option<Arc<ReloadableTls>>) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Serve the routes of the options until the shutdown future completes, then wait for
+/// in-flight requests in the grace period. Scripts are reloaded on `SIGHUP`,
+/// or when they change with [`ServeOptionsBuilder::watch`].
+pub async fn serve_file<F>(opts: &ServeOptions, shutdown: F) -> crate::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    run(opts, shutdown).await.map_err(serve_error)
+}
+
+async fn run<F>(opts: &ServeOptions, shutdown: F) -> anyhow::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let bind = &opts.bind;
+    let reloader = Reloader::new(opts)?;
+    let tls = opts.tls.as_ref().map(ReloadableTls::new).transpose()?;
+    reload_on_hangup(reloader.clone(), tls.clone())?;
+    let _watcher = if opts.watch {
+        Some(reloader.watch()?)
+    } else {
+        None
+    };
+    let app = reloader.router()?;
+    let listener = if let Some(listener) = Listener::from_fds(ListenFd::from_env())? {
+        info!("serving lua script with the listener passed by the supervisor");
+        listener
+    } else {
+        let listener = Listener::bind(bind, opts.unix_socket_mode).await?;
+        if tls.is_some() {
+            info!(%bind, "serving lua script over TLS");
+        } else {
+            info!(%bind, "serving lua script");
+        }
+        listener
+    };
+    // clients of a Unix domain socket have no IP address, so they would share one bucket
+    let rate_limit = opts.rate_limit.clone().unwrap_or_default();
+    if !listener.has_peer_addr() && rate_limit.limits_by_ip(&opts.routes) {
+        bail!("rate limiting by IP address is not possible on a Unix domain socket, limit by a header e.g. X-Forwarded-For instead");
+    }
+    let grace_period = opts.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD);
+    let metrics_server = match (&reloader.shared.metrics, &opts.metrics_bind) {
+        (Some(metrics), Some(metrics_bind)) => {
+            let listener = Listener::bind(metrics_bind, opts.unix_socket_mode).await?;
+            info!(bind = %metrics_bind, "serving metrics");
+            let app = metrics_router(metrics, opts);
+            // stopped along with the server, so no request is waited for
+            Some(tokio::spawn(serve_until(
+                app,
+                listener,
+                None,
+                Duration::ZERO,
+                future::pending(),
+            )))
+        }
+        _ => None,
+    };
+    serve_until(app, listener, tls, grace_period, shutdown).await?;
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
+    }
+    reloader.shared.store.checkpoint()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use axum::body::{to_bytes, Body, Bytes};
+    use axum_test::{
+        multipart::{MultipartForm, Part},
+        TestServer,
+    };
+    use futures_util::StreamExt as _;
+    use http::{
+        header::{ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, COOKIE},
+        HeaderName, HeaderValue, Method, Request, StatusCode,
+    };
+    use serde_json::{json, Map, Value};
+
+    use std::{
+        collections::HashMap,
+        future::IntoFuture as _,
+        io::Cursor,
+        net::SocketAddr,
+        path::Path,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use test_case::test_case;
+    use tokio::{net::TcpListener, sync::oneshot, task};
+    use tower::ServiceExt as _;
+
+    use super::{
+        init_route,
+        listener::{serve_until, Listener},
+        route_path, router, EvaluationFactory, Reloader, RequestMapper,
+        DEFAULT_WEBSOCKET_IDLE_TIMEOUT,
+    };
+    use crate::{
+        serve::{ScriptRoute, ServeOptions},
+        Error, Evaluation, ScriptArgs, Store, StoreOptions,
+    };
+
+    #[test_case("index.lua", "/", None)]
+    #[test_case("users.lua", "/users", None)]
+    #[test_case("users/index.post.lua", "/users", Some(Method::POST))]
+    #[test_case("users/[id].lua", "/users/:id", None)]
+    #[test_case("users/[id].get.lua", "/users/:id", Some(Method::GET))]
+    #[test_case(
+        "users/[id]/posts.DELETE.lua",
+        "/users/:id/posts",
+        Some(Method::DELETE)
+    )]
+    #[test_case("files/[...rest].lua", "/files/*rest", None)]
+    #[test_case("v1.2.lua", "/v1.2", None)]
+    #[test_case("feed.rss.lua", "/feed.rss", None)]
+    #[test_case("data.json.get.lua", "/data.json", Some(Method::GET))]
+    fn map_route_path(file: &str, expected: &str, method: Option<Method>) {
+        let (path, m) = route_path(Path::new(file)).unwrap();
+        assert_eq!(expected, path);
+        assert_eq!(method, m);
+    }
+
+    #[tokio::test]
+    async fn serve_dir() {
+        let dir = TempDir::new().unwrap();
+        dir.child("index.lua").write_str("return 'index'").unwrap();
+        dir.child("users/[id].get.lua")
+            .write_str("return 'get ' .. require('@lmb').request.params.id")
+            .unwrap();
+        dir.child("users/[id].lua")
+            .write_str(
+                r#"
+                --[[
+                --method = "DELETE"
+                --]]
+                return 'delete ' .. require('@lmb').request.params.id
+                "#,
+            )
+            .unwrap();
+        let routes = ScriptRoute::scan_dir(dir.path()).unwrap();
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), routes)
+            .json(false)
+            .store_options(store_options)
+            .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        let res = server.get("/").await;
+        assert_eq!("index", res.text());
+
+        let res = server.get("/users/1").await;
+        assert_eq!("get 1", res.text());
+
+        let res = server.delete("/users/2").await;
+        assert_eq!("delete 2", res.text());
+
+        let res = server.post("/users/3").await;
+        assert_eq!(405, res.status_code());
+
+        let res = server.get("/absent").await;
+        assert_eq!(404, res.status_code());
+    }
+
+    #[tokio::test]
+    async fn embed_router() {
+        let routes = vec![
+            ScriptRoute::builder(
+                "hello.lua",
+                "return 'hello ' .. require('@lmb').request.tenant",
+            )
+            .path("/hello")
+            .build(),
+            ScriptRoute::builder("count.lua", "return require('@lmb').store.count")
+                .path("/count")
+                .build(),
+        ];
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), routes)
+            .json(false)
+            .store_options(StoreOptions::builder().build())
+            .build();
+        let store = Store::default();
+        store.put("count", &json!(3)).unwrap();
+        let evaluation: EvaluationFactory = Arc::new(|route, cx| {
+            let script = format!("{}\n-- built by the embedder", route.script());
+            Evaluation::builder(script, Cursor::new(Bytes::new()))
+                .name(route.name().to_string())
+                .store(cx.store.clone())
+                .build()
+        });
+        let request_mapper: RequestMapper = Arc::new(|parts, request| {
+            if let Some(Tenant(tenant)) = parts.extensions.get::<Tenant>() {
+                request.insert("tenant".into(), tenant.clone().into());
+            }
+        });
+        let lua = router(&opts)
+            .evaluation(evaluation)
+            .request_mapper(request_mapper)
+            .store(store)
+            .build()
+            .unwrap();
+
+        #[derive(Clone)]
+        struct Tenant(String);
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async { "embedder" }))
+            .nest("/lua", lua)
+            .layer(axum::Extension(Tenant("acme".to_string())));
+        let server = TestServer::new(app.into_make_service()).unwrap();
+
+        assert_eq!("embedder", server.get("/").await.text());
+        assert_eq!("hello acme", server.get("/lua/hello").await.text());
+        assert_eq!("3", server.get("/lua/count").await.text());
+        server.get("/lua/absent").await.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn reload_file() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.child("a.lua");
+        file.write_str("return 'a'").unwrap();
+        let route = ScriptRoute::builder("a.lua", "return 'a'")
+            .file(file.path().to_path_buf())
+            .build();
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), vec![route])
+            .json(false)
+            .store_options(store_options)
+            .build();
+        let reloader = Reloader::new(&opts).unwrap();
+        let server = TestServer::new(reloader.router().unwrap().into_make_service()).unwrap();
+        server.get("/").await.assert_text("a");
+
+        file.write_str("return 'b'").unwrap();
+        reloader.reload().unwrap();
+        server.get("/").await.assert_text("b");
+
+        file.write_str("return 'c").unwrap();
+        let err = reloader.reload().unwrap_err().to_string();
+        assert!(err.contains("a.lua"), "{err}");
+        server.get("/").await.assert_text("b");
+    }
+
+    #[tokio::test]
+    async fn reload_dir() {
+        let dir = TempDir::new().unwrap();
+        dir.child("index.lua").write_str("return 'index'").unwrap();
+        let routes = ScriptRoute::scan_dir(dir.path()).unwrap();
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), routes)
+            .dir(dir.path().to_path_buf())
+            .json(false)
+            .store_options(store_options)
+            .build();
+        let reloader = Reloader::new(&opts).unwrap();
+        let server = TestServer::new(reloader.router().unwrap().into_make_service()).unwrap();
+        server.get("/users").await.assert_status_not_found();
+
+        dir.child("users.lua").write_str("return 'users'").unwrap();
+        reloader.reload().unwrap();
+        server.get("/users").await.assert_text("users");
+        server.get("/").await.assert_text("index");
+    }
+
+    #[tokio::test]
+    async fn require_modules() {
+        let dir = TempDir::new().unwrap();
+        dir.child("lib/greet.lua")
+            .write_str("return function(name) return 'hello ' .. name end")
+            .unwrap();
+        dir.child("api/users.lua")
+            .write_str("return require('../lib/greet')('users')")
+            .unwrap();
+        let routes = ScriptRoute::scan_dir(dir.path()).unwrap();
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), routes)
+            .dir(dir.path().to_path_buf())
+            .json(false)
+            .store_options(store_options)
+            .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        server.get("/api/users").await.assert_text("hello users");
+
+        // modules are confined to the directory of the script without a root
+        let file = dir.child("api/users.lua");
+        let route = ScriptRoute::builder("users.lua", "return require('../lib/greet')('users')")
+            .file(file.path().to_path_buf())
+            .build();
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), vec![route])
+            .json(false)
+            .store_options(store_options)
+            .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        server
+            .get("/")
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.child("a.lua");
+        file.write_str("return 'a'").unwrap();
+        let route = ScriptRoute::builder("a.lua", "return 'a'")
+            .file(file.path().to_path_buf())
+            .build();
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), vec![route])
+            .json(false)
+            .store_options(store_options)
+            .watch(true)
+            .build();
+        let reloader = Reloader::new(&opts).unwrap();
+        let _watcher = reloader.watch().unwrap();
+        let server = TestServer::new(reloader.router().unwrap().into_make_service()).unwrap();
+
+        file.write_str("return 'b'").unwrap();
+        for _ in 0..50 {
+            if server.get("/").await.text() == "b" {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("script is not reloaded");
+    }
+
+    fn busy_script(seconds: f64) -> String {
+        format!("local t = os.clock()\nwhile os.clock() - t < {seconds} do end\nreturn 'done'")
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", busy_script(0.3)).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_until(
+            router,
+            Listener::Tcp(listener),
+            None,
+            Duration::from_secs(5),
+            async move {
+                let _ = rx.await;
+            },
+        ));
+
+        let req = task::spawn_blocking(move || {
+            ureq::get(&format!("http://{addr}/"))
+                .call()
+                .unwrap()
+                .into_string()
+                .unwrap()
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        tx.send(()).unwrap();
+        assert_eq!("done", req.await.unwrap());
+        server.await.unwrap().unwrap();
+        assert!(std::net::TcpStream::connect(addr).is_err());
+    }
+
+    #[tokio::test]
+    async fn grace_period_elapsed() {
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", busy_script(2.0)).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_until(
+            router,
+            Listener::Tcp(listener),
+            None,
+            Duration::from_millis(100),
+            async move {
+                let _ = rx.await;
+            },
+        ));
+
+        let _req = task::spawn_blocking(move || ureq::get(&format!("http://{addr}/")).call().ok());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let start = Instant::now();
+        tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn serve_dir_duplicated_routes() {
+        let dir = TempDir::new().unwrap();
+        dir.child("users.get.lua").write_str("return 1").unwrap();
+        dir.child("users.lua")
+            .write_str("--[[\n--method = \"GET\"\n--]]\nreturn 2")
+            .unwrap();
+        assert!(matches!(
+            ScriptRoute::scan_dir(dir.path()),
+            Err(Error::InvalidRoute(_))
+        ));
+    }
+
+    #[test_case(&["users/[id].lua", "users/[name].lua"], "/users/:id and /users/:name")]
+    #[test_case(&["[id].lua", "[...rest].lua"], "/*rest and /:id")]
+    #[test_case(&["a/[x]/b.lua", "a/[...rest].lua"], "/a/*rest and /a/:x/b")]
+    fn serve_dir_conflicting_routes(files: &[&str], paths: &str) {
+        let dir = TempDir::new().unwrap();
+        for file in files {
+            dir.child(file).write_str("return 1").unwrap();
+        }
+        let err = ScriptRoute::scan_dir(dir.path()).unwrap_err().to_string();
+        assert!(err.contains(paths), "{err}");
+
+        // routes which are not scanned are checked before building the router
+        let routes = files
+            .iter()
+            .map(|file| {
+                let (path, _) = super::route_path(Path::new(file)).unwrap();
+                ScriptRoute::builder(*file, "return 1").path(path).build()
+            })
+            .collect::<Vec<_>>();
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), routes)
+            .json(false)
+            .store_options(store_options)
+            .build();
+        assert!(matches!(init_route(&opts), Err(Error::InvalidRoute(_))));
+    }
+
+    #[test_case("/users/:id", "/users/new")]
+    #[test_case("/users/new", "/users/*rest")]
+    #[test_case("/users/:id", "/users/:name/posts")]
+    #[test_case("/users", "/users/*rest")]
+    fn paths_without_conflict(a: &str, b: &str) {
+        assert!(!super::paths_conflict(a, b));
+        assert!(!super::paths_conflict(b, a));
+    }
+
+    #[tokio::test]
+    async fn echo_request() {
+        let script = r#"
+        local m = require('@lmb')
+        return { request = m.request, body = io.read('*a') }
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(true)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.post("/foo/bar/baz").json(&json!({"a":1})).await;
+        assert_eq!(200, res.status_code());
+
+        let value: Value = serde_json::from_str(&res.text()).unwrap();
+        let expected = json!({
+            "body": r#"{"a":1}"#,
+            "request": {
+                "body": {"a": 1},
+                "cookies": {},
+                "headers": {
+                    "content-type": "application/json",
+                },
+                "host": "localhost",
+                "method": "POST",
+                "path": "/foo/bar/baz",
+                "query": {},
+                "version": "HTTP/1.1",
+            },
+        });
+        assert_eq!(expected, value);
+    }
+
+    #[test_case(
+        "application/x-www-form-urlencoded",
+        "a=1&a=2&b=x%20y",
+        json!({ "a": ["1", "2"], "b": ["x y"] })
+    )]
+    #[test_case("application/json; charset=utf-8", r#"{"a":[1]}"#, json!({ "a": [1] }))]
+    #[test_case("application/problem+json", r#"{"a":1}"#, json!({ "a": 1 }))]
+    #[test_case("text/plain", "a=1", json!(null))]
+    #[tokio::test]
+    async fn parse_body(content_type: &'static str, body: &'static str, expected: Value) {
+        let script = r#"
+        local m = require('@lmb')
+        return { body = m.request.body, raw = io.read('*a') }
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(true)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.post("/").text(body).content_type(content_type).await;
+        let mut value: Value = res.json();
+        assert_eq!(json!(body), value["raw"]);
+        assert_eq!(expected, value["body"].take());
+    }
+
+    #[tokio::test]
+    async fn parse_body_errors() {
+        let script = r#"
+        local m = require('@lmb')
+        return { parsed = m.request.body ~= nil, raw = io.read('*a') }
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(true)
+        .max_parsed_body_size(8)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        // malformed bodies and bodies over the limit are left to the script
+        for body in ["{", r#"{"a":"long"}"#] {
+            let res = server
+                .post("/")
+                .text(body)
+                .content_type("application/json")
+                .await;
+            res.assert_status_ok();
+            res.assert_json(&json!({ "parsed": false, "raw": body }));
+        }
+        let res = server.post("/").text("long but not parsed").await;
+        res.assert_json(&json!({ "parsed": false, "raw": "long but not parsed" }));
+    }
+
+    #[tokio::test]
+    async fn parse_multipart_body() {
+        let script = r#"
+        local body = require('@lmb').request.body
+        if not body then return 'not parsed' end
+        local files = {}
+        for _, f in ipairs(body.files) do
+          table.insert(files, { f.name, f.filename, f.content_type, #f.bytes })
+        end
+        return { fields = body.fields, files = files }
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(true)
+        .max_files(1)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        let file = || {
+            Part::bytes(vec![0u8, 159, 146, 150])
+                .file_name("a.bin")
+                .mime_type("application/octet-stream")
+        };
+        let form = MultipartForm::new()
+            .add_text("a", "1")
+            .add_part("f", file());
+        let res = server.post("/").multipart(form).await;
+        res.assert_json(&json!({
+            "fields": { "a": ["1"] },
+            "files": [["f", "a.bin", "application/octet-stream", 4]],
+        }));
+
+        let form = MultipartForm::new()
+            .add_part("f", file())
+            .add_part("g", file());
+        let res = server.post("/").multipart(form).await;
+        res.assert_json(&json!("not parsed"));
+    }
+
+    #[tokio::test]
+    async fn request_query_remote_addr() {
+        let script = r#"
+        local m = require('@lmb')
+        local r = m.request
+        return {
+          a = r.query.a,
+          b = r.query.b[1],
+          host = r.host,
+          raw_query = r.raw_query,
+          remote_addr = r.remote_addr ~= nil,
+          version = r.version,
+        }
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(true)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router.into_make_service_with_connect_info::<SocketAddr>())
+            .unwrap();
+        let res = server.get("/?a=1&a=2&b=hello%20world").await;
+        assert_eq!(200, res.status_code());
+
+        let value: Value = serde_json::from_str(&res.text()).unwrap();
+        let host = server.server_address().unwrap();
+        let expected = json!({
+            "a": ["1", "2"],
+            "b": "hello world",
+            "host": format!("{}:{}", host.host_str().unwrap(), host.port().unwrap()),
+            "raw_query": "a=1&a=2&b=hello%20world",
+            "remote_addr": true,
+            "version": "HTTP/1.1",
+        });
+        assert_eq!(expected, value);
+    }
+
+    #[tokio::test]
+    async fn repeated_request_headers() {
+        let script = "return require('@lmb').request.headers['x-value']";
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server
+            .get("/")
+            .add_header(
+                HeaderName::from_static("x-value"),
+                HeaderValue::from_static("first"),
+            )
+            .add_header(
+                HeaderName::from_static("x-value"),
+                HeaderValue::from_static("second"),
+            )
+            .await;
+        res.assert_text("second");
+    }
+
+    #[tokio::test]
+    async fn headers_status_code() {
+        let script = r#"
+        local m = require('@lmb')
+        print(m.response)
+        local res = {}
+        res.status_code = 418 -- I'm a teapot
+        res.headers = { quantity = 1, whoami = "a teapot" }
+        m.response = res
+        print(m.response)
+        return "I'm a teapot."
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.post("/").await;
+        assert_eq!(418, res.status_code());
+        assert_eq!(
+            HeaderValue::from_static("a teapot"),
+            res.headers().get("whoami").unwrap()
+        );
+        assert_eq!("I'm a teapot.", res.text());
+    }
+
+    #[tokio::test]
+    async fn multi_value_headers() {
+        let script = r#"
+        local m = require('@lmb')
+        m.response = { headers = { ['x-value'] = { 'a', 'b' }, ['x-single'] = 'c' } }
+        return ''
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/").await;
+        let values: Vec<_> = res.headers().get_all("x-value").iter().collect();
+        assert_eq!(vec!["a", "b"], values);
+        res.assert_header("x-single", "c");
+    }
+
+    #[tokio::test]
+    async fn cookies() {
+        let script = r#"
+        local m = require('@lmb')
+        m.response:set_cookie('plain', 'a', { path = '/', http_only = true, same_site = 'lax' })
+        m.response:set_cookie('session', 'b', { max_age = 60, secure = true, signed = true })
+        return { cookies = m.request.cookies, signed_cookies = m.request.signed_cookies }
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .cookie_secret("secret".to_string())
+        .json(true)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        let res = server.get("/").await;
+        let set_cookies: Vec<_> = res
+            .headers()
+            .get_all("set-cookie")
+            .iter()
+            .map(|v| v.to_str().unwrap().to_string())
+            .collect();
+        assert_eq!("plain=a; HttpOnly; SameSite=Lax; Path=/", set_cookies[0]);
+        let session = set_cookies[1].split(';').next().unwrap();
+        assert!(session.starts_with("session=b."), "actual {session}");
+        assert!(set_cookies[1].ends_with("; Secure; Max-Age=60"));
+        res.assert_json(&json!({ "cookies": {}, "signed_cookies": {} }));
+
+        let res = server
+            .get("/")
+            .add_header(
+                COOKIE,
+                HeaderValue::from_str(&format!("plain=a; {session}")).unwrap(),
+            )
+            .await;
+        let value: Value = res.json();
+        assert_eq!(json!("a"), value["cookies"]["plain"]);
+        assert_eq!(json!({ "session": "b" }), value["signed_cookies"]);
+
+        let tampered = session.replacen("=b.", "=c.", 1);
+        let res = server
+            .get("/")
+            .add_header(COOKIE, HeaderValue::from_str(&tampered).unwrap())
+            .await;
+        let value: Value = res.json();
+        assert_eq!(json!({}), value["signed_cookies"]);
+    }
+
+    #[test_case(
+        "require('@lmb').response:set_cookie('a', 'b', { signed = true })",
+        "cookie secret is required to sign cookie a"
+    )]
+    #[test_case(
+        "return require('@lmb').request['signed_cookies']",
+        "cookie secret is required to read signed cookies"
+    )]
+    #[tokio::test]
+    async fn signed_cookie_without_secret(script: &'static str, expected: &str) {
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("a.lua", script).build()],
+        )
+        .dev(true)
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        let res = server.get("/").await;
+        res.assert_status_internal_server_error();
+        let problem: Value = res.json();
+        let detail = problem["detail"].as_str().unwrap();
+        assert!(detail.contains(expected), "{detail}");
+    }
+
+    #[tokio::test]
+    async fn signed_cookie_in_comment() {
+        let script = "-- m.response:set_cookie('a', 'b', { signed = true })\nreturn 'ok'";
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("a.lua", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        server.get("/").await.assert_text("ok");
+    }
+
+    #[tokio::test]
+    async fn dev_mode() {
+        let script = "local a = nil\nreturn a + 1";
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("a.lua", script).build()],
+        )
+        .dev(true)
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        let res = server.get("/").await;
+        res.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        res.assert_header("content-type", "application/problem+json");
+        let problem: Value = res.json();
+        assert_eq!(json!(500), problem["status"]);
+        assert_eq!(json!("Internal Server Error"), problem["title"]);
+        let detail = problem["detail"].as_str().unwrap();
+        assert!(detail.contains("attempt to perform arithmetic"), "{detail}");
+        let excerpt = problem["excerpt"].as_str().unwrap();
+        assert!(excerpt.contains("return a + 1"), "{excerpt}");
+        assert!(problem["traceback"].is_string(), "{problem}");
+
+        let res = server
+            .get("/")
+            .add_header(ACCEPT, HeaderValue::from_static("text/html"))
+            .await;
+        res.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        let html = res.text();
+        assert!(
+            html.contains("<h1>500 Internal Server Error</h1>"),
+            "{html}"
+        );
+        assert!(html.contains("return a + 1"), "{html}");
+    }
+
+    #[test_case("error({ status = 404 })", 404)]
+    #[test_case("error({ status = 200 })", 500)]
+    #[test_case("error({ message = 'oops' })", 500)]
+    #[tokio::test]
+    async fn raise_status(script: &'static str, expected: u16) {
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/").await;
+        assert_eq!(expected, res.status_code());
+        assert_eq!("", res.text());
+    }
+
+    #[tokio::test]
+    async fn error_handler() {
+        let handler = r#"
+        local m = require('@lmb')
+        local e = m.request.error
+        if e.status == 404 then
+          m.response = { status_code = 410, headers = { ['x-path'] = m.request.path } }
+        end
+        return e.status .. ' ' .. e.message
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let routes = vec![
+            ScriptRoute::builder("a.lua", "error({ status = 404, message = 'no user' })")
+                .path("/a".to_string())
+                .build(),
+            ScriptRoute::builder("b.lua", "return nil + 1")
+                .path("/b".to_string())
+                .build(),
+        ];
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), routes)
+            .error_handler(ScriptRoute::builder("error.lua", handler).build())
+            .json(false)
+            .store_options(store_options)
+            .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        let res = server.get("/a").await;
+        res.assert_status(StatusCode::GONE);
+        res.assert_header("x-path", "/a");
+        res.assert_text("404 no user");
+
+        let res = server.get("/b").await;
+        res.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(res.text().starts_with("500 "), "{}", res.text());
+
+        let res = server.get("/c").await;
+        res.assert_status(StatusCode::GONE);
+        res.assert_text("404 no route matches the path");
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        let store_options = StoreOptions::builder().build();
+        let routes = vec![
+            ScriptRoute::builder(
+                "a.lua",
+                "require('@lmb/metrics'):counter('a_total'):inc(); return 'a'",
+            )
+            .path("/a".to_string())
+            .build(),
+            ScriptRoute::builder(
+                "b.lua",
+                "local m = require('@lmb'); m.store.n = 1; return nil + 1",
+            )
+            .path("/b".to_string())
+            .build(),
+        ];
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), routes)
+            .json(false)
+            .metrics_path("/metrics")
+            .store_options(store_options)
+            .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        server.get("/a").await.assert_status_ok();
+        server.get("/a").await.assert_status_ok();
+        server
+            .post("/b")
+            .await
+            .assert_status_internal_server_error();
+        server.get("/c").await.assert_status_not_found();
+
+        let res = server.get("/metrics").await;
+        res.assert_status_ok();
+        res.assert_header("content-type", "text/plain; version=0.0.4");
+        let text = res.text();
+        for expected in [
+            r#"lmb_http_requests_total{method="GET",route="a.lua",status="200"} 2"#,
+            r#"lmb_http_requests_total{method="POST",route="b.lua",status="500"} 1"#,
+            r#"lmb_http_requests_total{method="GET",route="",status="404"} 1"#,
+            r#"lmb_evaluation_duration_seconds_count{script="a.lua"} 2"#,
+            r#"lmb_store_operation_duration_seconds_count{operation="put"} 1"#,
+            r#"lmb_evaluations_in_flight 0"#,
+            "a_total 2",
+        ] {
+            assert!(text.contains(expected), "{expected} not found in\n{text}");
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_bind() {
+        let store_options = StoreOptions::builder().build();
+        let routes = vec![ScriptRoute::builder("a.lua", "return 'a'").build()];
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), routes)
+            .json(false)
+            .metrics_bind("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+            .store_options(store_options)
+            .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        server.get("/metrics").await.assert_text("a");
+    }
+
+    #[tokio::test]
+    async fn compression() {
+        let store_options = StoreOptions::builder().build();
+        let script = "return string.rep('lmb', 100)";
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("a.lua", script).build()],
+        )
+        .compression(true)
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        for encoding in ["gzip", "br", "zstd"] {
+            let res = server.get("/").add_header(ACCEPT_ENCODING, encoding).await;
+            res.assert_header(CONTENT_ENCODING, encoding);
+            assert!(res.as_bytes().len() < 300);
         }
-        info!(?path, "open store");
-        store
-    } else {
-        let store = Store::default();
-        warn!("no store path is specified, an in-memory store will be used and values will be lost when process ends");
-        store
-    };
-    let app_state = AppState::builder()
-        .json(opts.json)
-        .name(&opts.name)
-        .script(&opts.script)
-        .store(store)
-        .maybe_timeout(opts.timeout)
-        .build();
-    let app = Router::new()
-        .route("/", any(index_route))
-        .route("/*path", any(match_all_route))
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-                .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
-        )
-        .with_state(app_state);
-    Ok(app)
-}
+        let res = server.get("/").await;
+        assert!(res.maybe_header(CONTENT_ENCODING).is_none());
+        assert_eq!(300, res.text().len());
+    }
 
-pub async fn serve_file<'a>(opts: &ServeOptions) -> anyhow::Result<()> {
-    let bind = &opts.bind;
-    let app = init_route(opts)?;
-    let listener = tokio::net::TcpListener::bind(&bind).await?;
-    info!(%bind, "serving lua script");
-    axum::serve(listener, app).await?;
-    Ok(())
-}
+    #[tokio::test]
+    async fn max_body_size() {
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("a.lua", "return #io.read('*a')").build()],
+        )
+        .json(false)
+        .max_body_size(4)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::init_route;
-    use crate::{serve::ServeOptions, Cli};
-    use axum_test::TestServer;
-    use clap::Parser;
-    use http::HeaderValue;
-    use lmb::StoreOptions;
-    use serde_json::{json, Value};
-    use std::net::SocketAddr;
+        server.post("/").text("1234").await.assert_text("4");
+        let res = server.post("/").text("12345").await;
+        res.assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+    }
 
     #[tokio::test]
-    async fn echo_request() {
-        let cli = Cli::parse_from(["lmb", "--json", "serve", "--file", "-"]);
+    async fn request_timeout() {
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("a.lua", busy_script(1.0)).build()],
+        )
+        .json(false)
+        .request_timeout(Duration::from_millis(100))
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        let start = Instant::now();
+        server
+            .get("/")
+            .await
+            .assert_status(StatusCode::GATEWAY_TIMEOUT);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_timed_out_request() {
         let script = r#"
-        local m = require('@lmb')
-        return { request = m.request, body = io.read('*a') }
+        if require('@lmb').request.query.wait then
+          while true do end
+        end
+        return 'ok'
         "#;
         let store_options = StoreOptions::builder().build();
-        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), "", script)
-            .json(cli.json)
-            .store_options(store_options)
-            .build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("a.lua", script).build()],
+        )
+        .json(false)
+        .max_concurrency(1)
+        .request_timeout(Duration::from_millis(100))
+        .store_options(store_options)
+        .build();
         let router = init_route(&opts).unwrap();
         let server = TestServer::new(router.into_make_service()).unwrap();
-        let res = server.post("/foo/bar/baz").json(&json!({"a":1})).await;
-        assert_eq!(200, res.status_code());
 
-        let value: Value = serde_json::from_str(&res.text()).unwrap();
-        let expected = json!({
-            "body": r#"{"a":1}"#,
-            "request": {
-                "headers": {
-                    "content-type": "application/json",
-                },
-                "method": "POST",
-                "path": "/foo/bar/baz",
-            },
-        });
-        assert_eq!(expected, value);
+        server
+            .get("/")
+            .add_query_param("wait", "1")
+            .await
+            .assert_status(StatusCode::GATEWAY_TIMEOUT);
+        // the slot is released once the script is cancelled
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        server.get("/").await.assert_text("ok");
     }
 
     #[tokio::test]
-    async fn headers_status_code() {
-        let cli = Cli::parse_from(["lmb", "serve", "--file", "-"]);
-        let script = r#"
-        local m = require('@lmb')
-        print(m.response)
-        local res = {}
-        res.status_code = 418 -- I'm a teapot
-        res.headers = { quantity = 1, whoami = "a teapot" }
-        m.response = res
-        print(m.response)
-        return "I'm a teapot."
-        "#;
+    async fn args_and_env() {
+        let script =
+            "local m = require('@lmb') return m.args[1] .. ' ' .. m.args.key .. ' ' .. m.env.NAME";
         let store_options = StoreOptions::builder().build();
-        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), "", script)
-            .json(cli.json)
-            .store_options(store_options)
+        let args = ScriptArgs::builder()
+            .positional(vec!["a".into()])
+            .named(Map::from_iter([("key".to_string(), "b".into())]))
             .build();
+        let env = HashMap::from([("NAME".to_string(), "c".to_string())]);
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("a.lua", script).build()],
+        )
+        .args(args)
+        .env(env)
+        .json(false)
+        .store_options(store_options)
+        .build();
         let router = init_route(&opts).unwrap();
         let server = TestServer::new(router.into_make_service()).unwrap();
-        let res = server.post("/").await;
-        assert_eq!(418, res.status_code());
+        server.get("/").await.assert_text("a b c");
+    }
+
+    #[tokio::test]
+    async fn memory_limit() {
+        let script = "local t = {} for i = 1, 1e7 do t[i] = { i } end return #t";
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("a.lua", script).build()],
+        )
+        .dev(true)
+        .memory_limit(1024 * 1024)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        let res = server.get("/").await;
+        res.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        let problem: Value = serde_json::from_str(&res.text()).unwrap();
         assert_eq!(
-            HeaderValue::from_static("a teapot"),
-            res.headers().get("whoami").unwrap()
+            json!("memory limit of 1048576 bytes exceeded"),
+            problem["detail"]
         );
-        assert_eq!("I'm a teapot.", res.text());
     }
 
     #[tokio::test]
     async fn headers_status_code_bad_script() {
-        let cli = Cli::parse_from(["lmb", "serve", "--file", "-"]);
         let script = "ret 'hello'";
         let store_options = StoreOptions::builder().build();
-        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), "", script)
-            .json(cli.json)
-            .store_options(store_options)
-            .build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
         let router = init_route(&opts).unwrap();
         let server = TestServer::new(router.into_make_service()).unwrap();
         let res = server.post("/").await;
@@ -302,7 +3265,6 @@ mod tests {
 
     #[tokio::test]
     async fn headers_status_code_invalid_status_code() {
-        let cli = Cli::parse_from(["lmb", "serve", "--file", "-"]);
         let script = r#"
         local m = require('@lmb')
         local res = {}
@@ -311,10 +3273,13 @@ mod tests {
         return "hello"
         "#;
         let store_options = StoreOptions::builder().build();
-        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), "", script)
-            .json(cli.json)
-            .store_options(store_options)
-            .build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
         let router = init_route(&opts).unwrap();
         let server = TestServer::new(router.into_make_service()).unwrap();
         let res = server.post("/").await;
@@ -322,15 +3287,515 @@ mod tests {
         assert_eq!("", res.text());
     }
 
+    #[tokio::test]
+    async fn reuse_virtual_machines() {
+        let script = r#"
+        count = (count or 0) + 1
+        return count
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .pool_size(1)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        for _ in 0..3 {
+            let res = server.post("/").await;
+            assert_eq!(200, res.status_code());
+            assert_eq!("1", res.text());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn max_concurrency() {
+        let script = r#"
+        local start = os.clock()
+        while os.clock() - start < 0.5 do end
+        return 1
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .max_concurrency(1)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let (a, b) = tokio::join!(
+            server.post("/").into_future(),
+            server.post("/").into_future()
+        );
+        let mut status_codes = vec![a.status_code(), b.status_code()];
+        status_codes.sort();
+        assert_eq!(vec![200, 503], status_codes);
+        let rejected = if a.status_code() == 503 { a } else { b };
+        assert_eq!(
+            HeaderValue::from_static("1"),
+            rejected.headers().get("retry-after").unwrap()
+        );
+    }
+
+    #[test_case("{ on_message = function() end }", Duration::from_millis(100); "idle")]
+    #[test_case(
+        "{ on_open = function(ws) while true do ws:send(string.rep('a', 65536)) end end }",
+        DEFAULT_WEBSOCKET_IDLE_TIMEOUT;
+        "not reading"
+    )]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn websocket_stuck_client(handler: &str, idle_timeout: Duration) {
+        let script = format!(
+            r#"
+            local m = require('@lmb')
+            if m.request.path == '/plain' then
+              return 1
+            end
+            return {handler}
+            "#
+        );
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .max_concurrency(1)
+        .store_options(store_options)
+        .timeout(Duration::from_millis(500))
+        .websocket_idle_timeout(idle_timeout)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router.into_make_service())
+            .unwrap();
+
+        // the client never reads, so it neither answers pings nor takes messages
+        let _ws = server.get_websocket("/chat").await.into_websocket().await;
+        let mut status = StatusCode::SERVICE_UNAVAILABLE;
+        for _ in 0..100 {
+            status = server.post("/plain").await.status_code();
+            if status != StatusCode::SERVICE_UNAVAILABLE {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(StatusCode::OK, status);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn websocket_busy() {
+        let script = r#"
+        local m = require('@lmb')
+        if m.request.path == '/plain' then
+          return 1
+        end
+        return {
+          on_message = function(ws, message)
+            ws:send(message)
+          end,
+        }
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .max_concurrency(1)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router.into_make_service())
+            .unwrap();
+
+        let mut ws = server.get_websocket("/chat").await.into_websocket().await;
+        ws.send_text("a").await;
+        ws.assert_receive_text("a").await;
+
+        // the connection holds the only slot, so other requests and upgrades are rejected
+        let res = server.post("/plain").await;
+        res.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            HeaderValue::from_static("1"),
+            res.headers().get("retry-after").unwrap()
+        );
+        server
+            .get_websocket("/chat")
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        ws.send_text("b").await;
+        ws.assert_receive_text("b").await;
+
+        // the slot is released once the connection is closed
+        ws.close().await;
+        let mut status = StatusCode::SERVICE_UNAVAILABLE;
+        for _ in 0..50 {
+            status = server.post("/plain").await.status_code();
+            if status != StatusCode::SERVICE_UNAVAILABLE {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(StatusCode::OK, status);
+    }
+
+    #[tokio::test]
+    async fn binary_body() {
+        let script = r#"
+        local m = require('@lmb')
+        m.response = { headers = { ['content-type'] = 'application/octet-stream' } }
+        return '\255\0\1'
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/").await;
+        assert_eq!(200, res.status_code());
+        assert_eq!(&[255u8, 0, 1][..], res.as_bytes().as_ref());
+    }
+
+    #[tokio::test]
+    async fn stream_body() {
+        let script = r#"
+        local m = require('@lmb')
+        m.response = { status_code = 201 }
+        local i = 0
+        return function()
+          i = i + 1
+          if i <= 3 then return 'chunk' .. i .. ';' end
+        end
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router.into_make_service())
+            .unwrap();
+        let res = server.get("/").await;
+        assert_eq!(201, res.status_code());
+        assert_eq!(
+            HeaderValue::from_static("chunked"),
+            res.headers().get("transfer-encoding").unwrap()
+        );
+        assert_eq!("chunk1;chunk2;chunk3;", res.text());
+    }
+
+    #[tokio::test]
+    async fn stream_body_timeout() {
+        let script = r#"
+        local i = 0
+        return function()
+          i = i + 1
+          if i > 5 then return nil end
+          local t = os.clock()
+          while os.clock() - t < 0.1 do end
+          if i == 3 and require('@lmb').request.query.hang then
+            while true do end
+          end
+          return tostring(i)
+        end
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .timeout(Duration::from_millis(250))
+        .build();
+        let router = init_route(&opts).unwrap();
+
+        // the stream lasts longer than the timeout of the evaluation
+        let res = router
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!("12345", body);
+
+        // a chunk running longer than the timeout stops the stream
+        let start = Instant::now();
+        let res = router
+            .oneshot(Request::get("/?hang=1").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(to_bytes(res.into_body(), usize::MAX).await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test_case("return function() return 'chunk' end")]
+    #[test_case("while true do require('@lmb'):emit('event') end")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stream_to_slow_client(stream: &str) {
+        let script =
+            format!("if require('@lmb').request.query.once then return 'once' end\n{stream}");
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .max_concurrency(1)
+        .store_options(store_options)
+        .timeout(Duration::from_millis(200))
+        .build();
+        let router = init_route(&opts).unwrap();
+
+        // the body is never read, so the stream stops when sending times out
+        let res = router
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let start = Instant::now();
+        loop {
+            let once = router
+                .clone()
+                .oneshot(Request::get("/?once=1").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            if once.status() == StatusCode::OK {
+                break;
+            }
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, once.status());
+            assert!(start.elapsed() < Duration::from_secs(5), "slot is held");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        drop(res);
+    }
+
+    #[tokio::test]
+    async fn stream_body_error() {
+        let script = r#"
+        local i = 0
+        return function()
+          i = i + 1
+          if i == 1 then return 'ok' end
+          error('boom')
+        end
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let res = router
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(200, res.status());
+        assert!(to_bytes(res.into_body(), usize::MAX).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sse_events() {
+        let script = r#"
+        local m = require('@lmb')
+        m.response = { headers = { ['x-stream'] = 'events' } }
+        m:emit({ event = 'greeting', id = '1', data = 'hello' })
+        m:emit({ data = { n = 1 }, retry = 1000 })
+        m:emit('bye')
+        return 'ignored'
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/").await;
+        res.assert_status_ok();
+        res.assert_header("content-type", "text/event-stream");
+        res.assert_header("x-stream", "events");
+        res.assert_text(concat!(
+            "event: greeting\nid: 1\ndata: hello\n\n",
+            "retry:1000\ndata: {\"n\":1}\n\n",
+            "data: bye\n\n",
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sse_idle_timeout() {
+        let script = r#"
+        local m = require('@lmb')
+        local start = os.clock()
+        local last = start
+        m:emit('0')
+        while os.clock() - start < 0.3 do
+          if os.clock() - last > 0.05 then
+            last = os.clock()
+            m:emit('tick')
+          end
+        end
+        m:emit('done')
+        "#;
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .sse_idle_timeout(Duration::from_millis(200))
+        .sse_keep_alive(Duration::from_millis(10))
+        .store_options(store_options)
+        .timeout(Duration::from_millis(100))
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let text = server.get("/").await.text();
+        assert!(text.starts_with("data: 0\n\n"), "actual {text:?}");
+        assert!(text.ends_with("data: done\n\n"), "actual {text:?}");
+        assert!(text.contains(":\n\n"), "actual {text:?}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sse_client_disconnect() {
+        let script = r#"
+        local m = require('@lmb')
+        local ok = pcall(function()
+          while true do m:emit('tick') end
+        end)
+        m.store.disconnected = not ok
+        "#;
+        let dir = TempDir::new().unwrap();
+        let store_path = dir.child("db.sqlite3");
+        let store_options = StoreOptions::builder()
+            .store_path(store_path.to_path_buf())
+            .run_migrations(true)
+            .build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let res = router
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let mut stream = res.into_body().into_data_stream();
+        assert_eq!("data: tick\n\n", stream.next().await.unwrap().unwrap());
+        drop(stream);
+
+        let store = Store::new(store_path.path()).unwrap();
+        let mut disconnected = Value::Null;
+        for _ in 0..100 {
+            disconnected = store.get("disconnected").unwrap();
+            if !disconnected.is_null() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(json!(true), disconnected);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn websocket_handler() {
+        let script = r#"
+        local m = require('@lmb')
+        local count = 0
+        return {
+          on_open = function(ws)
+            ws:send('welcome to ' .. m.request.path)
+          end,
+          on_message = function(ws, message)
+            count = count + 1
+            ws:send(count .. ':' .. message)
+          end,
+          on_close = function()
+            m.store.count = count
+          end,
+        }
+        "#;
+        let dir = TempDir::new().unwrap();
+        let store_path = dir.child("db.sqlite3");
+        let store_options = StoreOptions::builder()
+            .store_path(store_path.to_path_buf())
+            .run_migrations(true)
+            .build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::builder()
+            .http_transport()
+            .build(router.into_make_service())
+            .unwrap();
+
+        let res = server.get("/chat").await;
+        res.assert_status(StatusCode::UPGRADE_REQUIRED);
+
+        let mut ws = server.get_websocket("/chat").await.into_websocket().await;
+        ws.assert_receive_text("welcome to /chat").await;
+        ws.send_text("a").await;
+        ws.assert_receive_text("1:a").await;
+        ws.send_text("b").await;
+        ws.assert_receive_text("2:b").await;
+        ws.close().await;
+
+        let store = Store::new(store_path.path()).unwrap();
+        let mut count = Value::Null;
+        for _ in 0..100 {
+            count = store.get("count").unwrap();
+            if !count.is_null() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(json!(2), count);
+    }
+
     #[tokio::test]
     async fn json_string() {
-        let cli = Cli::parse_from(["lmb", "--json", "serve", "--file", "-"]);
         let script = "return 'hello'";
         let store_options = StoreOptions::builder().build();
-        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), "", script)
-            .json(cli.json)
-            .store_options(store_options)
-            .build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(true)
+        .store_options(store_options)
+        .build();
         let router = init_route(&opts).unwrap();
         let server = TestServer::new(router.into_make_service()).unwrap();
         let res = server.post("/").await;
@@ -340,13 +3805,15 @@ mod tests {
 
     #[tokio::test]
     async fn number() {
-        let cli = Cli::parse_from(["lmb", "serve", "--file", "-"]);
         let script = r#"return 1"#;
         let store_options = StoreOptions::builder().build();
-        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), "", script)
-            .json(cli.json)
-            .store_options(store_options)
-            .build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
         let router = init_route(&opts).unwrap();
         let server = TestServer::new(router.into_make_service()).unwrap();
         let res = server.post("/").await;
@@ -356,13 +3823,15 @@ mod tests {
 
     #[tokio::test]
     async fn raw_string() {
-        let cli = Cli::parse_from(["lmb", "serve", "--file", "-"]);
         let script = "return 'hello'";
         let store_options = StoreOptions::builder().build();
-        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), "", script)
-            .json(cli.json)
-            .store_options(store_options)
-            .build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
         let router = init_route(&opts).unwrap();
         let server = TestServer::new(router.into_make_service()).unwrap();
         let res = server.post("/").await;
@@ -372,13 +3841,15 @@ mod tests {
 
     #[tokio::test]
     async fn serve() {
-        let cli = Cli::parse_from(["lmb", "--json", "serve", "--file", "-"]);
         let script = "return 1";
         let store_options = StoreOptions::builder().build();
-        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), "", script)
-            .json(cli.json)
-            .store_options(store_options)
-            .build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", script).build()],
+        )
+        .json(true)
+        .store_options(store_options)
+        .build();
         let router = init_route(&opts).unwrap();
         let server = TestServer::new(router.into_make_service()).unwrap();
         let res = server.post("/").await;
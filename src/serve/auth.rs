@@ -0,0 +1,587 @@
+use anyhow::{anyhow, bail, Context as _};
+use axum::{
+    body::Body,
+    extract::{Request, State as AxumState},
+    middleware::Next,
+    response::{IntoResponse as _, Response},
+};
+use base64::prelude::*;
+use bon::Builder;
+use http::{
+    header::{AUTHORIZATION, RETRY_AFTER, WWW_AUTHENTICATE},
+    HeaderMap, StatusCode,
+};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde_json::{json, Value};
+use sha1::{Digest as _, Sha1};
+use std::{
+    collections::HashMap,
+    fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+use subtle::ConstantTimeEq as _;
+use tokio::{sync::Semaphore, task};
+use tracing::{debug, error, warn};
+
+/// Authentication of requests, checked before scripts are evaluated.
+#[derive(Clone, Debug)]
+pub enum AuthOptions {
+    /// Bearer tokens in a file, one per line, optionally prefixed with a subject and a colon.
+    Tokens(PathBuf),
+    /// Basic authentication with users in an htpasswd file, hashed with bcrypt or SHA-1.
+    Htpasswd(PathBuf),
+    /// Bearer tokens in JSON Web Token.
+    Jwt(JwtOptions),
+}
+
+/// Options to verify JSON Web Tokens. Tokens must not expire.
+#[derive(Builder, Clone, Debug)]
+pub struct JwtOptions {
+    /// Audience, the `aud` claim.
+    #[builder(into)]
+    audience: Option<String>,
+    /// Issuer, the `iss` claim.
+    #[builder(into)]
+    issuer: Option<String>,
+    /// JWKS file of public keys to verify tokens signed with RS256.
+    #[builder(into)]
+    jwks: Option<PathBuf>,
+    /// Secret to verify tokens signed with HS256.
+    #[builder(into)]
+    secret: Option<String>,
+}
+
+/// Claims of the authenticated request, available in `m.request.auth`.
+#[derive(Clone, Debug)]
+pub(super) struct AuthClaims(pub(super) Value);
+
+pub(super) enum Authenticator {
+    Tokens(Vec<(Option<String>, String)>),
+    Htpasswd {
+        users: HashMap<String, String>,
+        /// Hash verified for unknown users, as slow as the hashes of the users.
+        dummy: String,
+        /// Slots of password verifications, one per CPU, as bcrypt is slow by design.
+        verifications: Arc<Semaphore>,
+    },
+    Jwt {
+        keys: Vec<(Option<String>, Algorithm, DecodingKey)>,
+        options: JwtOptions,
+    },
+}
+
+impl Authenticator {
+    /// Read the files of the options.
+    pub(super) fn new(options: &AuthOptions) -> anyhow::Result<Arc<Self>> {
+        let auth = match options {
+            AuthOptions::Tokens(path) => {
+                let tokens = read_lines(path)?
+                    .into_iter()
+                    .map(|line| match line.split_once(':') {
+                        Some((subject, token)) => (Some(subject.to_string()), token.to_string()),
+                        None => (None, line.clone()),
+                    })
+                    .collect();
+                Self::Tokens(tokens)
+            }
+            AuthOptions::Htpasswd(path) => {
+                let mut users = HashMap::new();
+                for line in read_lines(path)? {
+                    let Some((user, hash)) = line.split_once(':') else {
+                        bail!("invalid line in {}", path.display());
+                    };
+                    if !is_bcrypt(hash) && !hash.starts_with("{SHA}") {
+                        bail!("password of {user} must be hashed with bcrypt or SHA-1");
+                    }
+                    users.insert(user.to_string(), hash.to_string());
+                }
+                let dummy = match users.values().find(|hash| is_bcrypt(hash)) {
+                    Some(hash) => {
+                        let cost = hash
+                            .get(4..6)
+                            .and_then(|cost| cost.parse().ok())
+                            .unwrap_or(bcrypt::DEFAULT_COST);
+                        bcrypt::hash("", cost)?
+                    }
+                    None => "{SHA}".to_string(),
+                };
+                let cpus = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+                Self::Htpasswd {
+                    users,
+                    dummy,
+                    verifications: Arc::new(Semaphore::new(cpus)),
+                }
+            }
+            AuthOptions::Jwt(options) => {
+                let mut keys = Vec::new();
+                if let Some(secret) = &options.secret {
+                    keys.push((
+                        None,
+                        Algorithm::HS256,
+                        DecodingKey::from_secret(secret.as_bytes()),
+                    ));
+                }
+                if let Some(path) = &options.jwks {
+                    let jwks: JwkSet = serde_json::from_str(
+                        &fs::read_to_string(path)
+                            .with_context(|| format!("failed to read {}", path.display()))?,
+                    )?;
+                    for jwk in &jwks.keys {
+                        keys.push((
+                            jwk.common.key_id.clone(),
+                            Algorithm::RS256,
+                            DecodingKey::from_jwk(jwk)?,
+                        ));
+                    }
+                }
+                if keys.is_empty() {
+                    bail!("either a secret or a JWKS file is required to verify tokens");
+                }
+                Self::Jwt {
+                    keys,
+                    options: options.clone(),
+                }
+            }
+        };
+        Ok(Arc::new(auth))
+    }
+
+    /// Verify credentials in the `Authorization` header, returning the claims.
+    fn authenticate(&self, headers: &HeaderMap) -> anyhow::Result<Value> {
+        let authorization = headers
+            .get(AUTHORIZATION)
+            .ok_or_else(|| anyhow!("no credentials"))?
+            .to_str()?;
+        let (scheme, credentials) = authorization
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("invalid authorization header"))?;
+        let credentials = credentials.trim();
+        match self {
+            Self::Tokens(tokens) => {
+                expect_scheme(scheme, "bearer")?;
+                // compare with every token, so the time does not tell which one matches
+                let mut subject = None;
+                let mut matched = false;
+                for (sub, token) in tokens {
+                    if bool::from(token.as_bytes().ct_eq(credentials.as_bytes())) {
+                        subject = sub.as_deref();
+                        matched = true;
+                    }
+                }
+                if !matched {
+                    bail!("invalid token");
+                }
+                Ok(match subject {
+                    Some(sub) => json!({ "sub": sub }),
+                    None => json!({}),
+                })
+            }
+            Self::Htpasswd { users, dummy, .. } => {
+                expect_scheme(scheme, "basic")?;
+                let decoded = String::from_utf8(BASE64_STANDARD.decode(credentials)?)?;
+                let (user, password) = decoded
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("invalid credentials"))?;
+                // verify unknown users too, so the time does not tell whether the user exists
+                let hash = users.get(user);
+                let verified = verify_password(password, hash.unwrap_or(dummy))?;
+                if hash.is_none() {
+                    bail!("no such user");
+                }
+                if !verified {
+                    bail!("invalid password");
+                }
+                Ok(json!({ "sub": user }))
+            }
+            Self::Jwt { keys, options } => {
+                expect_scheme(scheme, "bearer")?;
+                let header = jsonwebtoken::decode_header(credentials)?;
+                let mut validation = Validation::new(header.alg);
+                validation.validate_aud = options.audience.is_some();
+                if let Some(audience) = &options.audience {
+                    validation.set_audience(&[audience]);
+                    validation.required_spec_claims.insert("aud".to_string());
+                }
+                if let Some(issuer) = &options.issuer {
+                    validation.set_issuer(&[issuer]);
+                    validation.required_spec_claims.insert("iss".to_string());
+                }
+                let candidates = keys.iter().filter(|(kid, alg, _)| {
+                    *alg == header.alg && (header.kid.is_none() || kid == &header.kid)
+                });
+                let mut last_error = anyhow!("no key to verify the token");
+                for (_, _, key) in candidates {
+                    match jsonwebtoken::decode::<Value>(credentials, key, &validation) {
+                        Ok(data) => return Ok(data.claims),
+                        Err(err) => last_error = err.into(),
+                    }
+                }
+                Err(last_error)
+            }
+        }
+    }
+
+    fn challenge(&self) -> &'static str {
+        match self {
+            Self::Tokens(_) | Self::Jwt { .. } => "Bearer",
+            Self::Htpasswd { .. } => r#"Basic realm="lmb""#,
+        }
+    }
+}
+
+/// Read lines of the file, skipping empty lines and comments.
+fn read_lines(path: &Path) -> anyhow::Result<Vec<String>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+fn expect_scheme(scheme: &str, expected: &str) -> anyhow::Result<()> {
+    if !scheme.eq_ignore_ascii_case(expected) {
+        bail!("unexpected authorization scheme {scheme}");
+    }
+    Ok(())
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+fn verify_password(password: &str, hash: &str) -> anyhow::Result<bool> {
+    if let Some(digest) = hash.strip_prefix("{SHA}") {
+        let expected = BASE64_STANDARD.encode(Sha1::digest(password.as_bytes()));
+        return Ok(expected.as_bytes().ct_eq(digest.as_bytes()).into());
+    }
+    Ok(bcrypt::verify(password, hash)?)
+}
+
+/// Reject requests without valid credentials before any Lua virtual machine is taken.
+pub(super) async fn authenticate(
+    AxumState(auth): AxumState<Arc<Authenticator>>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    // Hashing passwords with bcrypt takes a while, so verifications beyond the slots
+    // are rejected instead of piling up on the blocking pool, like evaluations are.
+    let permit = match &*auth {
+        Authenticator::Htpasswd { verifications, .. } => {
+            let Ok(permit) = verifications.clone().try_acquire_owned() else {
+                warn!("too many concurrent password verifications");
+                return (StatusCode::SERVICE_UNAVAILABLE, [(RETRY_AFTER, "1")]).into_response();
+            };
+            Some(permit)
+        }
+        _ => None,
+    };
+    let verified = task::spawn_blocking({
+        let auth = auth.clone();
+        let headers = req.headers().clone();
+        move || {
+            let _permit = permit;
+            auth.authenticate(&headers)
+        }
+    })
+    .await;
+    match verified {
+        Ok(Ok(claims)) => {
+            req.extensions_mut().insert(AuthClaims(claims));
+            next.run(req).await
+        }
+        Ok(Err(err)) => {
+            debug!(%err, "unauthorized request");
+            (
+                StatusCode::UNAUTHORIZED,
+                [(WWW_AUTHENTICATE, auth.challenge())],
+            )
+                .into_response()
+        }
+        Err(err) => {
+            error!(?err, "failed to authenticate request");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, NamedTempFile};
+    use axum::{middleware, routing, Router};
+    use axum_test::TestServer;
+    use base64::prelude::*;
+    use http::{
+        header::{RETRY_AFTER, WWW_AUTHENTICATE},
+        StatusCode,
+    };
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde_json::{json, Value};
+    use std::{
+        net::SocketAddr,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+    use test_case::test_case;
+
+    use super::{authenticate, AuthOptions, Authenticator, JwtOptions};
+    use crate::{
+        serve::{init_route, ScriptRoute, ServeOptions},
+        StoreOptions,
+    };
+
+    const SCRIPT: &str = r#"
+    local m = require('@lmb')
+    m.store.evaluated = true
+    return m.request.auth
+    "#;
+
+    fn server(auth: AuthOptions) -> TestServer {
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("auth.lua", SCRIPT)
+                .path("/".to_string())
+                .build()],
+        )
+        .auth(auth)
+        .json(true)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        TestServer::new(router.into_make_service()).unwrap()
+    }
+
+    async fn get(server: &TestServer, authorization: Option<&str>) -> (StatusCode, Value) {
+        let mut req = server.get("/");
+        if let Some(authorization) = authorization {
+            req = req.authorization(authorization);
+        }
+        let res = req.await;
+        let status = res.status_code();
+        let body = match status {
+            StatusCode::OK => res.json(),
+            _ => Value::Null,
+        };
+        (status, body)
+    }
+
+    fn exp(offset: i64) -> i64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        i64::try_from(now.as_secs()).unwrap() + offset
+    }
+
+    #[tokio::test]
+    async fn tokens() {
+        let file = NamedTempFile::new("tokens").unwrap();
+        file.write_str("# comment\nsecret\nalice:t0ken\n").unwrap();
+        let server = server(AuthOptions::Tokens(file.path().to_path_buf()));
+
+        assert_eq!(
+            (StatusCode::OK, json!({})),
+            get(&server, Some("Bearer secret")).await
+        );
+        assert_eq!(
+            (StatusCode::OK, json!({ "sub": "alice" })),
+            get(&server, Some("bearer t0ken")).await
+        );
+        for authorization in [None, Some("Bearer wrong"), Some("Basic secret")] {
+            let res = match authorization {
+                Some(a) => server.get("/").authorization(a).await,
+                None => server.get("/").await,
+            };
+            res.assert_status_unauthorized();
+            res.assert_header(WWW_AUTHENTICATE, "Bearer");
+        }
+    }
+
+    #[tokio::test]
+    async fn htpasswd() {
+        let file = NamedTempFile::new("htpasswd").unwrap();
+        let bcrypt = bcrypt::hash("pass", 4).unwrap();
+        // SHA-1 of "pass" in base64
+        file.write_str(&format!(
+            "alice:{bcrypt}\nbob:{{SHA}}nU4eI71bcnBGqeO0t9tXvY1u5oQ=\n"
+        ))
+        .unwrap();
+        let server = server(AuthOptions::Htpasswd(file.path().to_path_buf()));
+        let basic = |credentials: &str| format!("Basic {}", BASE64_STANDARD.encode(credentials));
+
+        assert_eq!(
+            (StatusCode::OK, json!({ "sub": "alice" })),
+            get(&server, Some(&basic("alice:pass"))).await
+        );
+        assert_eq!(
+            (StatusCode::OK, json!({ "sub": "bob" })),
+            get(&server, Some(&basic("bob:pass"))).await
+        );
+        for credentials in ["alice:wrong", "bob:wrong", "carol:pass", "carol:"] {
+            let res = server.get("/").authorization(basic(credentials)).await;
+            res.assert_status_unauthorized();
+            res.assert_header(WWW_AUTHENTICATE, r#"Basic realm="lmb""#);
+        }
+    }
+
+    #[test]
+    fn htpasswd_dummy() {
+        let file = NamedTempFile::new("htpasswd").unwrap();
+        let bcrypt = bcrypt::hash("pass", 4).unwrap();
+        file.write_str(&format!("alice:{bcrypt}\n")).unwrap();
+        let auth = Authenticator::new(&AuthOptions::Htpasswd(file.path().to_path_buf())).unwrap();
+        let Authenticator::Htpasswd { dummy, .. } = &*auth else {
+            panic!("expect htpasswd");
+        };
+        // as slow as the hash of alice
+        assert_eq!(bcrypt[..7], dummy[..7]);
+    }
+
+    #[tokio::test]
+    async fn htpasswd_busy() {
+        let file = NamedTempFile::new("htpasswd").unwrap();
+        let bcrypt = bcrypt::hash("pass", 4).unwrap();
+        file.write_str(&format!("alice:{bcrypt}\n")).unwrap();
+        let auth = Authenticator::new(&AuthOptions::Htpasswd(file.path().to_path_buf())).unwrap();
+        let Authenticator::Htpasswd { verifications, .. } = &*auth else {
+            panic!("expect htpasswd");
+        };
+        let verifications = verifications.clone();
+        let router = Router::new()
+            .route("/", routing::get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(auth, authenticate));
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let basic = format!("Basic {}", BASE64_STANDARD.encode("alice:pass"));
+
+        let permits = verifications.available_permits();
+        let taken = verifications.acquire_many(permits as u32).await.unwrap();
+        let res = server.get("/").authorization(&basic).await;
+        res.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        res.assert_header(RETRY_AFTER, "1");
+
+        drop(taken);
+        server
+            .get("/")
+            .authorization(&basic)
+            .await
+            .assert_text("ok");
+    }
+
+    #[test]
+    fn htpasswd_unsupported_hash() {
+        let file = NamedTempFile::new("htpasswd").unwrap();
+        file.write_str("alice:$apr1$salt$hash\n").unwrap();
+        let options = AuthOptions::Htpasswd(file.path().to_path_buf());
+        assert!(super::Authenticator::new(&options).is_err());
+    }
+
+    #[test_case(json!({ "sub": "alice", "exp": exp(60), "iss": "lmb", "aud": "api" }), true)]
+    #[test_case(json!({ "sub": "alice", "exp": exp(-120), "iss": "lmb", "aud": "api" }), false)]
+    #[test_case(json!({ "sub": "alice", "iss": "lmb", "aud": "api" }), false)]
+    #[test_case(json!({ "sub": "alice", "exp": exp(60), "iss": "other", "aud": "api" }), false)]
+    #[test_case(json!({ "sub": "alice", "exp": exp(60), "iss": "lmb", "aud": "other" }), false)]
+    #[test_case(json!({ "sub": "alice", "exp": exp(60), "iss": "lmb" }), false)]
+    #[tokio::test]
+    async fn jwt_hs256(claims: Value, valid: bool) {
+        let options = JwtOptions::builder()
+            .audience("api")
+            .issuer("lmb")
+            .secret("secret")
+            .build();
+        let server = server(AuthOptions::Jwt(options));
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let (status, body) = get(&server, Some(&format!("Bearer {token}"))).await;
+        if valid {
+            assert_eq!((StatusCode::OK, claims.clone()), (status, body));
+        } else {
+            assert_eq!(StatusCode::UNAUTHORIZED, status);
+        }
+
+        let forged = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"forged"),
+        )
+        .unwrap();
+        let (status, _) = get(&server, Some(&format!("Bearer {forged}"))).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, status);
+    }
+
+    #[tokio::test]
+    async fn jwt_rs256() {
+        let options = JwtOptions::builder()
+            .jwks("tests/fixtures/jwks.json")
+            .build();
+        let server = server(AuthOptions::Jwt(options));
+        let key =
+            EncodingKey::from_rsa_pem(include_bytes!("../../tests/fixtures/jwt.key")).unwrap();
+        let claims = json!({ "sub": "alice", "exp": exp(60) });
+
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test".to_string());
+        let token = jsonwebtoken::encode(&header, &claims, &key).unwrap();
+        assert_eq!(
+            (StatusCode::OK, claims.clone()),
+            get(&server, Some(&format!("Bearer {token}"))).await
+        );
+
+        header.kid = Some("unknown".to_string());
+        let token = jsonwebtoken::encode(&header, &claims, &key).unwrap();
+        let (status, _) = get(&server, Some(&format!("Bearer {token}"))).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, status);
+
+        // tokens signed with HS256 are not verified by the public keys
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let (status, _) = get(&server, Some(&format!("Bearer {token}"))).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, status);
+    }
+
+    #[tokio::test]
+    async fn reject_before_evaluation() {
+        let file = NamedTempFile::new("tokens").unwrap();
+        file.write_str("secret\n").unwrap();
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("auth.lua", SCRIPT)
+                .path("/".to_string())
+                .build()],
+        )
+        .auth(AuthOptions::Tokens(file.path().to_path_buf()))
+        .error_handler(ScriptRoute::builder("error.lua", "return 'error handler'").build())
+        .json(false)
+        .metrics_path("/metrics")
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+
+        server.get("/").await.assert_status_unauthorized();
+        let res = server.get("/unknown").await;
+        res.assert_status_unauthorized();
+        res.assert_text("");
+
+        // metrics are exempt from authentication, so scrapers need no credentials
+        let res = server.get("/metrics").await;
+        res.assert_status_ok();
+        let text = res.text();
+        assert!(
+            !text.contains("lmb_evaluation_duration_seconds_count"),
+            "{text}"
+        );
+    }
+}
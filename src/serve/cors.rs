@@ -0,0 +1,158 @@
+use anyhow::bail;
+use bon::Builder;
+use http::{HeaderName, HeaderValue, Method};
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+/// Options of Cross-Origin Resource Sharing. Preflight requests are answered
+/// without running scripts.
+#[derive(Builder, Clone, Debug)]
+pub struct CorsOptions {
+    /// Allowed origins e.g. `https://example.com`, or `*` for any origin.
+    #[builder(start_fn)]
+    origins: Vec<String>,
+    /// Allowed methods. Methods requested by preflight requests are allowed when empty.
+    #[builder(default)]
+    methods: Vec<Method>,
+    /// Allowed request headers. Headers requested by preflight requests are allowed when empty.
+    #[builder(default)]
+    headers: Vec<HeaderName>,
+    /// Allow credentials e.g. cookies. Origins must be listed explicitly.
+    #[builder(default)]
+    credentials: bool,
+    /// Cache preflight responses for the duration.
+    max_age: Option<Duration>,
+}
+
+impl CorsOptions {
+    pub(super) fn layer(&self) -> anyhow::Result<CorsLayer> {
+        let any_origin = self.origins.iter().any(|o| o == "*");
+        if any_origin && self.credentials {
+            bail!("credentials cannot be allowed for any origin");
+        }
+        let origins = if any_origin {
+            AllowOrigin::any()
+        } else {
+            let origins = self
+                .origins
+                .iter()
+                .map(|o| HeaderValue::from_str(o))
+                .collect::<Result<Vec<_>, _>>()?;
+            AllowOrigin::list(origins)
+        };
+        let methods = if self.methods.is_empty() {
+            AllowMethods::mirror_request()
+        } else {
+            AllowMethods::list(self.methods.clone())
+        };
+        let headers = if self.headers.is_empty() {
+            AllowHeaders::mirror_request()
+        } else {
+            AllowHeaders::list(self.headers.clone())
+        };
+        let mut layer = CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(self.credentials);
+        if let Some(max_age) = self.max_age {
+            layer = layer.max_age(max_age);
+        }
+        Ok(layer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum_test::TestServer;
+    use http::{
+        header::{
+            ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+        },
+        HeaderName, Method, StatusCode,
+    };
+    use std::net::SocketAddr;
+
+    use super::CorsOptions;
+    use crate::{
+        serve::{init_route, ScriptRoute, ServeOptions},
+        StoreOptions,
+    };
+
+    fn server(cors: CorsOptions) -> TestServer {
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder(
+                "cors.lua",
+                "if require('@lmb').request.method == 'OPTIONS' then error('evaluated') end return 'ok'",
+            )
+            .build()],
+        )
+        .cors(cors)
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let router = init_route(&opts).unwrap();
+        TestServer::new(router.into_make_service()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn preflight() {
+        let cors = CorsOptions::builder(vec!["https://example.com".to_string()])
+            .methods(vec![Method::GET, Method::POST])
+            .headers(vec![HeaderName::from_static("x-token")])
+            .credentials(true)
+            .build();
+        let server = server(cors);
+
+        let res = server
+            .method(Method::OPTIONS, "/")
+            .add_header(ORIGIN, "https://example.com")
+            .add_header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .add_header(ACCESS_CONTROL_REQUEST_HEADERS, "x-token")
+            .await;
+        res.assert_status_ok();
+        res.assert_header(ACCESS_CONTROL_ALLOW_ORIGIN, "https://example.com");
+        res.assert_header(ACCESS_CONTROL_ALLOW_METHODS, "GET,POST");
+        res.assert_header(ACCESS_CONTROL_ALLOW_HEADERS, "x-token");
+        res.assert_header(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+
+        let res = server
+            .get("/")
+            .add_header(ORIGIN, "https://example.com")
+            .await;
+        res.assert_text("ok");
+        res.assert_header(ACCESS_CONTROL_ALLOW_ORIGIN, "https://example.com");
+
+        let res = server
+            .get("/")
+            .add_header(ORIGIN, "https://other.com")
+            .await;
+        res.assert_text("ok");
+        assert!(res.maybe_header(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn any_origin() {
+        let server = server(CorsOptions::builder(vec!["*".to_string()]).build());
+        let res = server
+            .method(Method::OPTIONS, "/")
+            .add_header(ORIGIN, "https://example.com")
+            .add_header(ACCESS_CONTROL_REQUEST_METHOD, "DELETE")
+            .await;
+        assert_eq!(StatusCode::OK, res.status_code());
+        res.assert_header(ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+        res.assert_header(ACCESS_CONTROL_ALLOW_METHODS, "DELETE");
+    }
+
+    #[test]
+    fn credentials_for_any_origin() {
+        let cors = CorsOptions::builder(vec!["*".to_string()])
+            .credentials(true)
+            .build();
+        assert!(cors.layer().is_err());
+    }
+}
@@ -0,0 +1,413 @@
+use anyhow::bail;
+use axum::{body::Body, extract::ConnectInfo, Router};
+use http::Request;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{
+        conn::auto,
+        graceful::{GracefulShutdown, Watcher},
+    },
+    service::TowerToHyperService,
+};
+use listenfd::ListenFd;
+use std::{
+    fmt, fs, future::Future, io, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+use tower::ServiceExt as _;
+use tracing::{debug, info, warn};
+
+use super::tls::{ReloadableTls, TlsInfo};
+use crate::Error;
+
+/// Prefix of addresses of Unix domain sockets.
+const UNIX_PREFIX: &str = "unix:";
+
+/// Connections are dropped when the TLS handshake takes longer.
+pub(super) const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Address which the server is bound to, a TCP address e.g. `127.0.0.1:3000`
+/// or the path of a Unix domain socket e.g. `unix:/run/lmb.sock`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Bind {
+    /// TCP address.
+    Tcp(SocketAddr),
+    /// Path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for Bind {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+impl FromStr for Bind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> crate::Result<Self> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(Error::InvalidBind(
+                    "path of the Unix domain socket is empty".into(),
+                ));
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        s.parse()
+            .map(Self::Tcp)
+            .map_err(|err| Error::InvalidBind(format!("{s}: {err}")))
+    }
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+/// Listener accepting connections over TCP or a Unix domain socket.
+#[derive(Debug)]
+pub(super) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: tokio::net::UnixListener,
+        /// Socket file created by the listener, which is removed when the listener is dropped.
+        path: Option<PathBuf>,
+    },
+}
+
+impl Listener {
+    /// Bind to the address. The permissions of the Unix domain socket are set to the mode.
+    pub(super) async fn bind(bind: &Bind, mode: Option<u32>) -> anyhow::Result<Self> {
+        match bind {
+            Bind::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            Bind::Unix(path) => {
+                use std::os::unix::fs::{FileTypeExt as _, PermissionsExt as _};
+                // remove the socket left by the previous process
+                if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    fs::remove_file(path)?;
+                }
+                let listener = tokio::net::UnixListener::bind(path)?;
+                if let Some(mode) = mode {
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+                }
+                Ok(Self::Unix {
+                    listener,
+                    path: Some(path.clone()),
+                })
+            }
+            #[cfg(not(unix))]
+            Bind::Unix(_) => {
+                let _mode = mode;
+                bail!("Unix domain sockets are not supported on this platform")
+            }
+        }
+    }
+
+    /// Whether accepted connections have the address of the client, unlike Unix domain sockets.
+    pub(super) fn has_peer_addr(&self) -> bool {
+        matches!(self, Self::Tcp(_))
+    }
+
+    /// Take the listener passed by the supervisor with systemd socket activation,
+    /// read from `LISTEN_FDS` and `LISTEN_PID` environment variables by the caller.
+    pub(super) fn from_fds(mut fds: ListenFd) -> anyhow::Result<Option<Self>> {
+        if fds.len() == 0 {
+            return Ok(None);
+        }
+        if fds.len() > 1 {
+            warn!(count = fds.len(), "only the first passed listener is used");
+        }
+        if let Ok(Some(listener)) = fds.take_tcp_listener(0) {
+            listener.set_nonblocking(true)?;
+            return Ok(Some(Self::Tcp(TcpListener::from_std(listener)?)));
+        }
+        #[cfg(unix)]
+        if let Some(listener) = fds.take_unix_listener(0)? {
+            listener.set_nonblocking(true)?;
+            return Ok(Some(Self::Unix {
+                listener: tokio::net::UnixListener::from_std(listener)?,
+                path: None,
+            }));
+        }
+        bail!("passed listener is neither a TCP nor a Unix stream socket")
+    }
+
+    async fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), Some(addr)))
+            }
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), None))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Self::Unix {
+            path: Some(path), ..
+        } = self
+        {
+            if let Err(err) = fs::remove_file(&*path) {
+                warn!(?err, path = %path.display(), "failed to remove the socket");
+            }
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+/// Serve until the signal resolves, then stop accepting connections and wait for
+/// in-flight requests to finish within the grace period.
+pub(super) async fn serve_until<F>(
+    app: Router,
+    listener: Listener,
+    tls: Option<Arc<ReloadableTls>>,
+    grace_period: Duration,
+    signal: F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(signal);
+    loop {
+        let accepted = tokio::select! {
+            res = listener.accept() => res,
+            () = &mut signal => break,
+        };
+        let (stream, remote_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // e.g. too many open files, wait for connections to be closed
+                warn!(?err, "failed to accept connection");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let app = app.clone();
+        let tls = tls.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            match stream {
+                Stream::Tcp(stream) => serve_stream(stream, remote_addr, app, tls, watcher).await,
+                #[cfg(unix)]
+                Stream::Unix(stream) => serve_stream(stream, remote_addr, app, tls, watcher).await,
+            }
+        });
+    }
+    drop(listener);
+    info!(
+        ?grace_period,
+        "stop accepting connections, wait for in-flight requests"
+    );
+    if tokio::time::timeout(grace_period, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        warn!("grace period elapsed, drop in-flight requests");
+    }
+    Ok(())
+}
+
+async fn serve_stream<S>(
+    stream: S,
+    remote_addr: Option<SocketAddr>,
+    app: Router,
+    tls: Option<Arc<ReloadableTls>>,
+    watcher: Watcher,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let Some(tls) = tls else {
+        serve_io(stream, remote_addr, None, app, watcher).await;
+        return;
+    };
+    // otherwise clients that never finish the handshake hold the connection forever
+    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.acceptor().accept(stream)).await {
+        Ok(Ok(stream)) => {
+            let info = TlsInfo::new(stream.get_ref().1);
+            serve_io(stream, remote_addr, Some(info), app, watcher).await;
+        }
+        Ok(Err(err)) => debug!(?err, ?remote_addr, "failed to complete TLS handshake"),
+        Err(_) => debug!(?remote_addr, "TLS handshake timed out"),
+    }
+}
+
+async fn serve_io<S>(
+    stream: S,
+    remote_addr: Option<SocketAddr>,
+    tls: Option<TlsInfo>,
+    app: Router,
+    watcher: Watcher,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let service = tower::service_fn(move |req: Request<_>| {
+        let mut req = req.map(Body::new);
+        if let Some(addr) = remote_addr {
+            req.extensions_mut().insert(ConnectInfo(addr));
+        }
+        if let Some(tls) = &tls {
+            req.extensions_mut().insert(tls.clone());
+        }
+        app.clone().oneshot(req)
+    });
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder
+        .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service));
+    if let Err(err) = watcher.watch(conn.into_owned()).await {
+        debug!(?err, ?remote_addr, "failed to serve connection");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::TempDir;
+    use listenfd::ListenFd;
+    use std::{
+        env,
+        io::{Read as _, Write as _},
+        net::SocketAddr,
+        path::PathBuf,
+        process::Command,
+        time::Duration,
+    };
+    use test_case::test_case;
+    use tokio::{sync::oneshot, task};
+
+    use super::{serve_until, Bind, Listener};
+    use crate::{
+        serve::{init_route, ScriptRoute, ServeOptions},
+        StoreOptions,
+    };
+
+    #[test_case("127.0.0.1:3000", Bind::Tcp("127.0.0.1:3000".parse().unwrap()))]
+    #[test_case("[::1]:3000", Bind::Tcp("[::1]:3000".parse().unwrap()))]
+    #[test_case("unix:/run/lmb.sock", Bind::Unix(PathBuf::from("/run/lmb.sock")))]
+    #[test_case("unix:lmb.sock", Bind::Unix(PathBuf::from("lmb.sock")))]
+    fn parse_bind(s: &str, expected: Bind) {
+        let bind = s.parse::<Bind>().unwrap();
+        assert_eq!(expected, bind);
+        assert_eq!(s, bind.to_string());
+    }
+
+    #[test_case("unix:")]
+    #[test_case("localhost")]
+    #[test_case("127.0.0.1")]
+    fn parse_invalid_bind(s: &str) {
+        assert!(s.parse::<Bind>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket() {
+        use std::os::unix::{fs::PermissionsExt as _, net::UnixStream};
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("lmb.sock");
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", "return 'hello'").build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let app = init_route(&opts).unwrap();
+        let listener = Listener::bind(&Bind::Unix(path.clone()), Some(0o600))
+            .await
+            .unwrap();
+        let mode = path.metadata().unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        let (stop, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_until(
+            app,
+            listener,
+            None,
+            Duration::from_secs(1),
+            async move {
+                let _ = rx.await;
+            },
+        ));
+
+        let socket = path.clone();
+        let res = task::spawn_blocking(move || {
+            let mut stream = UnixStream::connect(socket).unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut res = String::new();
+            stream.read_to_string(&mut res).unwrap();
+            res
+        })
+        .await
+        .unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK"), "{res}");
+        assert!(res.ends_with("hello"), "{res}");
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn no_socket_activation() {
+        assert!(Listener::from_fds(ListenFd::empty()).unwrap().is_none());
+    }
+
+    /// Run in a process of its own by `socket_activation`, as it changes the environment.
+    #[cfg(unix)]
+    #[tokio::test]
+    #[ignore = "run by socket_activation"]
+    async fn socket_activation_in_process() {
+        use std::os::fd::IntoRawFd as _;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.into_raw_fd();
+        env::set_var("LISTEN_FDS", "1");
+        env::set_var("LISTEN_FDS_FIRST_FD", fd.to_string());
+        let listener = Listener::from_fds(ListenFd::from_env());
+        let Some(Listener::Tcp(listener)) = &listener.unwrap() else {
+            panic!("expect a TCP listener");
+        };
+        assert_eq!(addr, listener.local_addr().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_activation() {
+        let status = Command::new(env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "serve::listener::tests::socket_activation_in_process",
+                "--ignored",
+                "--quiet",
+            ])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
@@ -0,0 +1,842 @@
+use anyhow::bail;
+use axum::{
+    body::Bytes,
+    extract::ConnectInfo,
+    response::{IntoResponse as _, Response},
+};
+use bon::Builder;
+use dashmap::DashMap;
+use http::{header::RETRY_AFTER, request::Parts, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use parking_lot::Mutex;
+use rusqlite::OptionalExtension as _;
+use serde_json::{Map, Value};
+use sha2::{Digest as _, Sha256};
+use std::{
+    io::Cursor,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::task;
+use tracing::{error, warn};
+
+use super::ScriptRoute;
+use crate::{Error, EvaluationPool, State, StateKey, Store};
+
+/// Buckets kept in memory. Beyond the number, the full ones and then the least recently
+/// updated ones are dropped.
+const MAX_BUCKETS: usize = 65_536;
+
+/// Buckets are dropped down to the number, so the sweep runs once in many new keys.
+const SWEPT_BUCKETS: usize = MAX_BUCKETS / 4 * 3;
+
+/// Keys longer than the number of bytes are hashed.
+const MAX_KEY_LENGTH: usize = 256;
+
+/// Full buckets are deleted, as absent buckets are full.
+const SQL_DELETE_FULL_BUCKETS: &str = "DELETE FROM rate_limit WHERE full_at <= ?1";
+
+const SQL_HAS_TABLE: &str =
+    "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'rate_limit')";
+
+const SQL_GET_BUCKET: &str = "SELECT tokens, updated_at, full_at FROM rate_limit WHERE name = ?1";
+
+const SQL_UPSERT_BUCKET: &str = r#"
+    INSERT INTO rate_limit (name, tokens, updated_at, full_at) VALUES (?1, ?2, ?3, ?4)
+    ON CONFLICT(name) DO UPDATE SET tokens = ?2, updated_at = ?3, full_at = ?4
+"#;
+
+/// Number of requests allowed in a period e.g. `100/1m`, which is also the burst size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    limit: u32,
+    period: Duration,
+}
+
+impl Quota {
+    /// Allow the number of requests in the period.
+    pub fn new(limit: u32, period: Duration) -> crate::Result<Self> {
+        if limit == 0 || period.is_zero() {
+            return Err(Error::InvalidQuota(
+                "quota must allow at least one request in a non-empty period".into(),
+            ));
+        }
+        Ok(Self { limit, period })
+    }
+
+    /// Tokens refilled per second.
+    fn rate(&self) -> f64 {
+        f64::from(self.limit) / self.period.as_secs_f64()
+    }
+
+    /// Take a token from the bucket, which is full when absent.
+    fn take(&self, bucket: Option<Bucket>, now: f64) -> (Bucket, Decision) {
+        let limit = f64::from(self.limit);
+        let rate = self.rate();
+        let mut tokens = bucket.map_or(limit, |b| {
+            (b.tokens + (now - b.updated_at).max(0.0) * rate).min(limit)
+        });
+        let allowed = tokens >= 1.0;
+        if allowed {
+            tokens -= 1.0;
+        }
+        let bucket = Bucket {
+            tokens,
+            updated_at: now,
+            full_at: now + (limit - tokens) / rate,
+        };
+        let decision = Decision {
+            limit: self.limit,
+            // truncating is intended, partial tokens do not allow requests
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            remaining: tokens.floor() as u32,
+            reset: seconds(bucket.full_at - now),
+            retry_after: (!allowed).then(|| seconds((1.0 - tokens) / rate)),
+        };
+        (bucket, decision)
+    }
+}
+
+/// Parse e.g. `100/60` for 100 requests in 60 seconds, or `100/1m` or `100/m`
+/// with a unit of `s`, `m`, `h` or `d`.
+impl FromStr for Quota {
+    type Err = Error;
+
+    fn from_str(s: &str) -> crate::Result<Self> {
+        let (limit, period) = s
+            .split_once('/')
+            .ok_or_else(|| Error::InvalidQuota(format!("expect a quota like 100/1m, got {s}")))?;
+        let limit = limit
+            .trim()
+            .parse::<u32>()
+            .map_err(|err| Error::InvalidQuota(format!("invalid limit of {s}: {err}")))?;
+        let period = period.trim();
+        let (count, unit) = match period.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => period.split_at(i),
+            None => (period, "s"),
+        };
+        let count = if count.is_empty() {
+            1
+        } else {
+            count
+                .parse::<u64>()
+                .map_err(|err| Error::InvalidQuota(format!("invalid period of {s}: {err}")))?
+        };
+        let unit = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => {
+                return Err(Error::InvalidQuota(format!(
+                    "unknown unit of period {unit}, expect s, m, h or d"
+                )))
+            }
+        };
+        Self::new(limit, Duration::from_secs(count * unit))
+    }
+}
+
+/// How requests are grouped into buckets.
+#[derive(Clone, Debug, Default)]
+pub enum RateLimitKey {
+    /// The IP address of the client.
+    #[default]
+    Ip,
+    /// The value of the header. Requests without the header share a bucket.
+    Header(HeaderName),
+    /// The value returned by the script, which reads the request from `m.request`.
+    /// Requests are not limited when the script returns `nil`.
+    Script(ScriptRoute),
+}
+
+/// Options of rate limiting. Routes without a quota of their own are limited by the quota.
+#[derive(Builder, Clone, Debug)]
+pub struct RateLimitOptions {
+    /// Quota of routes without the `rate_limit` front matter. They are not limited when omitted.
+    quota: Option<Quota>,
+    #[builder(default)]
+    key: RateLimitKey,
+    /// Keep buckets in the database file of the store, so limits survive restarts and
+    /// are shared between processes using the same file. Buckets are kept apart from
+    /// values of the store, and deleted once they are full again.
+    #[builder(default)]
+    persist: bool,
+}
+
+impl Default for RateLimitOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl RateLimitOptions {
+    pub(super) fn quota(&self) -> Option<Quota> {
+        self.quota
+    }
+
+    pub(super) fn key(&self) -> &RateLimitKey {
+        &self.key
+    }
+
+    pub(super) fn persist(&self) -> bool {
+        self.persist
+    }
+
+    /// Read the key script again when reloading.
+    pub(super) fn reread(&self) -> anyhow::Result<Self> {
+        let mut options = self.clone();
+        if let RateLimitKey::Script(route) = &self.key {
+            options.key = RateLimitKey::Script(route.reread()?);
+        }
+        Ok(options)
+    }
+
+    /// Whether requests of any of the routes are limited by the IP address of the client.
+    pub(super) fn limits_by_ip(&self, routes: &[ScriptRoute]) -> bool {
+        matches!(self.key, RateLimitKey::Ip)
+            && (self.quota.is_some() || routes.iter().any(|r| r.rate_limit.is_some()))
+    }
+
+    pub(super) fn script(&self) -> Option<&ScriptRoute> {
+        match &self.key {
+            RateLimitKey::Script(route) => Some(route),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: f64,
+    full_at: f64,
+}
+
+/// Buckets kept in memory across reloads of routes.
+#[derive(Clone, Default)]
+pub(super) struct Buckets {
+    map: Arc<DashMap<String, Bucket>>,
+    sweeping: Arc<Mutex<()>>,
+}
+
+impl Buckets {
+    /// Drop the full buckets, then the least recently updated ones until
+    /// [`SWEPT_BUCKETS`] are left. Concurrent sweeps are skipped.
+    fn sweep(&self, now: f64) {
+        let Some(_sweeping) = self.sweeping.try_lock() else {
+            return;
+        };
+        let len = self.map.len();
+        self.map.retain(|_, b| b.full_at > now);
+        let excess = self.map.len().saturating_sub(SWEPT_BUCKETS);
+        if excess > 0 {
+            let mut updated_at = self.map.iter().map(|b| b.updated_at).collect::<Vec<_>>();
+            let (_, oldest, _) = updated_at.select_nth_unstable_by(excess - 1, f64::total_cmp);
+            let oldest = *oldest;
+            self.map.retain(|_, b| b.updated_at > oldest);
+        }
+        warn!(
+            before = len,
+            after = self.map.len(),
+            "too many buckets, drop the full and least recently updated ones"
+        );
+    }
+}
+
+/// Key of requests, computed by [`RateLimitKey`].
+#[derive(Clone)]
+pub(super) enum KeyOf {
+    Ip,
+    Header(HeaderName),
+    Script(Arc<EvaluationPool<Cursor<Bytes>>>),
+}
+
+/// Outcome of taking a token, sent in `RateLimit-*` headers.
+#[derive(Debug, PartialEq)]
+pub(super) struct Decision {
+    limit: u32,
+    remaining: u32,
+    reset: u64,
+    retry_after: Option<u64>,
+}
+
+impl Decision {
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let mut insert = |name: &'static str, value: u64| {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        };
+        insert("ratelimit-limit", self.limit.into());
+        insert("ratelimit-remaining", self.remaining.into());
+        insert("ratelimit-reset", self.reset);
+        if let Some(retry_after) = self.retry_after {
+            headers.insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        headers
+    }
+
+    /// Add the headers to the response of an allowed request.
+    pub(super) fn apply(&self, res: &mut Response) {
+        res.headers_mut().extend(self.headers());
+    }
+
+    /// Response to a request over the limit.
+    pub(super) fn reject(&self) -> Option<Response> {
+        self.retry_after?;
+        Some((StatusCode::TOO_MANY_REQUESTS, self.headers()).into_response())
+    }
+}
+
+/// Token buckets of a route.
+#[derive(Clone)]
+pub(super) struct RateLimiter {
+    pub(super) buckets: Buckets,
+    pub(super) key: KeyOf,
+    pub(super) quota: Quota,
+    pub(super) route: String,
+    /// Store where buckets are persisted instead of memory.
+    pub(super) store: Option<Store>,
+}
+
+impl RateLimiter {
+    /// Take a token from the bucket of the request. `None` is returned when the request
+    /// is not limited. The request is built for the key script only.
+    pub(super) async fn check(
+        &self,
+        parts: &Parts,
+        request: impl FnOnce() -> Map<String, Value>,
+    ) -> Result<Option<Decision>, Response> {
+        let key = match &self.key {
+            KeyOf::Ip => parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string())
+                .unwrap_or_default(),
+            KeyOf::Header(name) => parts
+                .headers
+                .get(name)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                .unwrap_or_default(),
+            KeyOf::Script(pool) => {
+                let pool = pool.clone();
+                let request = request();
+                match task::spawn_blocking(move || script_key(&pool, request)).await {
+                    Ok(Ok(Some(key))) => key,
+                    Ok(Ok(None)) => return Ok(None),
+                    Ok(Err(err)) => {
+                        error!(?err, "failed to compute the key of rate limiting");
+                        return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+                    }
+                    Err(err) => {
+                        error!(?err, "failed to compute the key of rate limiting");
+                        return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+                    }
+                }
+            }
+        };
+        // keys may come from clients, which must not grow buckets without bound
+        let key = if key.len() > MAX_KEY_LENGTH {
+            hex::encode(Sha256::digest(key.as_bytes()))
+        } else {
+            key
+        };
+        let name = format!("{}:{key}", self.route);
+        let now = unix_now();
+        let Some(store) = &self.store else {
+            return Ok(Some(self.take_in_memory(name, now)));
+        };
+        let store = store.clone();
+        let quota = self.quota;
+        let taken = task::spawn_blocking(move || take_in_store(&store, &name, quota, now)).await;
+        match taken {
+            Ok(Ok(decision)) => Ok(Some(decision)),
+            // requests are let through rather than failed when the store is unavailable
+            Ok(Err(err)) => {
+                error!(
+                    ?err,
+                    "failed to take a token from the store, let the request through"
+                );
+                Ok(None)
+            }
+            Err(err) => {
+                error!(
+                    ?err,
+                    "failed to take a token from the store, let the request through"
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    fn take_in_memory(&self, name: String, now: f64) -> Decision {
+        let buckets = &self.buckets.map;
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&name) {
+            self.buckets.sweep(now);
+        }
+        let mut entry = buckets.entry(name).or_insert(Bucket {
+            tokens: f64::from(self.quota.limit),
+            updated_at: now,
+            full_at: now,
+        });
+        let (bucket, decision) = self.quota.take(Some(*entry), now);
+        *entry = bucket;
+        decision
+    }
+}
+
+/// Fail on startup when the table of buckets is absent, instead of failing every request.
+pub(super) fn check_store(store: &Store) -> anyhow::Result<()> {
+    let exists = store
+        .transaction(|tx| Ok(tx.query_row(SQL_HAS_TABLE, (), |row| row.get::<_, bool>(0))?))?;
+    if !exists {
+        bail!("rate limits cannot be persisted before the store is migrated, migrate it first e.g. with --run-migrations");
+    }
+    Ok(())
+}
+
+/// Take a token in a transaction, so processes sharing the database file share the bucket.
+fn take_in_store(store: &Store, name: &str, quota: Quota, now: f64) -> crate::Result<Decision> {
+    store.transaction(|tx| {
+        tx.prepare_cached(SQL_DELETE_FULL_BUCKETS)?
+            .execute((now,))?;
+        let bucket = tx
+            .prepare_cached(SQL_GET_BUCKET)?
+            .query_row((name,), |row| {
+                Ok(Bucket {
+                    tokens: row.get(0)?,
+                    updated_at: row.get(1)?,
+                    full_at: row.get(2)?,
+                })
+            })
+            .optional()?;
+        let (bucket, decision) = quota.take(bucket, now);
+        tx.prepare_cached(SQL_UPSERT_BUCKET)?.execute((
+            name,
+            bucket.tokens,
+            bucket.updated_at,
+            bucket.full_at,
+        ))?;
+        Ok(decision)
+    })
+}
+
+fn script_key(
+    pool: &Arc<EvaluationPool<Cursor<Bytes>>>,
+    request: Map<String, Value>,
+) -> anyhow::Result<Option<String>> {
+    let e = pool.get(Cursor::new(Bytes::new()))?;
+    let state = Arc::new(State::new());
+    state.insert(StateKey::Request, request.into());
+    let solution = e.evaluate().state(state).call()?;
+    Ok(match solution.payload {
+        Value::Null => None,
+        Value::String(s) => Some(s),
+        v => Some(v.to_string()),
+    })
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Round up to whole seconds for headers.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn seconds(secs: f64) -> u64 {
+    secs.max(0.0).ceil() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, NamedTempFile, TempDir};
+    use axum_test::TestServer;
+    use http::{header::RETRY_AFTER, HeaderName, StatusCode};
+    use serde_json::Map;
+    use sha2::{Digest as _, Sha256};
+    use std::{future, net::SocketAddr, time::Duration};
+    use test_case::test_case;
+
+    use super::{
+        take_in_store, Buckets, Decision, KeyOf, Quota, RateLimitKey, RateLimitOptions,
+        RateLimiter, MAX_BUCKETS, MAX_KEY_LENGTH, SWEPT_BUCKETS,
+    };
+    use crate::{
+        serve::{init_route, serve_file, Bind, ScriptRoute, ServeOptions},
+        Error, Store, StoreOptions,
+    };
+
+    #[test_case("10/60", 10, 60)]
+    #[test_case("10/1m", 10, 60)]
+    #[test_case("10/m", 10, 60)]
+    #[test_case("1/2h", 1, 7200)]
+    #[test_case(" 5 / d ", 5, 86400)]
+    fn parse_quota(s: &str, limit: u32, secs: u64) {
+        let quota = s.parse::<Quota>().unwrap();
+        assert_eq!(Quota::new(limit, Duration::from_secs(secs)).unwrap(), quota);
+    }
+
+    #[test_case("10")]
+    #[test_case("0/1m")]
+    #[test_case("10/0s")]
+    #[test_case("10/1w")]
+    #[test_case("a/1m")]
+    fn parse_invalid_quota(s: &str) {
+        assert!(matches!(s.parse::<Quota>(), Err(Error::InvalidQuota(_))));
+    }
+
+    #[test]
+    fn take() {
+        let quota = "2/10s".parse::<Quota>().unwrap();
+        let (bucket, decision) = quota.take(None, 100.0);
+        let expected = Decision {
+            limit: 2,
+            remaining: 1,
+            reset: 5,
+            retry_after: None,
+        };
+        assert_eq!(expected, decision);
+        let (bucket, decision) = quota.take(Some(bucket), 100.0);
+        assert_eq!(0, decision.remaining);
+        assert!(decision.retry_after.is_none());
+        let (bucket, decision) = quota.take(Some(bucket), 101.0);
+        let expected = Decision {
+            limit: 2,
+            remaining: 0,
+            reset: 9,
+            retry_after: Some(4),
+        };
+        assert_eq!(expected, decision);
+        let (_, decision) = quota.take(Some(bucket), 105.0);
+        assert_eq!(0, decision.remaining);
+        assert!(decision.retry_after.is_none());
+    }
+
+    #[test]
+    fn quota_from_front_matter() {
+        let dir = TempDir::new().unwrap();
+        let script = "--[[\n--rate_limit = \"5/1m\"\n--]]\nreturn 1";
+        dir.child("index.lua").write_str(script).unwrap();
+        let routes = ScriptRoute::scan_dir(dir.path()).unwrap();
+        let expected = Quota::new(5, Duration::from_secs(60)).unwrap();
+        assert_eq!(Some(expected), routes[0].rate_limit);
+
+        let script = "--[[\n--rate_limit = \"5/1w\"\n--]]\nreturn 1";
+        dir.child("index.lua").write_str(script).unwrap();
+        assert!(ScriptRoute::scan_dir(dir.path()).is_err());
+    }
+
+    fn server(
+        routes: Vec<ScriptRoute>,
+        rate_limit: RateLimitOptions,
+        store_options: StoreOptions,
+    ) -> TestServer {
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), routes)
+            .json(true)
+            .rate_limit(rate_limit)
+            .store_options(store_options)
+            .build();
+        let router = init_route(&opts).unwrap();
+        TestServer::new(router.into_make_service()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn limit_by_header() {
+        let rate_limit = RateLimitOptions::builder()
+            .quota("2/1m".parse().unwrap())
+            .key(RateLimitKey::Header(HeaderName::from_static("x-api-key")))
+            .build();
+        let routes = vec![ScriptRoute::builder("limited.lua", "return 1").build()];
+        let server = server(routes, rate_limit, StoreOptions::builder().build());
+
+        let res = server.get("/").add_header("x-api-key", "a").await;
+        res.assert_status_ok();
+        res.assert_header("ratelimit-limit", "2");
+        res.assert_header("ratelimit-remaining", "1");
+        res.assert_header("ratelimit-reset", "30");
+        server
+            .get("/")
+            .add_header("x-api-key", "a")
+            .await
+            .assert_status_ok();
+        let res = server.get("/").add_header("x-api-key", "a").await;
+        res.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        res.assert_header("ratelimit-remaining", "0");
+        res.assert_header(RETRY_AFTER, "30");
+
+        server
+            .get("/")
+            .add_header("x-api-key", "b")
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn limit_per_route() {
+        let routes = vec![
+            ScriptRoute::builder("strict.lua", "return 1")
+                .path("/strict")
+                .rate_limit("1/1h".parse().unwrap())
+                .build(),
+            ScriptRoute::builder("default.lua", "return 1")
+                .path("/default")
+                .build(),
+        ];
+        let rate_limit = RateLimitOptions::builder()
+            .quota("3/1h".parse().unwrap())
+            .build();
+        let server = server(routes, rate_limit, StoreOptions::builder().build());
+
+        server.get("/strict").await.assert_status_ok();
+        let res = server.get("/strict").await;
+        res.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        res.assert_header("ratelimit-limit", "1");
+
+        for _ in 0..3 {
+            server.get("/default").await.assert_status_ok();
+        }
+        server
+            .get("/default")
+            .await
+            .assert_status(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn unlimited_route() {
+        let routes = vec![ScriptRoute::builder("free.lua", "return 1").build()];
+        let server = server(
+            routes,
+            RateLimitOptions::default(),
+            StoreOptions::builder().build(),
+        );
+        for _ in 0..3 {
+            let res = server.get("/").await;
+            res.assert_status_ok();
+            assert!(res.maybe_header("ratelimit-limit").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn limit_by_script() {
+        let key_script = r#"
+        local m = require('@lmb')
+        return m.request.query.user and m.request.query.user[1]
+        "#;
+        let rate_limit = RateLimitOptions::builder()
+            .quota("1/1m".parse().unwrap())
+            .key(RateLimitKey::Script(
+                ScriptRoute::builder("key.lua", key_script).build(),
+            ))
+            .build();
+        let routes = vec![ScriptRoute::builder("limited.lua", "return 1").build()];
+        let server = server(routes, rate_limit, StoreOptions::builder().build());
+
+        server.get("/?user=a").await.assert_status_ok();
+        server
+            .get("/?user=a")
+            .await
+            .assert_status(StatusCode::TOO_MANY_REQUESTS);
+        server.get("/?user=b").await.assert_status_ok();
+        // requests without a key are not limited
+        for _ in 0..2 {
+            let res = server.get("/").await;
+            res.assert_status_ok();
+            assert!(res.maybe_header("ratelimit-limit").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn key_script_takes_evaluation_slot() {
+        let rate_limit = RateLimitOptions::builder()
+            .quota("1/1m".parse().unwrap())
+            .key(RateLimitKey::Script(
+                ScriptRoute::builder("key.lua", "error('unreachable')").build(),
+            ))
+            .build();
+        let routes = vec![ScriptRoute::builder("limited.lua", "return 1").build()];
+        let opts = ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), routes)
+            .max_concurrency(0)
+            .rate_limit(rate_limit)
+            .store_options(StoreOptions::builder().build())
+            .build();
+        let router = init_route(&opts).unwrap();
+        let server = TestServer::new(router.into_make_service()).unwrap();
+        let res = server.get("/").await;
+        res.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        res.assert_header(RETRY_AFTER, "1");
+    }
+
+    fn buckets(store: &Store) -> Vec<(String, f64)> {
+        store
+            .transaction(|tx| {
+                let mut stmt = tx.prepare("SELECT name, tokens FROM rate_limit ORDER BY name")?;
+                let rows = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+                Ok(rows.collect::<Result<Vec<_>, _>>()?)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn evict_buckets() {
+        let limiter = RateLimiter {
+            buckets: Buckets::default(),
+            key: KeyOf::Ip,
+            quota: "1/1d".parse().unwrap(),
+            route: "/".to_string(),
+            store: None,
+        };
+        for i in 0..MAX_BUCKETS {
+            #[allow(clippy::cast_precision_loss)]
+            limiter.take_in_memory(i.to_string(), i as f64);
+        }
+        assert_eq!(MAX_BUCKETS, limiter.buckets.map.len());
+
+        // none is full, so the least recently updated ones are dropped
+        #[allow(clippy::cast_precision_loss)]
+        limiter.take_in_memory("new".to_string(), MAX_BUCKETS as f64);
+        let buckets = &limiter.buckets.map;
+        assert_eq!(SWEPT_BUCKETS + 1, buckets.len());
+        assert!(!buckets.contains_key("0"));
+        assert!(buckets.contains_key(&(MAX_BUCKETS - 1).to_string()));
+        assert!(buckets.contains_key("new"));
+    }
+
+    #[tokio::test]
+    async fn hash_long_keys() {
+        let limiter = RateLimiter {
+            buckets: Buckets::default(),
+            key: KeyOf::Header(HeaderName::from_static("x-api-key")),
+            quota: "1/1d".parse().unwrap(),
+            route: "/".to_string(),
+            store: None,
+        };
+        let key = "k".repeat(MAX_KEY_LENGTH + 1);
+        let (parts, ()) = http::Request::builder()
+            .header("x-api-key", &key)
+            .body(())
+            .unwrap()
+            .into_parts();
+        let decision = limiter.check(&parts, Map::new).await.unwrap().unwrap();
+        assert!(decision.retry_after.is_none());
+        let decision = limiter.check(&parts, Map::new).await.unwrap().unwrap();
+        assert!(decision.retry_after.is_some());
+        let name = limiter.buckets.map.iter().next().unwrap().key().clone();
+        assert_eq!(format!("/:{}", hex::encode(Sha256::digest(&key))), name);
+    }
+
+    #[test]
+    fn delete_full_buckets() {
+        let store = Store::default();
+        let quota = "1/10s".parse::<Quota>().unwrap();
+        let decision = take_in_store(&store, "a", quota, 100.0).unwrap();
+        assert!(decision.retry_after.is_none());
+        take_in_store(&store, "b", quota, 105.0).unwrap();
+        let decision = take_in_store(&store, "b", quota, 106.0).unwrap();
+        assert!(decision.retry_after.is_some());
+        assert_eq!(
+            vec![("a".to_string(), 0.0), ("b".to_string(), 0.1)],
+            buckets(&store)
+        );
+
+        // the bucket of a is full again, and deleted by the next take
+        take_in_store(&store, "c", quota, 110.0).unwrap();
+        assert_eq!(
+            vec![("b".to_string(), 0.1), ("c".to_string(), 0.0)],
+            buckets(&store)
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn limit_by_ip_on_unix_socket() {
+        let dir = TempDir::new().unwrap();
+        let bind = Bind::Unix(dir.child("lmb.sock").to_path_buf());
+        let routes = vec![ScriptRoute::builder("limited.lua", "return 1").build()];
+        let serve = |rate_limit: RateLimitOptions| {
+            let opts = ServeOptions::builder(bind.clone(), routes.clone())
+                .rate_limit(rate_limit)
+                .store_options(StoreOptions::builder().build())
+                .build();
+            async move { serve_file(&opts, future::ready(())).await }
+        };
+
+        let rate_limit = RateLimitOptions::builder()
+            .quota("1/1m".parse().unwrap())
+            .build();
+        let err = serve(rate_limit).await.unwrap_err();
+        assert!(err.to_string().contains("Unix domain socket"), "{err}");
+
+        let rate_limit = RateLimitOptions::builder()
+            .quota("1/1m".parse().unwrap())
+            .key(RateLimitKey::Header(HeaderName::from_static(
+                "x-forwarded-for",
+            )))
+            .build();
+        serve(rate_limit).await.unwrap();
+        serve(RateLimitOptions::default()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn persist() {
+        let store_file = NamedTempFile::new("db.sqlite3").unwrap();
+        let store_options = StoreOptions::builder()
+            .store_path(store_file.to_path_buf())
+            .run_migrations(true)
+            .build();
+        let rate_limit = RateLimitOptions::builder()
+            .quota("1/1h".parse().unwrap())
+            .key(RateLimitKey::Header(HeaderName::from_static("x-api-key")))
+            .persist(true)
+            .build();
+        let routes = vec![ScriptRoute::builder("limited.lua", "return 1").build()];
+
+        let server = server(routes.clone(), rate_limit.clone(), store_options.clone());
+        server
+            .get("/")
+            .add_header("x-api-key", "a")
+            .await
+            .assert_status_ok();
+        // buckets are out of reach of scripts
+        let store = Store::new(store_file.path()).unwrap();
+        assert!(store.list().unwrap().is_empty());
+        assert_eq!(vec![("limited.lua:a".to_string(), 0.0)], buckets(&store));
+
+        // another server with the same database file shares the bucket
+        let server = self::server(routes, rate_limit, store_options);
+        server
+            .get("/")
+            .add_header("x-api-key", "a")
+            .await
+            .assert_status(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn persist_without_migrated_store() {
+        let rate_limit = RateLimitOptions::builder()
+            .quota("1/1h".parse().unwrap())
+            .persist(true)
+            .build();
+        let routes = vec![ScriptRoute::builder("limited.lua", "return 1").build()];
+        let init = |store_options: StoreOptions| {
+            let opts =
+                ServeOptions::builder("0.0.0.0:0".parse::<SocketAddr>().unwrap(), routes.clone())
+                    .rate_limit(rate_limit.clone())
+                    .store_options(store_options)
+                    .build();
+            init_route(&opts).map(|_| ()).unwrap_err().to_string()
+        };
+
+        let err = init(StoreOptions::builder().build());
+        assert!(err.contains("store path"), "{err}");
+
+        let store_file = NamedTempFile::new("db.sqlite3").unwrap();
+        let store_options = StoreOptions::builder()
+            .store_path(store_file.to_path_buf())
+            .build();
+        let err = init(store_options);
+        assert!(err.contains("migrated"), "{err}");
+    }
+}
@@ -0,0 +1,333 @@
+use anyhow::{anyhow, bail};
+use arc_swap::ArcSwap;
+use bon::Builder;
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig, ServerConnection,
+};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::*;
+
+/// TLS options of the server.
+#[derive(Builder, Clone, Debug)]
+pub struct TlsOptions {
+    /// PEM file of the certificate chain.
+    #[builder(start_fn, into)]
+    cert: PathBuf,
+    /// PEM file of the private key.
+    #[builder(start_fn, into)]
+    key: PathBuf,
+    /// PEM file of CA certificates. Clients are required to present certificates
+    /// issued by them when specified.
+    #[builder(into)]
+    client_ca: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    fn server_config(&self) -> anyhow::Result<ServerConfig> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = if let Some(path) = &self.client_ca {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots.add(cert)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        } else {
+            builder.with_no_client_auth()
+        };
+        let mut config = builder.with_single_cert(read_certs(&self.cert)?, read_key(&self.key)?)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        bail!("no certificate is found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| anyhow!("no private key is found in {}", path.display()))
+}
+
+/// TLS configuration which can be read again from the files while serving.
+#[derive(Debug)]
+pub(super) struct ReloadableTls {
+    config: ArcSwap<ServerConfig>,
+    options: TlsOptions,
+}
+
+impl ReloadableTls {
+    pub(super) fn new(options: &TlsOptions) -> anyhow::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            config: ArcSwap::from_pointee(options.server_config()?),
+            options: options.clone(),
+        }))
+    }
+
+    /// Read the certificates again. Established connections are not affected,
+    /// and the current configuration is kept when the files are invalid.
+    pub(super) fn reload(&self) -> anyhow::Result<()> {
+        self.config.store(Arc::new(self.options.server_config()?));
+        Ok(())
+    }
+
+    pub(super) fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.load_full())
+    }
+}
+
+/// TLS information of the connection, available in `m.request.tls`.
+#[derive(Clone, Debug)]
+pub(super) struct TlsInfo {
+    /// Subject of the verified client certificate.
+    pub(super) peer: Option<String>,
+}
+
+impl TlsInfo {
+    pub(super) fn new(conn: &ServerConnection) -> Self {
+        let peer = conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| X509Certificate::from_der(cert).ok())
+            .map(|(_, cert)| cert.subject().to_string());
+        Self { peer }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair, KeyUsagePurpose,
+    };
+    use rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject as _, CertificateDer, PrivateKeyDer},
+        ClientConfig, RootCertStore,
+    };
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+    use tokio::{
+        io::AsyncReadExt as _,
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+        task, time,
+    };
+
+    use super::{ReloadableTls, TlsOptions};
+    use crate::{
+        serve::{
+            init_route,
+            listener::{serve_until, Listener, TLS_HANDSHAKE_TIMEOUT},
+            ScriptRoute, ServeOptions,
+        },
+        StoreOptions,
+    };
+
+    const SCRIPT: &str = r#"
+    local tls = require('@lmb').request.tls
+    return tostring(tls ~= nil) .. ' ' .. tostring(tls.peer)
+    "#;
+
+    struct Authority {
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    impl Authority {
+        fn new() -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.key_usages = vec![KeyUsagePurpose::KeyCertSign];
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "lmb test CA");
+            let cert = params.self_signed(&key).unwrap();
+            Self { cert, key }
+        }
+
+        /// Issue a certificate, returning the certificate and the key in PEM.
+        fn issue(&self, name: &str) -> (String, String) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            (cert.pem(), key.serialize_pem())
+        }
+
+        fn client_config(&self, client: Option<&(String, String)>) -> Arc<ClientConfig> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.cert.der().clone()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+            let config = match client {
+                Some((cert, key)) => {
+                    let cert = CertificateDer::from_pem_slice(cert.as_bytes()).unwrap();
+                    let key = PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap();
+                    builder.with_client_auth_cert(vec![cert], key).unwrap()
+                }
+                None => builder.with_no_client_auth(),
+            };
+            Arc::new(config)
+        }
+    }
+
+    struct Server {
+        addr: SocketAddr,
+        stop: oneshot::Sender<()>,
+        tls: Arc<ReloadableTls>,
+    }
+
+    async fn start(tls_options: &TlsOptions) -> Server {
+        let store_options = StoreOptions::builder().build();
+        let opts = ServeOptions::builder(
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
+            vec![ScriptRoute::builder("", SCRIPT).build()],
+        )
+        .json(false)
+        .store_options(store_options)
+        .build();
+        let app = init_route(&opts).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tls = ReloadableTls::new(tls_options).unwrap();
+        let (stop, rx) = oneshot::channel::<()>();
+        tokio::spawn(serve_until(
+            app,
+            Listener::Tcp(listener),
+            Some(tls.clone()),
+            Duration::from_secs(1),
+            async move {
+                let _ = rx.await;
+            },
+        ));
+        Server { addr, stop, tls }
+    }
+
+    async fn get(addr: SocketAddr, config: Arc<ClientConfig>) -> Option<String> {
+        task::spawn_blocking(move || {
+            let agent = ureq::AgentBuilder::new().tls_config(config).build();
+            let res = agent
+                .get(&format!("https://localhost:{}/", addr.port()))
+                .call()
+                .ok()?;
+            res.into_string().ok()
+        })
+        .await
+        .unwrap()
+    }
+
+    fn write_pem(dir: &TempDir, name: &str, (cert, key): &(String, String)) -> TlsOptions {
+        let cert_file = dir.child(format!("{name}.crt"));
+        cert_file.write_str(cert).unwrap();
+        let key_file = dir.child(format!("{name}.key"));
+        key_file.write_str(key).unwrap();
+        TlsOptions::builder(cert_file.path(), key_file.path()).build()
+    }
+
+    #[tokio::test]
+    async fn serve_https() {
+        let dir = TempDir::new().unwrap();
+        let ca = Authority::new();
+        let tls_options = write_pem(&dir, "server", &ca.issue("localhost"));
+        let server = start(&tls_options).await;
+
+        let body = get(server.addr, ca.client_config(None)).await;
+        assert_eq!(Some("true nil".to_string()), body);
+
+        let other = Authority::new();
+        assert_eq!(None, get(server.addr, other.client_config(None)).await);
+        server.stop.send(()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_certificate() {
+        let dir = TempDir::new().unwrap();
+        let ca = Authority::new();
+        let ca_file = dir.child("ca.crt");
+        ca_file.write_str(&ca.cert.pem()).unwrap();
+        let (cert, key) = ca.issue("localhost");
+        dir.child("server.crt").write_str(&cert).unwrap();
+        dir.child("server.key").write_str(&key).unwrap();
+        let tls_options = TlsOptions::builder(
+            dir.child("server.crt").path(),
+            dir.child("server.key").path(),
+        )
+        .client_ca(ca_file.path())
+        .build();
+        let server = start(&tls_options).await;
+
+        let client = ca.issue("client");
+        let body = get(server.addr, ca.client_config(Some(&client))).await;
+        assert_eq!(Some("true CN=client".to_string()), body);
+
+        assert_eq!(None, get(server.addr, ca.client_config(None)).await);
+
+        let other = Authority::new();
+        let client = other.issue("client");
+        assert_eq!(
+            None,
+            get(server.addr, ca.client_config(Some(&client))).await
+        );
+        server.stop.send(()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let dir = TempDir::new().unwrap();
+        let ca = Authority::new();
+        let tls_options = write_pem(&dir, "server", &ca.issue("localhost"));
+        let server = start(&tls_options).await;
+
+        // the client connects without starting the handshake
+        let mut stream = TcpStream::connect(server.addr).await.unwrap();
+        let mut buf = [0; 1];
+        let read = time::timeout(TLS_HANDSHAKE_TIMEOUT * 2, stream.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0) | Err(_))), "{read:?}");
+        server.stop.send(()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_certificate() {
+        let dir = TempDir::new().unwrap();
+        let ca = Authority::new();
+        let tls_options = write_pem(&dir, "server", &ca.issue("localhost"));
+        let server = start(&tls_options).await;
+
+        let other = Authority::new();
+        assert_eq!(None, get(server.addr, other.client_config(None)).await);
+
+        dir.child("server.crt").write_str("invalid").unwrap();
+        assert!(server.tls.reload().is_err());
+        let body = get(server.addr, ca.client_config(None)).await;
+        assert_eq!(Some("true nil".to_string()), body);
+
+        write_pem(&dir, "server", &other.issue("localhost"));
+        server.tls.reload().unwrap();
+        let body = get(server.addr, other.client_config(None)).await;
+        assert_eq!(Some("true nil".to_string()), body);
+        assert_eq!(None, get(server.addr, ca.client_config(None)).await);
+        server.stop.send(()).unwrap();
+    }
+}
@@ -57,6 +57,28 @@ impl Store {
         })
     }
 
+    /// Write changes in the write-ahead log back to the database and truncate the log.
+    /// This should be called before the process exits.
+    ///
+    /// ```rust
+    /// # use assert_fs::NamedTempFile;
+    /// use lmb::*;
+    ///
+    /// # fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    /// let store_file = NamedTempFile::new("db.sqlite3")?;
+    /// let store = Store::new(store_file.path())?;
+    /// store.migrate(None)?;
+    /// store.checkpoint()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn checkpoint(&self) -> Result<()> {
+        let conn = self.conn.lock();
+        let busy: i64 = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |row| row.get(0))?;
+        debug!(busy, "checkpoint store");
+        Ok(())
+    }
+
     /// Perform migration on the database. Migrations should be idempotent. If version is omitted,
     /// database will be migrated to the latest. If version is 0, all migrations will be reverted.
     ///
@@ -90,6 +112,19 @@ impl Store {
         Ok(version)
     }
 
+    /// Run the closure in a transaction, for tables other than the store,
+    /// e.g. buckets of rate limiting. The transaction is rolled back when it fails.
+    pub(crate) fn transaction<T>(
+        &self,
+        f: impl FnOnce(&rusqlite::Transaction<'_>) -> Result<T>,
+    ) -> Result<T> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        let value = f(&tx)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
     /// Delete value by name.
     ///
     /// ```rust
@@ -297,7 +332,7 @@ impl Store {
 
         let _s = trace_span!("store_update", ?names).entered();
 
-        let default_vs = default_values.unwrap_or_else(|| Vec::new());
+        let default_vs = default_values.unwrap_or_default();
         let filled_default_values: Vec<&Value> = default_vs
             .iter()
             .chain(std::iter::repeat(&Value::Null))
@@ -327,8 +362,8 @@ impl Store {
         f(&mut values)?;
 
         for (name, value) in std::iter::zip(&names, &values) {
-            let size = Self::get_size(&value);
-            let type_hint = Self::type_hint(&value);
+            let size = Self::get_size(value);
+            let type_hint = Self::type_hint(value);
 
             let value = rmp_serde::to_vec(&value)?;
             let mut cached_stmt = tx.prepare_cached(SQL_UPSERT_STORE)?;
@@ -482,6 +517,22 @@ mod tests {
         store.migrate(Some(0)).unwrap();
     }
 
+    #[test]
+    fn checkpoint() {
+        let store_file = NamedTempFile::new("db.sqlite3").unwrap();
+        let store = Store::new(store_file.path()).unwrap();
+        store.migrate(None).unwrap();
+        store.put("a", &json!(1)).unwrap();
+        let wal = store_file.path().with_extension("sqlite3-wal");
+        assert!(wal.metadata().unwrap().len() > 0);
+        store.checkpoint().unwrap();
+        assert_eq!(0, wal.metadata().unwrap().len());
+        assert_eq!(
+            json!(1),
+            Store::new(store_file.path()).unwrap().get("a").unwrap()
+        );
+    }
+
     #[test]
     fn new_store() {
         let store_file = NamedTempFile::new("db.sqlite3").unwrap();
@@ -1,4 +1,4 @@
-use assert_fs::NamedTempFile;
+use assert_fs::{prelude::*, NamedTempFile, TempDir};
 use snapbox::{
     cmd::{cargo_bin, Command},
     str,
@@ -46,7 +46,7 @@ fn eval_file() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 nullhello, world!
 
 "#]]);
@@ -66,7 +66,7 @@ fn eval_json_output() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 {"bool":true,"num":1.23,"str":"hello"}
 "#]]);
 }
@@ -79,7 +79,7 @@ fn eval_stdin() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 2
 "#]]);
 }
@@ -100,6 +100,140 @@ Error: attempt to perform arithmetic (add) on nil and number
 "#]]);
 }
 
+#[test]
+fn eval_memory_limit() {
+    Command::new(cargo_bin("lmb"))
+        .stdin("local t = {} for i = 1, 1e7 do t[i] = { i } end return #t")
+        .args([
+            "--no-color",
+            "eval",
+            "--file",
+            "-",
+            "--memory-limit",
+            "1048576",
+        ])
+        .assert()
+        .failure()
+        .stderr_eq(str![[r#"
+memory limit of 1048576 bytes exceeded
+
+"#]]);
+}
+
+#[test]
+fn eval_args_and_env() {
+    let script = r#"
+local m = require('@lmb')
+return table.concat({ m.args[1], m.args[2], m.args.key, m.env.LMB_TEST_NAME, tostring(m.env.HOME) }, ' ')
+"#;
+    Command::new(cargo_bin("lmb"))
+        .stdin(script)
+        .env("LMB_TEST_NAME", "lmb")
+        .args([
+            "--no-color",
+            "eval",
+            "--file",
+            "-",
+            "--arg",
+            "key=value",
+            "--env-allow",
+            "LMB_TEST_*",
+            "--",
+            "--foo",
+            "bar",
+        ])
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+[..]  INFO rusqlite_migration: Database migrated to version 2    
+--foo bar value lmb nil
+"#]]);
+}
+
+#[test]
+fn eval_cache() {
+    let dir = TempDir::new().unwrap();
+    let cache_dir = dir.path().to_string_lossy();
+    for _ in 0..2 {
+        Command::new(cargo_bin("lmb"))
+            .stdin("return 1+1")
+            .args([
+                "--no-color",
+                "--cache-dir",
+                &cache_dir,
+                "eval",
+                "--file",
+                "-",
+            ])
+            .assert()
+            .success()
+            .stdout_eq(str![[r#"
+[..]  INFO rusqlite_migration: Database migrated to version 2    
+2
+"#]]);
+    }
+    Command::new(cargo_bin("lmb"))
+        .args([
+            "--no-color",
+            "--cache-dir",
+            &cache_dir,
+            "--json",
+            "cache",
+            "stats",
+        ])
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+{"entries":1,"bytes":[..]}
+
+"#]]);
+    Command::new(cargo_bin("lmb"))
+        .args(["--no-color", "--cache-dir", &cache_dir, "cache", "clear"])
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+1
+
+"#]]);
+}
+
+#[test]
+fn eval_without_cache() {
+    let dir = TempDir::new().unwrap();
+    Command::new(cargo_bin("lmb"))
+        .env("HOME", dir.path())
+        .env("XDG_CACHE_HOME", dir.child("cache").path())
+        .stdin("return 1+1")
+        .args(["--no-color", "eval", "--file", "-"])
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+[..]  INFO rusqlite_migration: Database migrated to version 2    
+2
+"#]]);
+    dir.child("cache").assert(predicates::path::missing());
+}
+
+#[test]
+fn eval_require_module() {
+    let dir = TempDir::new().unwrap();
+    dir.child("lib/util.lua")
+        .write_str("return { greet = function(name) return 'hello ' .. name end }")
+        .unwrap();
+    let main = dir.child("main.lua");
+    main.write_str("return require('./lib/util').greet('lmb')")
+        .unwrap();
+    let main_path = main.path().to_string_lossy();
+    Command::new(cargo_bin("lmb"))
+        .args(["--no-color", "eval", "--file", &main_path])
+        .assert()
+        .success()
+        .stdout_eq(str![[r#"
+[..]  INFO rusqlite_migration: Database migrated to version 2    
+hello lmb
+"#]]);
+}
+
 #[test]
 fn eval_stdin_syntax_error() {
     Command::new(cargo_bin("lmb"))
@@ -108,7 +242,7 @@ fn eval_stdin_syntax_error() {
         .assert()
         .failure()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 
 "#]]);
 }
@@ -131,7 +265,7 @@ fn eval_store_migrate() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 true
 "#]]);
 }
@@ -171,7 +305,7 @@ fn example_eval() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 3798601
 "#]]);
 }
@@ -217,7 +351,7 @@ fn example_serve() {
         ])
         .assert()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 [..]  WARN lmb::serve: no store path is specified, an in-memory store will be used and values will be lost when process ends
 [..]  INFO lmb::serve: serving lua script bind=127.0.0.1:3000
 
@@ -338,7 +472,7 @@ fn serve() {
         .timeout(Duration::from_secs(2))
         .assert()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 [..]  WARN lmb::serve: no store path is specified, an in-memory store will be used and values will be lost when process ends
 [..]  INFO lmb::serve: serving lua script bind=127.0.0.1:3001
 
@@ -367,7 +501,7 @@ fn store_delete() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 1
 "#]]);
 
@@ -404,7 +538,7 @@ fn store_get() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 null
 "#]]);
 }
@@ -431,7 +565,7 @@ fn store_get_list_put() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 1
 "#]]);
 
@@ -484,7 +618,7 @@ fn store_list() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
  name  type  size  created at  updated at 
 
 "#]]);
@@ -505,7 +639,7 @@ fn store_migrate() {
         .assert()
         .success()
         .stdout_eq(str![[r#"
-[..]  INFO rusqlite_migration: Database migrated to version 1    
+[..]  INFO rusqlite_migration: Database migrated to version 2    
 
 "#]]);
 }